@@ -1,214 +1,2421 @@
 use std::env;
 use std::fs::{self, File};
 use std::io::{self, BufRead, BufReader, Read, Write};
-use std::path::PathBuf;
-use std::collections::VecDeque;
-use std::os::unix::io::{FromRawFd, AsRawFd};
+use std::path::{Path, PathBuf};
+use std::collections::{HashMap, VecDeque};
+use std::os::unix::io::{FromRawFd, AsRawFd, RawFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use eframe::egui;
-use rustyline::{CompletionType, Config, EditMode, Editor};
+use rustyline::{CompletionType, Config as EditorConfig, EditMode, Editor};
 use rustyline::completion::FilenameCompleter;
 use rustyline::history::DefaultHistory;
 
 use nix::pty::{openpty, Winsize};
 use nix::unistd::{ForkResult, fork, setsid, Pid, tcsetpgrp};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
 use nix::sys::termios::{self, SetArg};
-use nix::sys::select::{select, FdSet};
-use nix::sys::time::TimeVal;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::resource::{setrlimit, Resource};
 use nix::libc;
 
 use vte::{Parser, Perform};
 use vte::Params;
 
+use base64::Engine;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+use serde::{Deserialize, Serialize};
+
 const HISTORY_SIZE: usize = 1000;
+/// Window title until a program sets one with OSC 0 / OSC 2.
+const DEFAULT_TITLE: &str = "PhantomTTY";
+const PASTE_START: &str = "\x1b[200~";
+const PASTE_END: &str = "\x1b[201~";
+const UNFOCUSED_REPAINT_INTERVAL: Duration = Duration::from_millis(1000);
+/// Output is handed to the UI once the child has been quiet this long...
+const FLUSH_QUIET: Duration = Duration::from_millis(16);
+/// ...or once this much has piled up, or it has waited this long.
+const FLUSH_MAX_BYTES: usize = 64 * 1024;
+const FLUSH_MAX_DELAY: Duration = Duration::from_millis(50);
+/// How often an idle reader wakes to check whether the machine was suspended.
+const SUSPEND_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// Suspended time that counts as a suspend/resume rather than clock jitter.
+const SUSPEND_THRESHOLD: Duration = Duration::from_secs(2);
+/// Back-to-back EINTRs from poll treated as a resume, as some kernels deliver them.
+const EINTR_STORM: u32 = 64;
+/// Recent output a detached session keeps for replay on reattach.
+const DETACH_REPLAY_BYTES: usize = 64 * 1024;
+/// Size of the detach handshake header carrying the shell pid and replay length.
+const DETACH_HEADER_LEN: usize = 32;
+/// The default log file is rotated to `.1` at startup once it grows past this.
+const LOG_ROTATE_BYTES: u64 = 1024 * 1024;
+/// How long `phantom:run` waits for the command's OSC 133 `D` mark.
+const RUN_TIMEOUT: Duration = Duration::from_secs(30);
+/// `--headless` treats the shell as done with a line once it has been silent this long.
+const HEADLESS_QUIET: Duration = Duration::from_millis(300);
+/// Extensions advertised in the primary DA reply, one per implemented feature:
+/// 6 selective erase (DECSCA, DECSED, DECSEL), 22 ANSI color, 28 rectangular
+/// editing (DECERA, DECFRA). Add a code here when its feature lands.
+const DA_EXTENSIONS: &[u16] = &[6, 22, 28];
+/// Fills the cell after a double-width glyph. The renderer skips it, so the glyph
+/// lines up over both columns, and it is dropped whenever the screen becomes text.
+const WIDE_SPACER: char = '\0';
+/// Depth of the XTPUSHSGR stack, as in xterm.
+const SGR_STACK_DEPTH: usize = 10;
+/// Depth of the title stack used by `CSI 22 t` / `CSI 23 t`.
+const TITLE_STACK_DEPTH: usize = 10;
+const DEFAULT_SCROLLBACK_LINES: usize = 10_000;
+/// Ctrl-Z steps kept for the input line.
+const INPUT_UNDO_DEPTH: usize = 64;
+/// Half period of a blinking cursor.
+const CURSOR_BLINK: Duration = Duration::from_millis(530);
+const DEFAULT_FONT_SIZE: f32 = 14.0;
+const MIN_FONT_SIZE: f32 = 6.0;
+const MAX_FONT_SIZE: f32 = 72.0;
+
+/// Written to `config.toml` when there is none, so users have something to edit.
+const DEFAULT_CONFIG: &str = r##"# PhantomTTY configuration. Uncomment a line to change it.
+
+# shell = "/bin/bash"
+# font_size = 14.0
+# font = "/usr/share/fonts/truetype/dejavu/DejaVuSansMono.ttf"
+# scrollback_lines = 10000
+# safe_mode = false
+# command_not_found = "did-you-mean"
+
+# [theme]
+# palette = [
+#     "#000000", "#cd0000", "#00cd00", "#cdcd00", "#0000ee", "#cd00cd", "#00cdcd", "#e5e5e5",
+#     "#7f7f7f", "#ff0000", "#00ff00", "#ffff00", "#5c5cff", "#ff00ff", "#00ffff", "#ffffff",
+# ]
+# foreground = "#b4b4b4"
+# background = "#1b1b1b"
+# cursor = "#c0deff"
+"##;
+
+/// User configuration, read from `config.toml` in the PhantomTTY config directory.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Config {
+    /// Shell to run instead of `$SHELL` or the passwd entry.
+    shell: Option<String>,
+    /// Starting font size in points.
+    font_size: Option<f32>,
+    /// TTF/OTF file for the terminal text. Glyphs it lacks come from the built-in
+    /// font, which has box drawing.
+    font: Option<PathBuf>,
+    limits: Limits,
+    clipboard: ClipboardConfig,
+    snippets: Vec<Snippet>,
+    show_snippets: bool,
+    /// Initial grid size when `COLUMNS`/`LINES` are not set.
+    columns: Option<usize>,
+    lines: Option<usize>,
+    phantom_command: Vec<PhantomCommand>,
+    /// Insert dropped files under the shell's cwd as relative paths.
+    relativize_dropped_paths: bool,
+    paste: PasteConfig,
+    /// Columns between default tab stops (8 when unset).
+    tab_width: Option<usize>,
+    /// Copy tab-laid-out gaps as spaces instead of a literal tab.
+    copy_tabs_as_spaces: bool,
+    theme: Theme,
+    /// Let programs raise desktop notifications with OSC 9 and OSC 777.
+    notifications: bool,
+    /// Start every session in safe mode, as `phantom:safe-mode on` does: output
+    /// can't set the clipboard, title or window size, notify, or get status replies.
+    safe_mode: bool,
+    /// Ctrl-L on an empty input line scrolls the screen away locally instead of
+    /// clearing the screen and scrollback as `phantom:clear` does.
+    clear_scrolls: bool,
+    /// Keep the shell running when the window closes and reattach to it on the
+    /// next launch.
+    detach_on_close: bool,
+    start_maximized: bool,
+    start_fullscreen: bool,
+    /// Scroll earlier output off the screen before each command runs, so only the
+    /// current prompt and its output are in view.
+    clear_on_command: bool,
+    /// Mark each finished command's prompt with its exit status (needs OSC 133).
+    show_exit_badges: bool,
+    /// Cursor style used at startup, for `CSI 0 SP q` and after resets.
+    cursor: CursorStyle,
+    /// Faintly shade the row the cursor is on (not on the alternate screen).
+    highlight_current_line: bool,
+    /// Force a maximally contrasting palette and draw faint text at full strength.
+    high_contrast: bool,
+    /// Build the accessibility tree from startup, so screen readers can read the
+    /// output even before asking for it.
+    screen_reader: bool,
+    /// Rows kept after scrolling off the top of the screen (10000 when unset).
+    scrollback_lines: Option<usize>,
+    /// Modifier that turns a click on a path into opening it.
+    open_modifier: ClickModifier,
+    /// Commands for opening files by extension (e.g. `pdf = "zathura"`); the rest
+    /// use `$EDITOR`.
+    open_with: HashMap<String, String>,
+    /// Let programs resize the grid and window with `CSI 8 ; rows ; cols t`.
+    allow_window_resize: bool,
+    binary_output: BinaryOutputConfig,
+    autosave: AutosaveConfig,
+    /// Extra regexes for commands that need confirming before they run, on top of
+    /// `DANGEROUS_COMMANDS`.
+    confirm_commands: Vec<String>,
+    /// How `phantom:insert` puts multi-line output on the input line.
+    insert_multiline: MultilineInsert,
+    /// Home and End go to a full-screen program even while the input line has focus.
+    home_end_to_program: bool,
+    /// What to suggest when a command isn't found: "did-you-mean",
+    /// "command-not-found", or "off" (the default), as with `phantom:not-found`.
+    command_not_found: Option<String>,
+    /// Give the shell a pipe for stderr instead of the PTY and tint what arrives on
+    /// it. Programs then see stderr isn't a terminal, and its ordering against
+    /// stdout is only as close as the two reads land.
+    separate_stderr: bool,
+    /// Save the screen and scrollback on exit and bring them back as scrollback on
+    /// the next launch (unless a detached session is reattached instead).
+    restore_session: bool,
+    /// Most tabs open at once; unset for no cap.
+    max_tabs: Option<usize>,
+    /// What opening a tab past `max_tabs` does.
+    max_tabs_policy: TabLimitPolicy,
+    /// Take `run <command>` requests on a Unix socket in the runtime directory
+    /// (`phantomtty-<uid>-<pid>-control.sock`), each answered with the command's
+    /// output once it finishes in the showing tab. Needs OSC 133 marks.
+    control_socket: bool,
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+enum TabLimitPolicy {
+    /// Leave the tabs as they are and say why no tab opened.
+    #[default]
+    Refuse,
+    /// Close the tab that has gone longest without being shown.
+    Recycle,
+}
+
+/// What opening one more tab does under `max_tabs`.
+#[derive(PartialEq, Debug)]
+enum TabOpening {
+    Open,
+    /// Close this tab first.
+    Replace(usize),
+    Refuse,
+}
+
+impl TabLimitPolicy {
+    /// Decides for tabs last shown at `last_used`, of which `active` is showing. The
+    /// showing tab is never recycled.
+    fn opening(self, max_tabs: Option<usize>, last_used: &[Instant], active: usize) -> TabOpening {
+        match max_tabs {
+            Some(max) if last_used.len() >= max.max(1) => {}
+            _ => return TabOpening::Open,
+        }
+        let oldest = last_used.iter().enumerate()
+            .filter(|&(i, _)| i != active)
+            .min_by_key(|&(_, used)| used)
+            .map(|(i, _)| i);
+        match (self, oldest) {
+            (TabLimitPolicy::Recycle, Some(i)) => TabOpening::Replace(i),
+            _ => TabOpening::Refuse,
+        }
+    }
+}
+
+/// Where the output of a captured command goes once it finishes.
+enum RunSink {
+    /// `phantom:run`: onto the clipboard.
+    Clipboard,
+    /// `phantom:insert`: onto the input line.
+    Input,
+    /// `PhantomTTY::run_capture`: back to the caller.
+    Caller(mpsc::Sender<Result<String, RunTimeout>>),
+}
+
+/// A captured command didn't finish within `RUN_TIMEOUT`.
+#[derive(PartialEq, Debug)]
+struct RunTimeout;
+
+/// A request read from the control socket.
+enum ControlRequest {
+    /// `run <command>`, answered on the sender.
+    Run(String, mpsc::Sender<Result<String, RunTimeout>>),
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum MultilineInsert {
+    /// Lines joined with spaces, e.g. a list of file names.
+    #[default]
+    Join,
+    /// The whole output as one shell-quoted word, newlines included.
+    Quote,
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+enum ClickModifier {
+    #[default]
+    Ctrl,
+    Alt,
+    Shift,
+}
+
+impl ClickModifier {
+    fn held(self, modifiers: &egui::Modifiers) -> bool {
+        match self {
+            ClickModifier::Ctrl => modifiers.ctrl,
+            ClickModifier::Alt => modifiers.alt,
+            ClickModifier::Shift => modifiers.shift,
+        }
+    }
+}
+
+/// Terminal colors as `#rrggbb` strings. `phantom:export-theme` writes this as a
+/// `[theme]` table, so an exported file can be pasted into the config as is.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+struct Theme {
+    /// ANSI colors 0-15.
+    palette: Vec<String>,
+    foreground: String,
+    background: String,
+    cursor: String,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        let palette = [
+            "#000000", "#cd0000", "#00cd00", "#cdcd00", "#0000ee", "#cd00cd", "#00cdcd", "#e5e5e5",
+            "#7f7f7f", "#ff0000", "#00ff00", "#ffff00", "#5c5cff", "#ff00ff", "#00ffff", "#ffffff",
+        ];
+        Self {
+            palette: palette.iter().map(|c| c.to_string()).collect(),
+            foreground: "#b4b4b4".to_string(),
+            background: "#1b1b1b".to_string(),
+            cursor: "#c0deff".to_string(),
+        }
+    }
+}
+
+impl Theme {
+    /// Colors for high-contrast mode: pure white on black with saturated ANSI colors.
+    fn high_contrast() -> Self {
+        let palette = [
+            "#000000", "#ff4040", "#00ff00", "#ffff00", "#6060ff", "#ff40ff", "#00ffff", "#ffffff",
+            "#c0c0c0", "#ff8080", "#80ff80", "#ffff80", "#a0a0ff", "#ff80ff", "#80ffff", "#ffffff",
+        ];
+        Self {
+            palette: palette.iter().map(|c| c.to_string()).collect(),
+            foreground: "#ffffff".to_string(),
+            background: "#000000".to_string(),
+            cursor: "#ffff00".to_string(),
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.palette.len() != 16 {
+            return Err(format!("palette has {} colors, expected 16", self.palette.len()));
+        }
+        for (name, color) in [("foreground", &self.foreground), ("background", &self.background), ("cursor", &self.cursor)] {
+            if parse_hex_color(color).is_none() {
+                return Err(format!("{} color {:?} is not #rrggbb", name, color));
+            }
+        }
+        for (i, color) in self.palette.iter().enumerate() {
+            if parse_hex_color(color).is_none() {
+                return Err(format!("palette color {} ({:?}) is not #rrggbb", i, color));
+            }
+        }
+        Ok(())
+    }
+
+    fn color(hex: &str) -> egui::Color32 {
+        parse_hex_color(hex).unwrap_or(egui::Color32::GRAY)
+    }
+}
+
+/// On-disk shape of an exported theme.
+#[derive(Serialize, Deserialize)]
+struct ThemeFile {
+    theme: Theme,
+}
+
+fn parse_hex_color(hex: &str) -> Option<egui::Color32> {
+    let digits = hex.strip_prefix('#').filter(|d| d.len() == 6 && d.is_ascii())?;
+    let channel = |i: usize| u8::from_str_radix(&digits[i..i + 2], 16).ok();
+    Some(egui::Color32::from_rgb(channel(0)?, channel(2)?, channel(4)?))
+}
+
+#[derive(Deserialize, Default, Clone)]
+#[serde(default)]
+struct PasteConfig {
+    /// Pastes longer than this many bytes are written to a temp file and replaced
+    /// by a `$(cat <file>)` reference in the input; unset sends every paste as is.
+    temp_file_threshold: Option<usize>,
+}
+
+/// What to do when output looks like binary data, e.g. from `cat`-ing an executable.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+struct BinaryOutputConfig {
+    action: BinaryOutputAction,
+    /// Fraction of control bytes in a batch of output that marks it as binary.
+    threshold: f32,
+}
+
+impl Default for BinaryOutputConfig {
+    fn default() -> Self {
+        Self { action: BinaryOutputAction::Off, threshold: 0.3 }
+    }
+}
+
+/// Periodic saving of each tab's scrollback and screen to the `autosave` folder in
+/// the config directory, so a crash doesn't lose a long session. This is the text
+/// as rendered, escapes already applied, not a log of the raw PTY output.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+struct AutosaveConfig {
+    enabled: bool,
+    /// Save once output has been quiet this many seconds; 0 saves only by lines.
+    idle_seconds: u64,
+    /// Save after this many rows scroll into scrollback, even while output goes on;
+    /// 0 saves only when idle.
+    lines: usize,
+    /// Autosave files kept, the oldest removed first.
+    keep: usize,
+}
+
+impl Default for AutosaveConfig {
+    fn default() -> Self {
+        Self { enabled: false, idle_seconds: 30, lines: 1000, keep: 5 }
+    }
+}
+
+impl AutosaveConfig {
+    /// Whether output not yet saved should be saved now, given how long output has
+    /// been quiet and how many rows scrolled into scrollback since the last save.
+    fn due(&self, quiet: Duration, new_lines: usize) -> bool {
+        (self.idle_seconds > 0 && quiet >= Duration::from_secs(self.idle_seconds))
+            || (self.lines > 0 && new_lines >= self.lines)
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum BinaryOutputAction {
+    #[default]
+    Off,
+    /// Ask whether to interrupt the program and reset the terminal.
+    Prompt,
+    /// Interrupt and reset without asking.
+    Reset,
+}
+
+/// Commands that ask for confirmation before being sent to the shell.
+const DANGEROUS_COMMANDS: &[&str] = &[
+    r"\brm\s+(-[\w-]+\s+)*-\w*(r\w*f|f\w*r)\w*\s+(-[\w-]+\s+)*/(\s|\*|$)",
+    r"\bdd\b.*\bof=/dev/",
+    r"\bmkfs(\.\w+)?\b",
+    r":\(\)\s*\{\s*:\s*\|\s*:\s*&\s*\}\s*;\s*:",
+];
+
+/// Output shorter than this is never judged binary; a few stray controls are normal.
+const BINARY_SAMPLE_MIN: usize = 256;
+
+/// Whether more than `threshold` of `bytes` are control characters other than the
+/// ones text and escape sequences use (tab, newline, CR, backspace, bell, ESC).
+fn looks_binary(bytes: &[u8], threshold: f32) -> bool {
+    if bytes.len() < BINARY_SAMPLE_MIN {
+        return false;
+    }
+    let controls = bytes.iter()
+        .filter(|&&b| (b < 0x20 && !matches!(b, b'\t' | b'\n' | b'\r' | 0x08 | 0x07 | 0x1b)) || b == 0x7f)
+        .count();
+    controls as f32 / bytes.len() as f32 > threshold
+}
+
+/// A user-defined `phantom:<name>` command that sends `run` to the shell, with
+/// `{args}` replaced by the shell-quoted words that followed the name. Names taken
+/// by a builtin are never reached.
+#[derive(Deserialize, Clone)]
+struct PhantomCommand {
+    name: String,
+    run: String,
+}
+
+/// A saved command listed in the snippets panel.
+#[derive(Serialize, Deserialize, Clone)]
+struct Snippet {
+    name: String,
+    command: String,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+struct ClipboardConfig {
+    history_size: usize,
+    max_snippet_len: usize,
+    persist: bool,
+}
+
+impl Default for ClipboardConfig {
+    fn default() -> Self {
+        Self {
+            history_size: 20,
+            max_snippet_len: 4096,
+            persist: false,
+        }
+    }
+}
+
+/// Resource limits applied to the shell before `execve`; unset means unlimited.
+#[derive(Deserialize, Default, Clone)]
+#[serde(default)]
+struct Limits {
+    cpu_seconds: Option<u64>,
+    address_space_bytes: Option<u64>,
+    open_files: Option<u64>,
+}
+
+impl Limits {
+    /// Called in the forked shell, so failures are reported without allocating.
+    fn apply(&self) {
+        let limits = [
+            (Resource::RLIMIT_CPU, self.cpu_seconds, "Warning: Failed to set RLIMIT_CPU\n"),
+            (Resource::RLIMIT_AS, self.address_space_bytes, "Warning: Failed to set RLIMIT_AS\n"),
+            (Resource::RLIMIT_NOFILE, self.open_files, "Warning: Failed to set RLIMIT_NOFILE\n"),
+        ];
+        for (resource, limit, warning) in limits {
+            if let Some(limit) = limit {
+                if setrlimit(resource, limit, limit).is_err() {
+                    child_warning(warning);
+                }
+            }
+        }
+    }
+}
+
+/// Writes `message` straight to stderr, for the forked child between `fork` and
+/// `exec`, where formatting or taking the stderr lock isn't safe.
+fn child_warning(message: &str) {
+    let _ = nix::unistd::write(2, message.as_bytes());
+}
+
+/// A desktop notification requested by the child through OSC 9 or OSC 777.
+struct Notification {
+    title: String,
+    body: String,
+}
+
+impl Notification {
+    const MAX_TITLE: usize = 64;
+    const MAX_BODY: usize = 256;
+
+    /// Drops control characters and truncates, so output can't flood the
+    /// notification daemon or smuggle escapes into it.
+    fn new(title: &str, body: &str) -> Self {
+        let clean = |text: &str, max: usize| text.chars().filter(|c| !c.is_control()).take(max).collect::<String>();
+        Self {
+            title: clean(title, Self::MAX_TITLE),
+            body: clean(body, Self::MAX_BODY),
+        }
+    }
+
+    fn show(&self) {
+        let result = std::process::Command::new("notify-send")
+            .arg("--app-name=PhantomTTY")
+            .arg("--")
+            .arg(&self.title)
+            .arg(&self.body)
+            .spawn();
+        match result {
+            Ok(mut child) => {
+                thread::spawn(move || child.wait());
+            }
+            Err(e) => log::warn!("Failed to show notification: {}", e),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+enum CursorShape {
+    #[default]
+    Block,
+    Underline,
+    Bar,
+}
+
+/// Cursor shape and blinking, as set by DECSCUSR (`CSI Ps SP q`).
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(default)]
+struct CursorStyle {
+    shape: CursorShape,
+    blink: bool,
+}
+
+impl Default for CursorStyle {
+    fn default() -> Self {
+        Self { shape: CursorShape::Block, blink: true }
+    }
+}
+
+impl CursorStyle {
+    /// DECSCUSR parameters 1-6; 0 means the configured default and is handled by the caller.
+    fn from_decscusr(ps: usize) -> Option<Self> {
+        let shape = match ps {
+            1 | 2 => CursorShape::Block,
+            3 | 4 => CursorShape::Underline,
+            5 | 6 => CursorShape::Bar,
+            _ => return None,
+        };
+        Some(Self { shape, blink: ps % 2 == 1 })
+    }
+}
+
+/// The terminal cursor as the widget draws it.
+#[derive(Clone, Copy)]
+struct CursorMark {
+    row: usize,
+    col: usize,
+    /// Character under the cursor, redrawn over a block cursor.
+    glyph: char,
+    /// The cursor is on a double-width glyph and covers both its cells.
+    wide: bool,
+    style: CursorStyle,
+}
+
+/// A cell color: the theme's default, an index into the 256-color palette (the
+/// first 16 from the theme), or 24-bit RGB.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+enum Color {
+    #[default]
+    Default,
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+/// Style of one screen cell, kept alongside its character.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+struct Cell {
+    fg: Color,
+    bg: Color,
+    /// `Cell::BOLD` and friends.
+    attrs: u8,
+    /// Set by DECSCA; selective erase skips the cell.
+    protected: bool,
+    /// Combining marks, ZWJ continuations and the like after the cell's character
+    /// in its grapheme cluster: an index + 1 into `VteTerminal::clusters`, 0 for none.
+    cluster: u16,
+}
+
+impl Cell {
+    const BOLD: u8 = 1 << 0;
+    const ITALIC: u8 = 1 << 1;
+    const UNDERLINE: u8 = 1 << 2;
+    const REVERSE: u8 = 1 << 3;
+    const FAINT: u8 = 1 << 4;
+    /// Printed from the shell's stderr pipe (`separate_stderr`).
+    const STDERR: u8 = 1 << 5;
+}
+
+/// A row that scrolled off the top of the screen.
+struct ScrollbackLine {
+    /// Characters as they were in the grid, tab markers included.
+    chars: Vec<char>,
+    cells: Vec<Cell>,
+    /// The row's text continued on the next row.
+    wrapped: bool,
+}
+
+/// Scrollback and screen saved on exit for `restore_session`.
+#[derive(Serialize, Deserialize, Default)]
+struct SessionSnapshot {
+    /// Cluster tails the lines' cells refer to, as in `VteTerminal::clusters`.
+    clusters: Vec<String>,
+    lines: Vec<SnapshotLine>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotLine {
+    /// The row's characters, tab markers and wide-glyph spacers included.
+    text: String,
+    /// Cell styles as runs of (length, style).
+    styles: Vec<(usize, Cell)>,
+    wrapped: bool,
+}
+
+impl SessionSnapshot {
+    fn load() -> Option<Self> {
+        let path = get_snapshot_file_path();
+        let contents = fs::read_to_string(&path).ok()?;
+        toml::from_str(&contents)
+            .map_err(|e| log::error!("Error parsing {}: {}", path.display(), e))
+            .ok()
+    }
+
+    fn save(&self) {
+        let result = toml::to_string(self)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            .and_then(|contents| fs::write(get_snapshot_file_path(), contents));
+        if let Err(e) = result {
+            log::error!("Error saving session snapshot: {}", e);
+        }
+    }
+}
+
+/// The Ctrl+Shift+F search bar.
+#[derive(Default)]
+struct SearchState {
+    query: String,
+    case_sensitive: bool,
+    /// Matches from `VteTerminal::search`, as (line, column).
+    hits: Vec<(usize, usize)>,
+    /// Index into `hits` of the match last jumped to.
+    current: usize,
+    /// Scroll the current match into view on the next frame.
+    jump: bool,
+}
+
+/// A finished command's mark on its prompt row.
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct ExitBadge {
+    status: i32,
+    /// The command's output rows, down to the next prompt, are hidden.
+    folded: bool,
+}
+
+/// The main screen, put aside while the alternate screen is showing.
+struct SavedScreen {
+    screen: Vec<char>,
+    cells: Vec<Cell>,
+    line_attrs: Vec<LineAttr>,
+    exit_badges: Vec<Option<ExitBadge>>,
+    wrapped: Vec<bool>,
+    prompt_row: Option<usize>,
+    cursor_x: usize,
+    cursor_y: usize,
+}
+
+/// Per-row size set by DECDWL/DECDHL (`ESC # 3..6`).
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+enum LineAttr {
+    #[default]
+    Single,
+    DoubleWidth,
+    DoubleHeightTop,
+    DoubleHeightBottom,
+}
 
 struct VteTerminal {
     screen: Vec<char>,
+    line_attrs: Vec<LineAttr>,
+    /// Colors, attributes and protection for each character in `screen`.
+    cells: Vec<Cell>,
+    /// Style given to newly printed characters, set by SGR.
+    pen: Cell,
+    /// Pens saved by XTPUSHSGR, with the attribute bits and whether fg/bg were pushed.
+    sgr_stack: Vec<(Cell, u8, bool, bool)>,
+    /// DECSCA (`CSI 1 " q`): mark newly printed cells as protected from selective erase.
+    protect_chars: bool,
+    /// Exit status of the command whose prompt starts on each row, from OSC 133.
+    exit_badges: Vec<Option<ExitBadge>>,
+    /// Rows whose text ran past the last column and continues on the next row.
+    wrapped: Vec<bool>,
+    /// Rows scrolled off the top of the main screen, oldest first.
+    scrollback: VecDeque<ScrollbackLine>,
+    scrollback_limit: usize,
+    /// Rows ever saved to scrollback, still counting once old ones are dropped.
+    scrolled_rows: usize,
+    /// Row of the latest OSC 133 `A` (prompt start) mark, while it is on screen.
+    prompt_row: Option<usize>,
     cursor_x: usize,
     cursor_y: usize,
     width: usize,
     height: usize,
+    parser: Parser,
+    /// When set, sequences that reach outside the grid are parsed but ignored.
+    /// Gated today:
+    /// - OSC 0 / OSC 2 title changes, and title stack pops (`CSI 23 t`)
+    /// - OSC 52 clipboard writes (and reads, which are never answered)
+    /// - OSC 8 hyperlinks (which aren't followed with safe mode off either)
+    /// - OSC 9 / OSC 777 desktop notifications
+    /// - `CSI 8 ; rows ; cols t` window resizes
+    /// - DSR status and cursor position reports (`CSI 5 n`, `CSI [?] 6 n`)
+    ///
+    /// Display-only sequences (SGR, cursor motion, erase) are unaffected.
+    safe_mode: bool,
+    clipboard_writes: Vec<String>,
+    notifications: Vec<Notification>,
+    /// Exit status from the last OSC 133 `D` (command finished) mark.
+    exit_status: Option<i32>,
+    /// Capture the next command's output, from its OSC 133 `C` mark to its `D` mark.
+    capture_armed: bool,
+    /// Text printed since the `C` mark while a capture runs.
+    capture: Option<String>,
+    /// Output of the last finished capture.
+    captured: Option<String>,
+    /// Last graphic character printed, repeated by REP (`CSI Ps b`).
+    last_printed: Option<char>,
+    /// Screen index of the glyph just printed, which a following combining mark or
+    /// ZWJ continuation joins. Anything but another print clears it.
+    last_glyph: Option<usize>,
+    /// Grapheme cluster tails that `Cell::cluster` refers to, each kept once.
+    clusters: Vec<String>,
+    /// Columns between the default tab stops.
+    tab_width: usize,
+    /// Tab stop at each column; HTS sets one at the cursor and TBC clears them.
+    tab_stops: Vec<bool>,
+    /// Set after printing in the last column: the cursor stays there and the line
+    /// wraps only when the next glyph arrives. Any explicit cursor motion cancels it.
+    wrap_pending: bool,
+    /// Replies to queries (device attributes, status reports) waiting to be
+    /// written back to the PTY.
+    responses: Vec<u8>,
+    /// Conformance level from DECSCL: 61 for VT100, 62 and up for VT200 and later.
+    conformance_level: u16,
+    /// Whether replies use 8-bit C1 controls (0x9B) instead of `ESC [`.
+    c1_8bit: bool,
+    /// Working directory reported by the shell through OSC 7.
+    cwd: Option<PathBuf>,
+    /// Window title from OSC 0 / OSC 2, and titles saved by `CSI 22 t`.
+    title: String,
+    title_stack: Vec<String>,
+    /// Set when the title changes, for the app to apply to the window.
+    title_changed: bool,
+    allow_window_resize: bool,
+    /// Grid size asked for by `CSI 8 ; rows ; cols t`, for the app to apply.
+    resize_request: Option<(usize, usize)>,
+    /// DECCKM (`?1`): arrow keys send `ESC O` instead of `ESC [` sequences.
+    app_cursor_keys: bool,
+    /// Whether the alternate screen buffer is showing.
+    alt_screen: bool,
+    /// The main screen and cursor while the alternate screen is showing.
+    saved_screen: Option<SavedScreen>,
+    /// Cursor position saved by DECSC (`ESC 7`) or `CSI s`, as (x, y).
+    saved_cursor: Option<(usize, usize)>,
+    /// Output being processed came from the stderr pipe; printed cells get `Cell::STDERR`.
+    stderr_output: bool,
+    /// Alternate scroll (`?1007`): wheel scrolling on the alternate screen sends arrow keys.
+    alternate_scroll: bool,
+    mouse_tracking: MouseTracking,
+    /// SGR mouse encoding (`?1006`): `CSI < b ; col ; row M/m`, with no coordinate limit.
+    sgr_mouse: bool,
+    /// Bracketed paste (`?2004`): pastes reach the PTY between `PASTE_START` and
+    /// `PASTE_END`, so the shell inserts them rather than running each line.
+    bracketed_paste: bool,
+    /// DECSTBM margins, as inclusive 0-based rows.
+    scroll_top: usize,
+    scroll_bottom: usize,
+    /// DECOM (`?6`): cursor addressing is relative to the scroll region.
+    origin_mode: bool,
+    /// IRM (`4`): printing shifts the rest of the row right instead of overwriting.
+    insert_mode: bool,
+    /// DECAWM (`?7`): printing past the last column continues on the next row.
+    /// When off, the last column is overwritten instead.
+    autowrap: bool,
+    /// LNM (`20`): line feed also returns the carriage.
+    newline_mode: bool,
+    cursor_style: CursorStyle,
+    /// DECTCEM (`?25`): programs hide the cursor while they redraw.
+    cursor_visible: bool,
+    /// Style from the config, restored by `CSI 0 SP q`, DECSTR and RIS.
+    default_cursor_style: CursorStyle,
+}
+
+/// Which pointer events a program asked to receive (`?1000`, `?1002`, `?1003`).
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+enum MouseTracking {
+    #[default]
+    Off,
+    /// Presses, releases and the wheel.
+    Click,
+    /// Also motion while a button is held.
+    Drag,
+    /// Also motion with no button held.
+    Motion,
+}
+
+/// Terminal modes that change how the widget turns user input into bytes.
+#[derive(Clone, Copy, Default)]
+struct InputModes {
+    app_cursor_keys: bool,
+    /// Wheel events become arrow keys rather than scrolling the output.
+    alternate_scroll: bool,
+    mouse_tracking: MouseTracking,
+    /// Mouse reports use the SGR encoding (`?1006`) rather than X10 bytes.
+    sgr_mouse: bool,
+    /// A full-screen program is running on the alternate screen.
+    alt_screen: bool,
+}
+
+/// What Home and End act on.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum HomeEndTarget {
+    /// Start and end of the input line.
+    Line,
+    /// Sent to the program as terminal keys.
+    Program,
+    /// Top and bottom of the scrollback.
+    Scrollback,
+}
+
+/// Routes Home and End: a full-screen program comes first when configured so,
+/// then the focused input line, then a full-screen program, then the output under
+/// the pointer. `None` leaves them to whatever has focus.
+fn home_end_target(input_focused: bool, full_screen: bool, over_output: bool, program_first: bool) -> Option<HomeEndTarget> {
+    if full_screen && program_first {
+        Some(HomeEndTarget::Program)
+    } else if input_focused {
+        Some(HomeEndTarget::Line)
+    } else if full_screen {
+        Some(HomeEndTarget::Program)
+    } else if over_output {
+        Some(HomeEndTarget::Scrollback)
+    } else {
+        None
+    }
 }
 
 impl VteTerminal {
     fn new(width: usize, height: usize) -> Self {
         Self {
             screen: vec![' '; width * height],
+            line_attrs: vec![LineAttr::Single; height],
+            cells: vec![Cell::default(); width * height],
+            pen: Cell::default(),
+            sgr_stack: Vec::new(),
+            protect_chars: false,
+            exit_badges: vec![None; height],
+            wrapped: vec![false; height],
+            scrollback: VecDeque::new(),
+            scrollback_limit: DEFAULT_SCROLLBACK_LINES,
+            scrolled_rows: 0,
+            prompt_row: None,
             cursor_x: 0,
             cursor_y: 0,
             width,
             height,
+            parser: Parser::new(),
+            safe_mode: false,
+            clipboard_writes: Vec::new(),
+            notifications: Vec::new(),
+            exit_status: None,
+            capture_armed: false,
+            capture: None,
+            captured: None,
+            last_printed: None,
+            last_glyph: None,
+            clusters: Vec::new(),
+            tab_width: 8,
+            tab_stops: (0..width).map(|col| col > 0 && col % 8 == 0).collect(),
+            wrap_pending: false,
+            responses: Vec::new(),
+            conformance_level: 61,
+            c1_8bit: false,
+            cwd: None,
+            title: DEFAULT_TITLE.to_string(),
+            title_stack: Vec::new(),
+            title_changed: false,
+            allow_window_resize: false,
+            resize_request: None,
+            app_cursor_keys: false,
+            alt_screen: false,
+            saved_screen: None,
+            saved_cursor: None,
+            stderr_output: false,
+            alternate_scroll: false,
+            bracketed_paste: false,
+            mouse_tracking: MouseTracking::Off,
+            sgr_mouse: false,
+            scroll_top: 0,
+            scroll_bottom: height - 1,
+            origin_mode: false,
+            insert_mode: false,
+            autowrap: true,
+            newline_mode: false,
+            cursor_style: CursorStyle::default(),
+            cursor_visible: true,
+            default_cursor_style: CursorStyle::default(),
         }
     }
 
     fn process(&mut self, data: &[u8]) {
-        let mut parser = Parser::new();
+        // The parser lives across calls so sequences split between reads still parse.
+        let mut parser = std::mem::take(&mut self.parser);
         for (i, &byte) in data.iter().enumerate() {
             parser.advance(self, byte);
             if self.cursor_x >= self.width || self.cursor_y >= self.height {
-                eprintln!("Warning: Cursor out of bounds at byte {} (x: {}, y: {})", i, self.cursor_x, self.cursor_y);
+                log::warn!("Cursor out of bounds at byte {} (x: {}, y: {})", i, self.cursor_x, self.cursor_y);
                 self.cursor_x = self.cursor_x.min(self.width - 1);
                 self.cursor_y = self.cursor_y.min(self.height - 1);
             }
         }
+        self.parser = parser;
     }
-    
-    fn get_screen(&self) -> String {
-        let mut output = String::with_capacity(self.width * self.height);
-        for chunk in self.screen.chunks(self.width) {
-            output.extend(chunk.iter());
-            output.push('\n');
-        }
-        output
+
+    /// The new window title, once per change.
+    fn take_title(&mut self) -> Option<String> {
+        std::mem::take(&mut self.title_changed).then(|| self.title.clone())
     }
 
-    fn clear_screen(&mut self) {
-        self.screen = vec![' '; self.width * self.height];
-        self.cursor_x = 0;
-        self.cursor_y = 0;
+    fn take_clipboard_writes(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.clipboard_writes)
     }
 
-    fn move_cursor(&mut self, row: usize, col: usize) {
-        self.cursor_y = row.min(self.height - 1);
-        self.cursor_x = col.min(self.width - 1);
+    fn take_notifications(&mut self) -> Vec<Notification> {
+        std::mem::take(&mut self.notifications)
     }
 
-    fn erase_in_line(&mut self, mode: usize) {
-        let start = match mode {
-            0 => self.cursor_y * self.width + self.cursor_x,
-            1 => self.cursor_y * self.width,
-            2 => 0,
-            _ => return,
-        };
-        let end = (self.cursor_y + 1) * self.width;
-        self.screen[start..end].fill(' ');
+    fn take_responses(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.responses)
     }
-}
 
-impl Perform for VteTerminal {
-    fn print(&mut self, c: char) {
-        if self.cursor_x >= self.width {
-            self.cursor_x = 0;
-            self.cursor_y += 1;
-        }
-        if self.cursor_y >= self.height {
-            self.screen.drain(0..self.width);
-            self.screen.extend(std::iter::repeat(' ').take(self.width));
-            self.cursor_y = self.height - 1;
-        }
-        let pos = self.cursor_y * self.width + self.cursor_x;
-        if pos < self.screen.len() {
-            self.screen[pos] = c;
-        } else {
-            eprintln!("Warning: Attempted to print outside screen bounds (x: {}, y: {})", self.cursor_x, self.cursor_y);
-        }
-        self.cursor_x += 1;
+    /// Human-readable dump of cursor, grid and mode state for `phantom:state`.
+    fn state_dump(&self) -> String {
+        let on_off = |flag: bool| if flag { "on" } else { "off" };
+        let mut dump = String::new();
+        dump.push_str(&format!("Grid: {}x{}\n", self.width, self.height));
+        dump.push_str(&format!("Cursor: row {}, col {}\n", self.cursor_y + 1, self.cursor_x + 1));
+        dump.push_str(&format!("Cursor line: {:?}\n", self.line_attrs[self.cursor_y]));
+        dump.push_str(&format!("Wrap pending: {}\n", on_off(self.wrap_pending)));
+        dump.push_str(&format!("Pen: fg {:?}, bg {:?}, attrs {:#06b}\n", self.pen.fg, self.pen.bg, self.pen.attrs));
+        dump.push_str(&format!("Cursor keys (DECCKM): {}\n", if self.app_cursor_keys { "application" } else { "normal" }));
+        dump.push_str(&format!("Alternate screen: {}\n", on_off(self.alt_screen)));
+        dump.push_str(&format!("Alternate scroll (?1007): {}\n", on_off(self.alternate_scroll)));
+        dump.push_str(&format!("Bracketed paste (?2004): {}\n", on_off(self.bracketed_paste)));
+        dump.push_str(&format!(
+            "Mouse tracking: {:?}{}\n",
+            self.mouse_tracking,
+            if self.sgr_mouse { " (SGR)" } else { "" },
+        ));
+        dump.push_str(&format!("Scroll region: rows {}-{}\n", self.scroll_top + 1, self.scroll_bottom + 1));
+        dump.push_str(&format!("Origin mode: {}\n", on_off(self.origin_mode)));
+        dump.push_str(&format!("Insert mode (IRM): {}\n", on_off(self.insert_mode)));
+        dump.push_str(&format!("Newline mode (LNM): {}\n", on_off(self.newline_mode)));
+        dump.push_str(&format!(
+            "Conformance level: {} ({}-bit controls)\n",
+            self.conformance_level,
+            if self.c1_8bit { 8 } else { 7 },
+        ));
+        dump.push_str(&format!(
+            "Cursor style: {:?}{}\n",
+            self.cursor_style.shape,
+            if self.cursor_style.blink { ", blinking" } else { "" },
+        ));
+        dump.push_str(&format!("Cursor visible (DECTCEM): {}\n", on_off(self.cursor_visible)));
+        dump.push_str(&format!("Safe mode: {}\n", on_off(self.safe_mode)));
+        dump.push_str(&format!(
+            "Working directory: {}\n",
+            self.cwd.as_ref().map_or_else(|| "unknown".to_string(), |cwd| cwd.display().to_string()),
+        ));
+        dump
     }
 
-    fn execute(&mut self, byte: u8) {
-        match byte {
-            b'\r' => self.cursor_x = 0,
-            b'\n' => {
-                self.cursor_y += 1;
-                if self.cursor_y >= self.height {
-                    self.screen.drain(0..self.width);
-                    self.screen.extend(std::iter::repeat(' ').take(self.width));
-                    self.cursor_y = self.height - 1;
-                }
-            },
-            b'\x08' => if self.cursor_x > 0 { self.cursor_x -= 1 },
-            b'\x0C' => self.clear_screen(),
-            _ => {}
+    fn input_modes(&self) -> InputModes {
+        InputModes {
+            app_cursor_keys: self.app_cursor_keys,
+            alternate_scroll: self.alt_screen && self.alternate_scroll,
+            mouse_tracking: self.mouse_tracking,
+            sgr_mouse: self.sgr_mouse,
+            alt_screen: self.alt_screen,
         }
     }
 
-    fn hook(&mut self, _params: &Params, _intermediates: &[u8], _ignore: bool, _c: char) {}
-    fn put(&mut self, _byte: u8) {}
-    fn unhook(&mut self) {}
-    fn osc_dispatch(&mut self, _params: &[&[u8]], _bell_terminated: bool) {}
-    
-    fn csi_dispatch(&mut self, params: &Params, _intermediates: &[u8], _ignore: bool, c: char) {
-        let param = |idx: usize| -> usize {
-            params.iter()
-                .nth(idx)
-                .and_then(|slice| slice.first())
-                .map(|&x| x as usize)
-                .unwrap_or(1)
-        };
-
-        match c {
-            'A' => {
-                let n = param(0);
-                self.cursor_y = self.cursor_y.saturating_sub(n);
-            }
-            'B' => {
-                let n = param(0);
-                self.cursor_y = (self.cursor_y + n).min(self.height - 1);
-            }
-            'C' => {
-                let n = param(0);
-                self.cursor_x = (self.cursor_x + n).min(self.width - 1);
+    /// DECSET/DECRST (`CSI ? Pm h` / `CSI ? Pm l`).
+    fn set_private_mode(&mut self, mode: u16, enabled: bool) {
+        match mode {
+            1 => self.app_cursor_keys = enabled,
+            7 => {
+                self.autowrap = enabled;
+                self.wrap_pending &= enabled;
             }
-            'D' => {
-                let n = param(0);
-                self.cursor_x = self.cursor_x.saturating_sub(n);
+            25 => self.cursor_visible = enabled,
+            6 => {
+                self.origin_mode = enabled;
+                self.cursor_home();
             }
-            'H' | 'f' => {
-                let row = param(0).saturating_sub(1);
-                let col = param(1).saturating_sub(1);
-                self.move_cursor(row, col);
-            }
-            'J' => {
-                let mode = param(0);
-                match mode {
-                    0 => {
-                        let start = self.cursor_y * self.width + self.cursor_x;
-                        self.screen[start..].fill(' ');
-                    }
-                    1 => {
-                        let end = self.cursor_y * self.width + self.cursor_x;
-                        self.screen[..=end].fill(' ');
-                    }
-                    2 | 3 => self.clear_screen(),
-                    _ => {}
+            1007 => self.alternate_scroll = enabled,
+            2004 => self.bracketed_paste = enabled,
+            1000 | 1002 | 1003 => {
+                let tracking = match mode {
+                    1000 => MouseTracking::Click,
+                    1002 => MouseTracking::Drag,
+                    _ => MouseTracking::Motion,
+                };
+                if enabled {
+                    self.mouse_tracking = tracking;
+                } else if self.mouse_tracking == tracking {
+                    self.mouse_tracking = MouseTracking::Off;
                 }
             }
-            'K' => {
-                let mode = param(0);
-                self.erase_in_line(mode);
-            }
+            1006 => self.sgr_mouse = enabled,
+            47 | 1047 | 1049 if enabled => self.enter_alt_screen(),
+            47 | 1047 | 1049 => self.leave_alt_screen(mode == 1049),
             _ => {}
         }
     }
 
-    fn esc_dispatch(&mut self, _intermediates: &[u8], _ignore: bool, _byte: u8) {}
-}
-
-struct PhantomCompleter {
-    filename_completer: FilenameCompleter,
-}
-
-impl rustyline::completion::Completer for PhantomCompleter {
-    type Candidate = rustyline::completion::Pair;
+    /// `?47` / `?1047` / `?1049` set: puts the main screen aside and shows a blank
+    /// alternate one, with the cursor where it was.
+    fn enter_alt_screen(&mut self) {
+        if self.alt_screen {
+            return;
+        }
+        let (cursor_x, cursor_y) = (self.cursor_x, self.cursor_y);
+        self.saved_screen = Some(SavedScreen {
+            screen: std::mem::take(&mut self.screen),
+            cells: std::mem::take(&mut self.cells),
+            line_attrs: self.line_attrs.clone(),
+            exit_badges: self.exit_badges.clone(),
+            wrapped: self.wrapped.clone(),
+            prompt_row: self.prompt_row,
+            cursor_x,
+            cursor_y,
+        });
+        self.alt_screen = true;
+        self.clear_screen();
+        self.cursor_x = cursor_x;
+        self.cursor_y = cursor_y;
+    }
 
-    fn complete(&self, line: &str, pos: usize, ctx: &rustyline::Context<'_>) 
-        -> rustyline::Result<(usize, Vec<Self::Candidate>)> 
-    {
-        if line.starts_with("cd ") || line.contains('/') {
-            self.filename_completer.complete(line, pos, ctx)
-        } else {
-            let commands = vec!["cd", "ls", "echo", "cat", "grep", "history", "exit"];
-            let matches: Vec<Self::Candidate> = commands.iter()
-                .filter(|&cmd| cmd.starts_with(&line[..pos]))
-                .map(|&cmd| Self::Candidate { 
-                    display: cmd.to_string(),
-                    replacement: cmd.to_string(),
-                })
-                .collect();
-            Ok((0, matches))
+    /// `?47` / `?1047` / `?1049` reset: brings the main screen back, and for 1049 the
+    /// cursor saved on entry.
+    fn leave_alt_screen(&mut self, restore_cursor: bool) {
+        let Some(saved) = self.saved_screen.take() else { return };
+        self.alt_screen = false;
+        self.screen = saved.screen;
+        self.cells = saved.cells;
+        self.line_attrs = saved.line_attrs;
+        self.exit_badges = saved.exit_badges;
+        self.wrapped = saved.wrapped;
+        self.prompt_row = saved.prompt_row;
+        if restore_cursor {
+            self.cursor_x = saved.cursor_x;
+            self.cursor_y = saved.cursor_y;
         }
+        self.wrap_pending = false;
     }
-}
+
+    /// DECSTR (`CSI ! p`): puts modes, margins and the cursor style back to their
+    /// defaults without touching the screen contents.
+    fn soft_reset(&mut self) {
+        self.insert_mode = false;
+        self.origin_mode = false;
+        self.app_cursor_keys = false;
+        self.protect_chars = false;
+        self.pen = Cell::default();
+        self.sgr_stack.clear();
+        self.wrap_pending = false;
+        self.scroll_top = 0;
+        self.scroll_bottom = self.height - 1;
+        self.cursor_style = self.default_cursor_style;
+        self.cursor_visible = true;
+    }
+
+    /// RIS (`ESC c`): back to a blank screen in the initial state. Settings that come
+    /// from the user rather than the program (tab width, safe mode, cursor style)
+    /// survive, as do the window title and replies and requests still waiting for
+    /// the app.
+    fn full_reset(&mut self) {
+        let mut fresh = VteTerminal::new(self.width, self.height);
+        fresh.set_tab_width(self.tab_width);
+        fresh.safe_mode = self.safe_mode;
+        fresh.default_cursor_style = self.default_cursor_style;
+        fresh.cursor_style = self.default_cursor_style;
+        fresh.cwd = self.cwd.take();
+        fresh.title = std::mem::take(&mut self.title);
+        fresh.allow_window_resize = self.allow_window_resize;
+        fresh.stderr_output = self.stderr_output;
+        fresh.scrollback = std::mem::take(&mut self.scrollback);
+        fresh.clusters = std::mem::take(&mut self.clusters);
+        fresh.scrollback_limit = self.scrollback_limit;
+        fresh.scrolled_rows = self.scrolled_rows;
+        fresh.responses = std::mem::take(&mut self.responses);
+        fresh.clipboard_writes = std::mem::take(&mut self.clipboard_writes);
+        fresh.notifications = std::mem::take(&mut self.notifications);
+        *self = fresh;
+    }
+
+    /// SM/RM (`CSI Pm h` / `CSI Pm l`).
+    fn set_ansi_mode(&mut self, mode: u16, enabled: bool) {
+        match mode {
+            4 => self.insert_mode = enabled,
+            20 => self.newline_mode = enabled,
+            _ => {}
+        }
+    }
+
+    /// DECRQM (`CSI Ps $ p` / `CSI ? Ps $ p`), answered with `CSI [?] Ps ; Pm $ y`
+    /// where Pm is 1 for set, 2 for reset and 0 for modes we don't know.
+    fn report_mode(&mut self, mode: u16, private: bool) {
+        let state = if private {
+            match mode {
+                1 => Some(self.app_cursor_keys),
+                6 => Some(self.origin_mode),
+                7 => Some(self.autowrap),
+                25 => Some(self.cursor_visible),
+                1007 => Some(self.alternate_scroll),
+                47 | 1047 | 1049 => Some(self.alt_screen),
+                2004 => Some(self.bracketed_paste),
+                1000 => Some(self.mouse_tracking == MouseTracking::Click),
+                1002 => Some(self.mouse_tracking == MouseTracking::Drag),
+                1003 => Some(self.mouse_tracking == MouseTracking::Motion),
+                1006 => Some(self.sgr_mouse),
+                _ => None,
+            }
+        } else {
+            match mode {
+                4 => Some(self.insert_mode),
+                20 => Some(self.newline_mode),
+                _ => None,
+            }
+        };
+        let value = match state {
+            Some(true) => 1,
+            Some(false) => 2,
+            None => 0,
+        };
+        self.respond_csi(&format!("{}{};{}$y", if private { "?" } else { "" }, mode, value));
+    }
+
+    /// Queues a control sequence reply, introduced by 7-bit `ESC [` or 8-bit CSI.
+    fn respond_csi(&mut self, body: &str) {
+        if self.c1_8bit {
+            self.responses.push(0x9b);
+        } else {
+            self.responses.extend_from_slice(b"\x1b[");
+        }
+        self.responses.extend_from_slice(body.as_bytes());
+    }
+
+    /// Queues a device control string reply, in 7-bit or 8-bit form like `respond_csi`.
+    fn respond_dcs(&mut self, body: &str) {
+        let (start, end): (&[u8], &[u8]) = if self.c1_8bit { (b"\x90", b"\x9c") } else { (b"\x1bP", b"\x1b\\") };
+        self.responses.extend_from_slice(start);
+        self.responses.extend_from_slice(body.as_bytes());
+        self.responses.extend_from_slice(end);
+    }
+
+    /// Primary device attributes, answered for both `CSI c` and DECID (`ESC Z`):
+    /// a VT420-class terminal (the rectangle operations are VT420 ones) followed
+    /// by `DA_EXTENSIONS`.
+    fn device_attributes(&mut self) {
+        self.respond_csi(&da_reply(DA_EXTENSIONS));
+    }
+
+    /// DSR (`CSI Ps n`): 5 asks for the status, always OK, and 6 for the cursor
+    /// position (CPR), relative to the scroll region in origin mode. The DEC form
+    /// (`CSI ? 6 n`) gets DECXCPR, which adds the page. Nothing is answered in safe
+    /// mode.
+    fn device_status_report(&mut self, request: usize, private: bool) {
+        if self.safe_mode {
+            return;
+        }
+        match request {
+            5 if !private => self.respond_csi("0n"),
+            6 => {
+                let row = if self.origin_mode { self.cursor_y.saturating_sub(self.scroll_top) } else { self.cursor_y };
+                let page = if private { ";1" } else { "" };
+                self.respond_csi(&format!("{}{};{}{}R", if private { "?" } else { "" }, row + 1, self.cursor_x + 1, page));
+            }
+            _ => {}
+        }
+    }
+
+    /// DECSCL (`CSI Pl ; Pc " p`). A VT100 level always uses 7-bit controls;
+    /// higher levels use 8-bit unless `Pc` is 1.
+    fn set_conformance_level(&mut self, level: u16, controls: u16) {
+        if !(61..=65).contains(&level) {
+            return;
+        }
+        self.conformance_level = level;
+        self.c1_8bit = level > 61 && controls != 1;
+    }
+
+    fn take_exit_status(&mut self) -> Option<i32> {
+        self.exit_status.take()
+    }
+
+    /// OSC 7 (`file://host/path`) current directory report.
+    fn set_cwd(&mut self, params: &[&[u8]]) {
+        let Some(url) = params.get(1).and_then(|url| std::str::from_utf8(url).ok()) else { return };
+        let Some(rest) = url.strip_prefix("file://") else { return };
+        if let Some(start) = rest.find('/') {
+            self.cwd = Some(PathBuf::from(percent_decode(&rest[start..])));
+        }
+    }
+
+    fn semantic_prompt(&mut self, params: &[&[u8]]) {
+        match params.get(1) {
+            Some(&b"A") => self.prompt_row = Some(self.cursor_y),
+            Some(&b"C") if std::mem::take(&mut self.capture_armed) => self.capture = Some(String::new()),
+            Some(&b"D") => {
+                self.exit_status = params.get(2)
+                    .and_then(|code| std::str::from_utf8(code).ok())
+                    .and_then(|code| code.parse().ok());
+                if let Some(output) = self.capture.take() {
+                    self.captured = Some(output);
+                }
+                if let (Some(row), Some(code)) = (self.prompt_row.take(), self.exit_status) {
+                    self.exit_badges[row] = Some(ExitBadge { status: code, folded: false });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Where `query` occurs in the scrollback and on the screen, as (line, column)
+    /// cells: lines count scrollback rows oldest first, then screen rows. Matches
+    /// don't run across rows. Case is ignored unless `case_sensitive`.
+    fn search(&self, query: &str, case_sensitive: bool) -> Vec<(usize, usize)> {
+        let fold = |c: char| if case_sensitive { c } else { c.to_lowercase().next().unwrap_or(c) };
+        let needle: Vec<char> = query.chars().map(fold).collect();
+        if needle.is_empty() {
+            return Vec::new();
+        }
+        let rows = self.scrollback.iter().map(|line| &line.chars[..]).chain(self.screen.chunks(self.width));
+        let mut hits = Vec::new();
+        for (line, row) in rows.enumerate() {
+            let (text, cols): (Vec<char>, Vec<usize>) = row.iter()
+                .enumerate()
+                .filter(|&(_, &c)| c != WIDE_SPACER)
+                .map(|(col, &c)| (fold(if c == '\t' { ' ' } else { c }), col))
+                .unzip();
+            for start in 0..text.len() {
+                if text[start..].starts_with(&needle) {
+                    hits.push((line, cols[start]));
+                }
+            }
+        }
+        hits
+    }
+
+    /// Folds or unfolds the output of the command whose prompt is on `row`.
+    fn toggle_fold(&mut self, row: usize) {
+        if let Some(Some(badge)) = self.exit_badges.get_mut(row) {
+            badge.folded = !badge.folded;
+        }
+    }
+
+    /// Rows hidden by folded commands: each runs from below its prompt row to the
+    /// next prompt (the next badge, or the live prompt), else to the cursor row.
+    /// Empty when nothing is folded.
+    fn folded_rows(&self) -> Vec<bool> {
+        if !self.exit_badges.iter().flatten().any(|badge| badge.folded) {
+            return Vec::new();
+        }
+        let mut hidden = vec![false; self.height];
+        for (row, badge) in self.exit_badges.iter().enumerate() {
+            if !badge.is_some_and(|badge| badge.folded) {
+                continue;
+            }
+            let end = (row + 1..self.height)
+                .find(|&next| self.exit_badges[next].is_some() || self.prompt_row == Some(next))
+                .unwrap_or(if self.cursor_y > row { self.cursor_y } else { self.height });
+            hidden[row + 1..end].fill(true);
+        }
+        hidden
+    }
+
+    fn take_captured(&mut self) -> Option<String> {
+        self.captured.take()
+    }
+
+    /// OSC 9 (`9 ; message`) and OSC 777 (`777 ; notify ; title ; body`). A message
+    /// split on `;` by the parser is joined back together. ConEmu's `OSC 9 ; <n> ; ...`
+    /// progress and status forms are not notifications and are skipped.
+    fn notify(&mut self, params: &[&[u8]]) {
+        if self.safe_mode {
+            return;
+        }
+        let text = |parts: &[&[u8]]| parts.iter().map(|p| String::from_utf8_lossy(p)).collect::<Vec<_>>().join(";");
+        let notification = match params {
+            [b"9", first, ..] if !first.iter().all(u8::is_ascii_digit) => Notification::new("PhantomTTY", &text(&params[1..])),
+            [b"777", b"notify", title, body @ ..] => Notification::new(&String::from_utf8_lossy(title), &text(body)),
+            _ => return,
+        };
+        self.notifications.push(notification);
+    }
+
+    fn set_clipboard(&mut self, params: &[&[u8]]) {
+        if self.safe_mode {
+            return;
+        }
+        let Some(data) = params.get(2) else { return };
+        if *data == b"?" {
+            return;
+        }
+        match base64::engine::general_purpose::STANDARD.decode(data) {
+            Ok(bytes) => self.clipboard_writes.push(String::from_utf8_lossy(&bytes).into_owned()),
+            Err(e) => log::warn!("Invalid OSC 52 payload: {}", e),
+        }
+    }
+    
+    fn get_screen(&self) -> String {
+        self.screen_text().replace('\t', " ").replace(WIDE_SPACER, "")
+    }
+
+    /// Screen rows as text, keeping the `'\t'` markers at tab gaps for the renderer.
+    fn screen_text(&self) -> String {
+        let mut output = String::with_capacity(self.width * self.height);
+        for chunk in self.screen.chunks(self.width) {
+            output.extend(chunk.iter());
+            output.push('\n');
+        }
+        output
+    }
+
+    /// Screen text for copying, with trailing blanks trimmed. A gap made by a tab
+    /// (marked by `'\t'` in its first cell) becomes a literal tab, or the spaces it
+    /// spans when `tabs_as_spaces` is set.
+    fn copy_text(&self, tabs_as_spaces: bool) -> String {
+        let mut text = String::new();
+        for (row, cells) in self.screen.chunks(self.width).zip(self.cells.chunks(self.width)) {
+            text.push_str(&self.line_text(row, cells, tabs_as_spaces));
+            text.push('\n');
+        }
+        text.trim_end_matches('\n').to_string()
+    }
+
+    /// Scrollback followed by the screen, laid out as `copy_text` does, for autosave.
+    fn transcript(&self, tabs_as_spaces: bool) -> String {
+        let mut text = String::new();
+        for line in &self.scrollback {
+            text.push_str(&self.line_text(&line.chars, &line.cells, tabs_as_spaces));
+            text.push('\n');
+        }
+        text.push_str(&self.copy_text(tabs_as_spaces));
+        text.push('\n');
+        text
+    }
+
+    /// One row's text for copying, trailing blanks trimmed.
+    fn line_text(&self, row: &[char], cells: &[Cell], tabs_as_spaces: bool) -> String {
+        let mut line = String::new();
+        let mut col = 0;
+        while col < row.len() {
+            if row[col] == '\t' {
+                let stop = self.next_tab_stop(col, row.len());
+                let gap = row[col..stop].iter().skip(1).take_while(|&&c| c == ' ').count() + 1;
+                if tabs_as_spaces {
+                    line.extend(std::iter::repeat_n(' ', gap));
+                } else {
+                    line.push('\t');
+                }
+                col += gap;
+            } else {
+                if row[col] != WIDE_SPACER {
+                    line.push(row[col]);
+                    line.push_str(cells.get(col).map_or("", |&cell| self.cluster_tail(cell)));
+                }
+                col += 1;
+            }
+        }
+        line.truncate(line.trim_end_matches(' ').len());
+        line
+    }
+
+    /// Replaces the tab stops with one every `width` columns.
+    fn set_tab_width(&mut self, width: usize) {
+        self.tab_width = width;
+        self.tab_stops = (0..self.width).map(|col| col > 0 && col % width == 0).collect();
+    }
+
+    /// First tab stop after `col`, or `limit` when there is none before it.
+    fn next_tab_stop(&self, col: usize, limit: usize) -> usize {
+        (col + 1..limit).find(|&c| self.tab_stops.get(c).copied().unwrap_or(false)).unwrap_or(limit)
+    }
+
+    /// HT: advance to the next tab stop, marking the gap so copies can restore the tab.
+    fn tab(&mut self) {
+        let row_width = self.row_width(self.cursor_y);
+        let next = self.next_tab_stop(self.cursor_x, row_width - 1);
+        if next > self.cursor_x {
+            let pos = self.cursor_y * self.width + self.cursor_x;
+            if self.screen[pos] == ' ' {
+                self.screen[pos] = '\t';
+            }
+            self.cursor_x = next;
+        }
+    }
+
+    fn clear_screen(&mut self) {
+        self.screen = vec![' '; self.width * self.height];
+        self.cells = vec![self.blank_cell(); self.width * self.height];
+        self.line_attrs.fill(LineAttr::Single);
+        self.exit_badges.fill(None);
+        self.wrapped.fill(false);
+        self.prompt_row = None;
+        self.wrap_pending = false;
+        self.cursor_x = 0;
+        self.cursor_y = 0;
+    }
+
+    /// Usable columns on `row`; double-width and double-height rows hold half as many.
+    fn row_width(&self, row: usize) -> usize {
+        match self.line_attrs[row] {
+            LineAttr::Single => self.width,
+            _ => (self.width / 2).max(1),
+        }
+    }
+
+    /// DECSTBM (`CSI Pt ; Pb r`). A missing or zero top means row 1 and a missing or
+    /// zero bottom means the last row; a region that isn't at least two rows is ignored.
+    fn set_scroll_region(&mut self, top: usize, bottom: usize) {
+        let top = top.max(1);
+        let bottom = if bottom == 0 { self.height } else { bottom.min(self.height) };
+        if top >= bottom {
+            return;
+        }
+        self.scroll_top = top - 1;
+        self.scroll_bottom = bottom - 1;
+        self.cursor_home();
+    }
+
+    /// Moves to row 1, column 1, which is the top margin in origin mode.
+    fn cursor_home(&mut self) {
+        self.wrap_pending = false;
+        self.cursor_y = if self.origin_mode { self.scroll_top } else { 0 };
+        self.cursor_x = 0;
+    }
+
+    fn move_cursor(&mut self, row: usize, col: usize) {
+        self.cursor_y = row.min(self.height - 1);
+        self.cursor_x = col.min(self.row_width(self.cursor_y) - 1);
+    }
+
+    fn resize(&mut self, width: usize, height: usize) {
+        let width = width.max(1);
+        let height = height.max(1);
+        self.screen = reshape_grid(&self.screen, self.width, width, height, ' ');
+        self.cells = reshape_grid(&self.cells, self.width, width, height, Cell::default());
+        self.line_attrs.resize(height, LineAttr::Single);
+        self.exit_badges.resize(height, None);
+        self.wrapped.resize(height, false);
+        self.prompt_row = self.prompt_row.filter(|&row| row < height);
+        let tab_width = self.tab_width;
+        self.tab_stops.truncate(width);
+        let kept = self.tab_stops.len();
+        self.tab_stops.extend((kept..width).map(|col| col % tab_width == 0));
+        if let Some(saved) = self.saved_screen.as_mut() {
+            saved.screen = reshape_grid(&saved.screen, self.width, width, height, ' ');
+            saved.cells = reshape_grid(&saved.cells, self.width, width, height, Cell::default());
+            saved.line_attrs.resize(height, LineAttr::Single);
+            saved.exit_badges.resize(height, None);
+            saved.wrapped.resize(height, false);
+            saved.prompt_row = saved.prompt_row.filter(|&row| row < height);
+            saved.cursor_x = saved.cursor_x.min(width - 1);
+            saved.cursor_y = saved.cursor_y.min(height - 1);
+        }
+        self.width = width;
+        self.height = height;
+        self.scroll_top = 0;
+        self.scroll_bottom = height - 1;
+        self.cursor_x = self.cursor_x.min(width - 1);
+        self.cursor_y = self.cursor_y.min(height - 1);
+        self.wrap_pending = false;
+    }
+
+    /// Moves the rows between the margins up one row, blanking the bottom margin row.
+    /// A row leaving the top of the screen goes to scrollback.
+    fn scroll_up(&mut self) {
+        if self.scroll_top == 0 {
+            self.save_to_scrollback(1);
+        }
+        self.scroll_rows_up(self.scroll_top, self.scroll_bottom, 1);
+    }
+
+    /// Copies the top `n` rows to scrollback, except on the alternate screen, whose
+    /// rows are never kept.
+    fn save_to_scrollback(&mut self, n: usize) {
+        if self.alt_screen || self.scrollback_limit == 0 {
+            return;
+        }
+        for row in 0..n.min(self.height) {
+            if self.scrollback.len() >= self.scrollback_limit {
+                self.scrollback.pop_front();
+            }
+            self.scrollback.push_back(ScrollbackLine {
+                chars: self.screen[row * self.width..(row + 1) * self.width].to_vec(),
+                cells: self.cells[row * self.width..(row + 1) * self.width].to_vec(),
+                wrapped: self.wrapped[row],
+            });
+            self.scrolled_rows += 1;
+        }
+    }
+
+    /// Scrollback and the main screen down to its last non-blank row (or the
+    /// cursor), for `restore_session`. On the alternate screen only scrollback is kept.
+    fn snapshot(&self) -> SessionSnapshot {
+        let line = |chars: &[char], cells: &[Cell], wrapped: bool| {
+            let mut styles: Vec<(usize, Cell)> = Vec::new();
+            for &cell in cells {
+                match styles.last_mut() {
+                    Some((len, style)) if *style == cell => *len += 1,
+                    _ => styles.push((1, cell)),
+                }
+            }
+            SnapshotLine { text: chars.iter().collect(), styles, wrapped }
+        };
+        let mut lines: Vec<SnapshotLine> = self.scrollback.iter()
+            .map(|saved| line(&saved.chars, &saved.cells, saved.wrapped))
+            .collect();
+        if !self.alt_screen {
+            let last = (0..self.height).rev()
+                .find(|&row| self.screen[row * self.width..(row + 1) * self.width].iter().any(|&c| c != ' '))
+                .map_or(self.cursor_y, |row| row.max(self.cursor_y));
+            lines.extend((0..=last).map(|row| {
+                let range = row * self.width..(row + 1) * self.width;
+                line(&self.screen[range.clone()], &self.cells[range], self.wrapped[row])
+            }));
+        }
+        SessionSnapshot { clusters: self.clusters.clone(), lines }
+    }
+
+    /// Appends a snapshot's lines to scrollback, keeping within `scrollback_limit`.
+    fn restore_snapshot(&mut self, snapshot: SessionSnapshot) {
+        let ids: Vec<u16> = snapshot.clusters.iter()
+            .map(|tail| self.intern_cluster(tail).unwrap_or(0))
+            .collect();
+        let skip = snapshot.lines.len().saturating_sub(self.scrollback_limit);
+        for saved in snapshot.lines.into_iter().skip(skip) {
+            let chars: Vec<char> = saved.text.chars().collect();
+            let mut cells: Vec<Cell> = saved.styles.iter()
+                .flat_map(|&(len, cell)| std::iter::repeat_n(cell, len))
+                .map(|mut cell| {
+                    cell.cluster = usize::from(cell.cluster).checked_sub(1)
+                        .and_then(|i| ids.get(i)).copied().unwrap_or(0);
+                    cell
+                })
+                .collect();
+            cells.resize(chars.len(), Cell::default());
+            while self.scrollback.len() >= self.scrollback_limit {
+                self.scrollback.pop_front();
+            }
+            self.scrollback.push_back(ScrollbackLine { chars, cells, wrapped: saved.wrapped });
+        }
+    }
+
+    /// Moves rows `top..=bottom` up `n` rows, blanking the rows uncovered at the bottom.
+    fn scroll_rows_up(&mut self, top: usize, bottom: usize, n: usize) {
+        let n = n.min(bottom + 1 - top);
+        let (start, end) = (top * self.width, (bottom + 1) * self.width);
+        let blank = self.blank_cell();
+        self.screen[start..end].rotate_left(n * self.width);
+        self.screen[end - n * self.width..end].fill(' ');
+        self.cells[start..end].rotate_left(n * self.width);
+        self.cells[end - n * self.width..end].fill(blank);
+        self.line_attrs[top..=bottom].rotate_left(n);
+        self.line_attrs[bottom + 1 - n..=bottom].fill(LineAttr::Single);
+        self.exit_badges[top..=bottom].rotate_left(n);
+        self.exit_badges[bottom + 1 - n..=bottom].fill(None);
+        self.wrapped[top..=bottom].rotate_left(n);
+        self.wrapped[bottom + 1 - n..=bottom].fill(false);
+        self.prompt_row = match self.prompt_row {
+            Some(row) if (top..=bottom).contains(&row) => row.checked_sub(n).filter(|&row| row >= top),
+            other => other,
+        };
+    }
+
+    /// Scrolls the rows above the cursor off the top, leaving the cursor row (usually
+    /// the prompt) first on the screen.
+    fn scroll_clear(&mut self) {
+        self.save_to_scrollback(self.cursor_y);
+        self.scroll_rows_up(0, self.height - 1, self.cursor_y);
+        self.cursor_y = 0;
+    }
+
+    /// Moves the rows between the margins down `n` rows, blanking the rows uncovered
+    /// at the top margin.
+    fn scroll_down(&mut self, n: usize) {
+        self.scroll_rows_down(self.scroll_top, self.scroll_bottom, n);
+    }
+
+    /// Moves rows `top..=bottom` down `n` rows, blanking the rows uncovered at the top.
+    fn scroll_rows_down(&mut self, top: usize, bottom: usize, n: usize) {
+        let n = n.min(bottom + 1 - top);
+        let (start, end) = (top * self.width, (bottom + 1) * self.width);
+        let blank = self.blank_cell();
+        self.screen[start..end].rotate_right(n * self.width);
+        self.screen[start..start + n * self.width].fill(' ');
+        self.cells[start..end].rotate_right(n * self.width);
+        self.cells[start..start + n * self.width].fill(blank);
+        self.line_attrs[top..=bottom].rotate_right(n);
+        self.line_attrs[top..top + n].fill(LineAttr::Single);
+        self.exit_badges[top..=bottom].rotate_right(n);
+        self.exit_badges[top..top + n].fill(None);
+        self.wrapped[top..=bottom].rotate_right(n);
+        self.wrapped[top..top + n].fill(false);
+        self.prompt_row = match self.prompt_row {
+            Some(row) if (top..=bottom).contains(&row) => Some(row + n).filter(|&row| row <= bottom),
+            other => other,
+        };
+    }
+
+    /// DECRC (`ESC 8` / `CSI u`). The grid may have shrunk since the save, so the
+    /// position is clamped to it; with nothing saved the cursor goes home.
+    fn restore_cursor(&mut self) {
+        let (x, y) = self.saved_cursor.unwrap_or((0, 0));
+        self.cursor_x = x.min(self.width - 1);
+        self.cursor_y = y.min(self.height - 1);
+        self.wrap_pending = false;
+    }
+
+    /// IL / DL (`CSI Ps L` / `CSI Ps M`): inserts or deletes `n` rows at the cursor,
+    /// moving the rows below it within the scroll region. Outside the region they do
+    /// nothing.
+    fn insert_delete_lines(&mut self, n: usize, insert: bool) {
+        if !(self.scroll_top..=self.scroll_bottom).contains(&self.cursor_y) {
+            return;
+        }
+        if insert {
+            self.scroll_rows_down(self.cursor_y, self.scroll_bottom, n);
+        } else {
+            self.scroll_rows_up(self.cursor_y, self.scroll_bottom, n);
+        }
+        self.cursor_x = 0;
+        self.wrap_pending = false;
+    }
+
+    /// ICH / DCH (`CSI Ps @` / `CSI Ps P`): inserts `n` blanks at the cursor, pushing
+    /// the rest of the row right, or deletes `n` cells there, pulling it left. Cells
+    /// pushed past the row's end are lost; vacated ones are blanked.
+    fn insert_delete_chars(&mut self, n: usize, insert: bool) {
+        let row_start = self.cursor_y * self.width;
+        let pos = row_start + self.cursor_x;
+        let end = row_start + self.row_width(self.cursor_y);
+        let n = n.min(end.saturating_sub(pos));
+        if n == 0 {
+            return;
+        }
+        self.split_wide_glyph(pos);
+        // Don't cut a glyph at the edge of the cells being dropped.
+        self.split_wide_glyph(if insert { end - n } else { pos + n - 1 });
+        let blank = self.blank_cell();
+        if insert {
+            self.screen[pos..end].rotate_right(n);
+            self.cells[pos..end].rotate_right(n);
+            self.screen[pos..pos + n].fill(' ');
+            self.cells[pos..pos + n].fill(blank);
+        } else {
+            self.screen[pos..end].rotate_left(n);
+            self.cells[pos..end].rotate_left(n);
+            self.screen[end - n..end].fill(' ');
+            self.cells[end - n..end].fill(blank);
+        }
+        self.wrap_pending = false;
+    }
+
+    /// Moves the cursor down a row; at the bottom margin the region scrolls instead.
+    /// Below the region the cursor stops at the last row.
+    fn line_feed(&mut self) {
+        if self.cursor_y == self.scroll_bottom {
+            self.scroll_up();
+        } else if self.cursor_y + 1 < self.height {
+            self.cursor_y += 1;
+        }
+    }
+
+    /// Moves the cursor up a row; at the top margin the region scrolls down instead.
+    /// Above the region the cursor stops at the first row.
+    fn reverse_index(&mut self) {
+        if self.cursor_y == self.scroll_top {
+            self.scroll_down(1);
+        } else {
+            self.cursor_y = self.cursor_y.saturating_sub(1);
+        }
+    }
+
+    /// Screen rows as text with their wrap flags, for reflowing.
+    fn wrapped_rows(&self) -> Vec<(String, bool)> {
+        let history = self.get_scrollback_lines(0, self.scrollback.len()).into_iter()
+            .zip(&self.scrollback)
+            .map(|(text, line)| if line.wrapped { (text, true) } else { (text.trim_end().to_string(), false) });
+        history.chain((0..self.height).map(|row| {
+            let text = if self.wrapped[row] {
+                grid_text(&self.screen[row * self.width..(row + 1) * self.width])
+            } else {
+                self.row_text(row)
+            };
+            (text, self.wrapped[row])
+        })).collect()
+    }
+
+    /// Up to `count` scrollback rows from `start` (0 is the oldest), as full-width
+    /// text with tab gaps as spaces.
+    fn get_scrollback_lines(&self, start: usize, count: usize) -> Vec<String> {
+        self.scrollback.iter()
+            .skip(start)
+            .take(count)
+            .map(|line| grid_text(&line.chars))
+            .collect()
+    }
+
+    /// The whitespace-delimited word at a cell, without surrounding quotes, brackets
+    /// or trailing punctuation.
+    fn token_at(&self, row: usize, col: usize) -> Option<String> {
+        let line: Vec<char> = self.screen.get(row * self.width..(row + 1) * self.width)?.to_vec();
+        if line.get(col).is_none_or(|c| c.is_whitespace()) {
+            return None;
+        }
+        let start = line[..col].iter().rposition(|c| c.is_whitespace()).map_or(0, |i| i + 1);
+        let end = line[col..].iter().position(|c| c.is_whitespace()).map_or(line.len(), |i| col + i);
+        let token: String = line[start..end].iter().filter(|&&c| c != WIDE_SPACER).collect();
+        let token = token.trim_start_matches(['\'', '"', '(', '[', '<'])
+            .trim_end_matches(['\'', '"', ')', ']', '>', ',', ';', ':', '.']);
+        (!token.is_empty()).then(|| token.to_string())
+    }
+
+    /// Text of one screen row, with tab gaps as spaces and trailing blanks trimmed.
+    fn row_text(&self, row: usize) -> String {
+        grid_text(&self.screen[row * self.width..(row + 1) * self.width]).trim_end().to_string()
+    }
+
+    fn set_line_attr(&mut self, attr: LineAttr) {
+        self.line_attrs[self.cursor_y] = attr;
+        if attr != LineAttr::Single {
+            let row_start = self.cursor_y * self.width;
+            let half = self.row_width(self.cursor_y);
+            if half < self.width {
+                self.split_wide_glyph(row_start + half);
+            }
+            self.screen[row_start + half..row_start + self.width].fill(' ');
+            self.cells[row_start + half..row_start + self.width].fill(Cell::default());
+            self.cursor_x = self.cursor_x.min(half - 1);
+            self.wrap_pending = false;
+        }
+    }
+
+    /// Turns 1-based `[top, left, bottom, right]` rectangle parameters into 0-based
+    /// row and column ranges. Zero or missing edges default to the screen's; in origin
+    /// mode rows count from the top margin. The result is clamped to the grid.
+    fn rect_bounds(&self, edges: [usize; 4]) -> (std::ops::Range<usize>, std::ops::Range<usize>) {
+        let origin = if self.origin_mode { self.scroll_top } else { 0 };
+        let edge = |n: usize, default: usize| if n == 0 { default } else { n };
+        let top = origin + edge(edges[0], 1) - 1;
+        let left = edge(edges[1], 1) - 1;
+        let bottom = (origin + edge(edges[2], self.height)).min(self.height);
+        let right = edge(edges[3], self.width).min(self.width);
+        (top..bottom.max(top), left..right.max(left))
+    }
+
+    /// DECERA/DECFRA: fills a rectangle with `c` in the style `cell`; `c` must be
+    /// single-width. A double-width glyph cut by the rectangle's left or right edge
+    /// loses its outside half too.
+    fn fill_rect(&mut self, edges: [usize; 4], c: char, cell: Cell) {
+        if c.width() != Some(1) {
+            return;
+        }
+        let (rows, cols) = self.rect_bounds(edges);
+        if cols.is_empty() {
+            return;
+        }
+        for row in rows {
+            let start = row * self.width;
+            self.split_wide_glyph(start + cols.start);
+            self.split_wide_glyph(start + cols.end - 1);
+            self.screen[start + cols.start..start + cols.end].fill(c);
+            self.cells[start + cols.start..start + cols.end].fill(cell);
+        }
+    }
+
+    /// DECRQCRA: replies `DCS Pid ! ~ xxxx ST` with the 16-bit negated sum of the
+    /// characters in a rectangle, as the VT420 and xterm do.
+    fn report_rect_checksum(&mut self, id: usize, edges: [usize; 4]) {
+        let (rows, cols) = self.rect_bounds(edges);
+        let mut sum: u16 = 0;
+        for row in rows {
+            for &c in &self.screen[row * self.width + cols.start..row * self.width + cols.end] {
+                let c = if c == '\t' { ' ' } else { c };
+                sum = sum.wrapping_add(c as u32 as u16);
+            }
+        }
+        self.respond_dcs(&format!("{}!~{:04X}", id, sum.wrapping_neg()));
+    }
+
+    /// SL/SR (`CSI Ps SP @` / `CSI Ps SP A`): shifts every row of the scroll region
+    /// `n` columns left or right, blanking the columns left behind.
+    fn scroll_columns(&mut self, n: usize, left: bool) {
+        let n = n.min(self.width);
+        let blank = self.blank_cell();
+        for row in self.scroll_top..=self.scroll_bottom {
+            let range = row * self.width..(row + 1) * self.width;
+            let (screen, cells) = (&mut self.screen[range.clone()], &mut self.cells[range]);
+            // A wide glyph straddling the cut loses both halves.
+            let cut = if left { n } else { self.width - n };
+            if (1..self.width).contains(&cut) && screen[cut] == WIDE_SPACER {
+                screen[cut - 1..=cut].fill(' ');
+                cells[cut - 1..=cut].fill(blank);
+            }
+            if left {
+                screen.rotate_left(n);
+                cells.rotate_left(n);
+                screen[self.width - n..].fill(' ');
+                cells[self.width - n..].fill(blank);
+            } else {
+                screen.rotate_right(n);
+                cells.rotate_right(n);
+                screen[..n].fill(' ');
+                cells[..n].fill(blank);
+            }
+            // A tab gap split by the shift no longer belongs to a tab.
+            if screen.first() == Some(&'\t') {
+                screen[0] = ' ';
+            }
+        }
+    }
+
+    /// The characters after `cell`'s own in its grapheme cluster.
+    fn cluster_tail(&self, cell: Cell) -> &str {
+        usize::from(cell.cluster).checked_sub(1).and_then(|i| self.clusters.get(i)).map_or("", String::as_str)
+    }
+
+    /// The `Cell::cluster` id for `tail`, adding it to the table if new; `None`
+    /// once the table is full.
+    fn intern_cluster(&mut self, tail: &str) -> Option<u16> {
+        match self.clusters.iter().position(|known| known == tail) {
+            Some(i) => Some(i as u16 + 1),
+            None if self.clusters.len() < usize::from(u16::MAX) => {
+                self.clusters.push(tail.to_string());
+                Some(self.clusters.len() as u16)
+            }
+            None => None,
+        }
+    }
+
+    /// Adds `c` to the cluster of the glyph just printed if it continues it (a
+    /// combining mark, a ZWJ sequence, the second half of a flag). A cluster that
+    /// grows to double width takes the next cell too, when the cursor is on it.
+    fn join_cluster(&mut self, c: char) -> bool {
+        let Some(pos) = self.last_glyph else { return false };
+        let base = self.screen[pos];
+        let mut text = String::from(base);
+        text.push_str(self.cluster_tail(self.cells[pos]));
+        text.push(c);
+        if text.graphemes(true).count() != 1 {
+            return false;
+        }
+        // When the table is full the mark is dropped, but still doesn't take a cell.
+        let Some(id) = self.intern_cluster(&text[base.len_utf8()..]) else { return true };
+        self.cells[pos].cluster = id;
+        let wide = self.screen.get(pos + 1) == Some(&WIDE_SPACER) && !(pos + 1).is_multiple_of(self.width);
+        let cursor = self.cursor_y * self.width + self.cursor_x;
+        if !wide && text.width() >= 2 && !self.wrap_pending && cursor == pos + 1 {
+            self.split_wide_glyph(cursor);
+            self.screen[cursor] = WIDE_SPACER;
+            self.cells[cursor] = self.cells[pos];
+            if self.cursor_x + 1 >= self.row_width(self.cursor_y) {
+                self.wrap_pending = self.autowrap;
+            } else {
+                self.cursor_x += 1;
+            }
+        }
+        true
+    }
+
+    /// Before `pos` is overwritten, blanks the other half of a double-width glyph it
+    /// belongs to, so no half glyph or orphaned spacer is left behind.
+    fn split_wide_glyph(&mut self, pos: usize) {
+        if self.screen[pos] == WIDE_SPACER && !pos.is_multiple_of(self.width) {
+            self.screen[pos - 1] = ' ';
+        } else if self.screen.get(pos + 1) == Some(&WIDE_SPACER) && !(pos + 1).is_multiple_of(self.width) {
+            self.screen[pos + 1] = ' ';
+        }
+    }
+
+    /// An erased cell: blank, keeping only the pen's background (as xterm does).
+    fn blank_cell(&self) -> Cell {
+        Cell { bg: self.pen.bg, ..Cell::default() }
+    }
+
+    /// Blanks a range of cells. A selective erase (DECSED/DECSEL) leaves protected
+    /// cells alone; an ordinary one clears them along with their protection.
+    fn erase_cells(&mut self, range: std::ops::Range<usize>, selective: bool) {
+        let blank = self.blank_cell();
+        for pos in range {
+            if selective && self.cells[pos].protected {
+                continue;
+            }
+            self.screen[pos] = ' ';
+            self.cells[pos] = blank;
+        }
+    }
+
+    fn set_title(&mut self, title: String) {
+        if self.safe_mode {
+            return;
+        }
+        self.title_changed = true;
+        self.title = title;
+    }
+
+    /// XTWINOPS (`CSI Ps ; Ps ; Ps t`). Only the operations that stay inside the
+    /// terminal are acted on: the size report, the title stack, and resizing when
+    /// `allow_window_resize` is set. Moving, raising, iconifying and the rest are
+    /// logged and ignored.
+    fn window_op(&mut self, params: &Params) {
+        let arg = |idx: usize| params.iter().nth(idx).and_then(|p| p.first()).map_or(0, |&n| n as usize);
+        match arg(0) {
+            8 if self.allow_window_resize && !self.safe_mode => {
+                let rows = match arg(1) { 0 => self.height, n => n.min(u16::MAX as usize) };
+                let cols = match arg(2) { 0 => self.width, n => n.min(u16::MAX as usize) };
+                self.resize_request = Some((cols, rows));
+            }
+            18 => self.respond_csi(&format!("8;{};{}t", self.height, self.width)),
+            22 => {
+                if self.title_stack.len() == TITLE_STACK_DEPTH {
+                    self.title_stack.remove(0);
+                }
+                self.title_stack.push(self.title.clone());
+            }
+            23 => {
+                if let Some(title) = self.title_stack.pop() {
+                    self.set_title(title);
+                }
+            }
+            op => log::debug!("Ignoring window operation CSI {} t", op),
+        }
+    }
+
+    /// XTPUSHSGR (`CSI Pm # {`): saves the pen, or only the parts named by the
+    /// parameters (SGR codes for attributes, 10 for fg, 11 for bg).
+    fn push_sgr(&mut self, params: &Params) {
+        let selected: Vec<u16> = params.iter().filter_map(|p| p.first().copied()).filter(|&n| n != 0).collect();
+        let (attrs, fg, bg) = if selected.is_empty() {
+            (!0, true, true)
+        } else {
+            let attrs = selected.iter().fold(0, |mask, code| mask | match code {
+                1 => Cell::BOLD,
+                2 => Cell::FAINT,
+                3 => Cell::ITALIC,
+                4 => Cell::UNDERLINE,
+                7 => Cell::REVERSE,
+                _ => 0,
+            });
+            (attrs, selected.contains(&10), selected.contains(&11))
+        };
+        if self.sgr_stack.len() == SGR_STACK_DEPTH {
+            self.sgr_stack.remove(0);
+        }
+        self.sgr_stack.push((self.pen, attrs, fg, bg));
+    }
+
+    /// XTPOPSGR (`CSI # }`): restores what the latest push saved.
+    fn pop_sgr(&mut self) {
+        let Some((saved, attrs, fg, bg)) = self.sgr_stack.pop() else { return };
+        self.pen.attrs = (self.pen.attrs & !attrs) | (saved.attrs & attrs);
+        if fg {
+            self.pen.fg = saved.fg;
+        }
+        if bg {
+            self.pen.bg = saved.bg;
+        }
+    }
+
+    /// SGR (`CSI Pm m`). Extended colors (`38`/`48`) come either as one parameter
+    /// with colon-separated parts or spread over the parameters after them, which
+    /// are consumed so they aren't read as attributes.
+    fn select_graphic_rendition(&mut self, params: &Params) {
+        let mut params = params.iter();
+        while let Some(param) = params.next() {
+            let code = param.first().copied().unwrap_or(0);
+            match code {
+                0 => self.pen = Cell::default(),
+                1 => self.pen.attrs |= Cell::BOLD,
+                2 => self.pen.attrs |= Cell::FAINT,
+                3 => self.pen.attrs |= Cell::ITALIC,
+                4 => self.pen.attrs |= Cell::UNDERLINE,
+                7 => self.pen.attrs |= Cell::REVERSE,
+                22 => self.pen.attrs &= !(Cell::BOLD | Cell::FAINT),
+                23 => self.pen.attrs &= !Cell::ITALIC,
+                24 => self.pen.attrs &= !Cell::UNDERLINE,
+                27 => self.pen.attrs &= !Cell::REVERSE,
+                30..=37 => self.pen.fg = Color::Indexed(code as u8 - 30),
+                39 => self.pen.fg = Color::Default,
+                40..=47 => self.pen.bg = Color::Indexed(code as u8 - 40),
+                49 => self.pen.bg = Color::Default,
+                90..=97 => self.pen.fg = Color::Indexed(code as u8 - 90 + 8),
+                100..=107 => self.pen.bg = Color::Indexed(code as u8 - 100 + 8),
+                38 | 48 => {
+                    let color = if param.len() > 1 {
+                        extended_color(&param[1..])
+                    } else {
+                        let selector = params.next().and_then(|p| p.first().copied()).unwrap_or(0);
+                        let count = match selector {
+                            5 => 1,
+                            2 => 3,
+                            _ => 0,
+                        };
+                        let args: Vec<u16> = std::iter::once(selector)
+                            .chain(params.by_ref().take(count).map(|p| p.first().copied().unwrap_or(0)))
+                            .collect();
+                        extended_color(&args)
+                    };
+                    match color {
+                        Some(color) if code == 38 => self.pen.fg = color,
+                        Some(color) => self.pen.bg = color,
+                        None => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// ED / DECSED (`CSI [?] Ps J`).
+    fn erase_in_display(&mut self, mode: usize, selective: bool) {
+        let cursor = self.cursor_y * self.width + self.cursor_x;
+        match mode {
+            0 => self.erase_cells(cursor..self.screen.len(), selective),
+            1 => self.erase_cells(0..cursor + 1, selective),
+            2 | 3 if selective => self.erase_cells(0..self.screen.len(), true),
+            2 => self.clear_screen(),
+            3 => {
+                self.clear_screen();
+                self.scrollback.clear();
+            }
+            _ => {}
+        }
+    }
+
+    /// EL / DECSEL (`CSI [?] Ps K`).
+    fn erase_in_line(&mut self, mode: usize, selective: bool) {
+        let row_start = self.cursor_y * self.width;
+        let cursor = row_start + self.cursor_x;
+        let range = match mode {
+            0 => cursor..row_start + self.width,
+            1 => row_start..cursor + 1,
+            2 => row_start..row_start + self.width,
+            _ => return,
+        };
+        if mode != 1 {
+            self.wrapped[self.cursor_y] = false;
+        }
+        self.erase_cells(range, selective);
+    }
+}
+
+/// The primary DA reply body for a VT420-class terminal with `extensions`, so
+/// adding an extension (Sixel's 4, say) only means adding it to `DA_EXTENSIONS`.
+fn da_reply(extensions: &[u16]) -> String {
+    let codes: Vec<String> = std::iter::once(64).chain(extensions.iter().copied()).map(|code| code.to_string()).collect();
+    format!("?{}c", codes.join(";"))
+}
+
+impl Perform for VteTerminal {
+    fn print(&mut self, c: char) {
+        if !c.is_ascii() && self.join_cluster(c) {
+            if let Some(ref mut capture) = self.capture {
+                capture.push(c);
+            }
+            return;
+        }
+        if self.wrap_pending {
+            self.wrap_pending = false;
+            self.wrapped[self.cursor_y] = true;
+            self.cursor_x = 0;
+            self.line_feed();
+        }
+        let mut row_width = self.row_width(self.cursor_y);
+        let wide = c.width() == Some(2) && row_width > 1;
+        if wide && self.cursor_x + 1 >= row_width && !self.autowrap {
+            // Without autowrap the glyph takes the last two columns instead.
+            self.cursor_x = row_width - 2;
+        } else if wide && self.cursor_x + 1 >= row_width {
+            // A wide glyph never splits across rows: the last column is left blank
+            // and the glyph starts the next row.
+            let pos = self.cursor_y * self.width + self.cursor_x;
+            self.split_wide_glyph(pos);
+            self.screen[pos] = ' ';
+            self.cells[pos] = self.blank_cell();
+            self.wrapped[self.cursor_y] = true;
+            self.cursor_x = 0;
+            self.line_feed();
+            row_width = self.row_width(self.cursor_y);
+        }
+        let wide = wide && self.cursor_x + 1 < row_width;
+        let advance = if wide { 2 } else { 1 };
+        let pos = self.cursor_y * self.width + self.cursor_x;
+        if self.insert_mode && pos < self.screen.len() {
+            let row_end = self.cursor_y * self.width + row_width;
+            self.screen.copy_within(pos..row_end - advance, pos + advance);
+            self.cells.copy_within(pos..row_end - advance, pos + advance);
+        }
+        if pos < self.screen.len() {
+            let mut cell = Cell { protected: self.protect_chars, ..self.pen };
+            if self.stderr_output {
+                cell.attrs |= Cell::STDERR;
+            }
+            self.split_wide_glyph(pos);
+            self.screen[pos] = c;
+            self.cells[pos] = cell;
+            self.last_glyph = Some(pos);
+            if wide {
+                self.split_wide_glyph(pos + 1);
+                self.screen[pos + 1] = WIDE_SPACER;
+                self.cells[pos + 1] = cell;
+            }
+        } else {
+            log::warn!("Attempted to print outside screen bounds (x: {}, y: {})", self.cursor_x, self.cursor_y);
+        }
+        if self.cursor_x + advance >= row_width {
+            self.cursor_x = row_width - 1;
+            self.wrap_pending = self.autowrap;
+        } else {
+            self.cursor_x += advance;
+        }
+        self.last_printed = Some(c);
+        if let Some(ref mut capture) = self.capture {
+            capture.push(c);
+        }
+    }
+
+    fn execute(&mut self, byte: u8) {
+        self.last_printed = None;
+        self.last_glyph = None;
+        if matches!(byte, b'\r' | b'\n' | b'\x08' | b'\t' | b'\x0C') {
+            self.wrap_pending = false;
+        }
+        if let (Some(capture), b'\n' | b'\t') = (&mut self.capture, byte) {
+            capture.push(byte as char);
+        }
+        match byte {
+            b'\r' => self.cursor_x = 0,
+            b'\n' => {
+                if self.newline_mode {
+                    self.cursor_x = 0;
+                }
+                self.line_feed();
+            },
+            b'\x08' => self.cursor_x = self.cursor_x.saturating_sub(1),
+            b'\t' => self.tab(),
+            b'\x0C' => self.clear_screen(),
+            _ => {}
+        }
+    }
+
+    fn hook(&mut self, _params: &Params, _intermediates: &[u8], _ignore: bool, _c: char) {}
+    fn put(&mut self, _byte: u8) {}
+    fn unhook(&mut self) {}
+    fn osc_dispatch(&mut self, params: &[&[u8]], _bell_terminated: bool) {
+        match params.first() {
+            // OSC 0 sets the icon name and title, OSC 2 just the title. OSC 1 (icon
+            // name only) has nothing to show on and is dropped.
+            Some(&b"0") | Some(&b"2") => {
+                let title = params[1..].iter().map(|p| String::from_utf8_lossy(p)).collect::<Vec<_>>().join(";");
+                self.set_title(title);
+            }
+            Some(&b"1") => {}
+            // OSC 8 hyperlinks aren't followed; the text between the marks shows as is.
+            Some(&b"8") => {}
+            Some(&b"52") => self.set_clipboard(params),
+            Some(&b"7") => self.set_cwd(params),
+            Some(&b"133") => self.semantic_prompt(params),
+            Some(&b"9") | Some(&b"777") => self.notify(params),
+            _ => {}
+        }
+    }
+    
+    fn csi_dispatch(&mut self, params: &Params, intermediates: &[u8], _ignore: bool, c: char) {
+        self.last_glyph = None;
+        let param = |idx: usize| -> usize {
+            params.iter()
+                .nth(idx)
+                .and_then(|slice| slice.first())
+                .map(|&x| x as usize)
+                .unwrap_or(1)
+        };
+
+        if matches!(c, 'A'..='D' | 'H' | 'f') {
+            self.wrap_pending = false;
+        }
+
+        match c {
+            '@' | 'A' if intermediates == b" " => self.scroll_columns(param(0).max(1), c == '@'),
+            '@' | 'P' if intermediates.is_empty() => self.insert_delete_chars(param(0).max(1), c == '@'),
+            'L' | 'M' if intermediates.is_empty() => self.insert_delete_lines(param(0).max(1), c == 'L'),
+            's' if intermediates.is_empty() => self.saved_cursor = Some((self.cursor_x, self.cursor_y)),
+            'u' if intermediates.is_empty() => self.restore_cursor(),
+            'A' => {
+                let n = param(0);
+                self.cursor_y = self.cursor_y.saturating_sub(n);
+            }
+            'B' => {
+                let n = param(0);
+                self.cursor_y = (self.cursor_y + n).min(self.height - 1);
+            }
+            'C' => {
+                let n = param(0);
+                self.cursor_x = (self.cursor_x + n).min(self.row_width(self.cursor_y) - 1);
+            }
+            'D' => {
+                let n = param(0);
+                self.cursor_x = self.cursor_x.saturating_sub(n);
+            }
+            'H' | 'f' => {
+                let row = param(0).saturating_sub(1);
+                let col = param(1).saturating_sub(1);
+                if self.origin_mode {
+                    self.move_cursor((self.scroll_top + row).min(self.scroll_bottom), col);
+                } else {
+                    self.move_cursor(row, col);
+                }
+            }
+            // TBC: 0 clears the stop at the cursor, 3 clears them all.
+            'g' if intermediates.is_empty() => match param(0) {
+                0 => {
+                    if let Some(stop) = self.tab_stops.get_mut(self.cursor_x) {
+                        *stop = false;
+                    }
+                }
+                3 => self.tab_stops.fill(false),
+                _ => {}
+            },
+            'J' => self.erase_in_display(param(0), intermediates == b"?"),
+            'K' => self.erase_in_line(param(0), intermediates == b"?"),
+            'q' if intermediates == b"\"" => self.protect_chars = param(0) == 1,
+            'q' if intermediates == b" " => {
+                self.cursor_style = match param(0) {
+                    0 => Some(self.default_cursor_style),
+                    ps => CursorStyle::from_decscusr(ps),
+                }.unwrap_or(self.cursor_style);
+            }
+            'p' if intermediates == b"!" => self.soft_reset(),
+            'h' | 'l' if intermediates == b"?" => {
+                for mode in params.iter().filter_map(|p| p.first()) {
+                    self.set_private_mode(*mode, c == 'h');
+                }
+            }
+            'h' | 'l' if intermediates.is_empty() => {
+                for mode in params.iter().filter_map(|p| p.first()) {
+                    self.set_ansi_mode(*mode, c == 'h');
+                }
+            }
+            'p' if intermediates == b"$" || intermediates == b"?$" => {
+                self.report_mode(param(0) as u16, intermediates[0] == b'?');
+            }
+            'c' if intermediates.is_empty() && param(0) <= 1 => self.device_attributes(),
+            'n' if intermediates.is_empty() || intermediates == b"?" => {
+                self.device_status_report(param(0), !intermediates.is_empty());
+            }
+            // `CSI > Ps ; Ps m` (modifyOtherKeys) and `CSI = Ps ; Ps m` share SGR's final
+            // byte but set keyboard options; they must never reach the SGR pen.
+            'm' if !intermediates.is_empty() => {}
+            'm' => self.select_graphic_rendition(params),
+            't' if intermediates.is_empty() => self.window_op(params),
+            't' if intermediates == b" " => log::debug!("Ignoring bell volume CSI {} SP t", param(0)),
+            '{' if intermediates == b"#" => self.push_sgr(params),
+            '}' if intermediates == b"#" => self.pop_sgr(),
+            'r' if intermediates.is_empty() => {
+                let margin = |idx: usize| params.iter().nth(idx).and_then(|p| p.first()).map_or(0, |&n| n as usize);
+                self.set_scroll_region(margin(0), margin(1));
+            }
+            'y' if intermediates == b"*" => {
+                let raw = |idx: usize| params.iter().nth(idx).and_then(|p| p.first()).map_or(0, |&n| n as usize);
+                self.report_rect_checksum(raw(0), [raw(2), raw(3), raw(4), raw(5)]);
+            }
+            'z' | 'x' if intermediates == b"$" => {
+                let raw = |idx: usize| params.iter().nth(idx).and_then(|p| p.first()).map_or(0, |&n| n as usize);
+                // DECERA blanks to the pen's background; DECFRA draws with the whole pen.
+                if c == 'z' {
+                    self.fill_rect([raw(0), raw(1), raw(2), raw(3)], ' ', self.blank_cell());
+                } else if let Some(fill) = char::from_u32(raw(0) as u32).filter(|&ch| matches!(ch, ' '..='~' | '\u{a0}'..='\u{ff}')) {
+                    self.fill_rect([raw(1), raw(2), raw(3), raw(4)], fill, Cell { protected: self.protect_chars, ..self.pen });
+                }
+            }
+            'p' if intermediates == b"\"" => {
+                let controls = params.iter().nth(1).and_then(|p| p.first()).copied().unwrap_or(0);
+                self.set_conformance_level(param(0) as u16, controls);
+            }
+            '~' if matches!(param(0), 200 | 201) => {
+                // Stray bracketed-paste markers (left by a program that exited with
+                // paste mode on) are swallowed so they never render or act as keys.
+            }
+            // With five parameters this is xterm's highlight mouse tracking, not SD.
+            'T' if params.len() <= 1 => self.scroll_down(param(0).max(1)),
+            'b' => {
+                // REP goes through `print` so repeats wrap and size exactly like typed glyphs.
+                if let Some(ch) = self.last_printed {
+                    let count = param(0).max(1).min(self.width * self.height);
+                    for _ in 0..count {
+                        self.print(ch);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn esc_dispatch(&mut self, intermediates: &[u8], _ignore: bool, byte: u8) {
+        self.last_glyph = None;
+        match (intermediates, byte) {
+            ([], b'Z') => self.device_attributes(),
+            ([], b'c') => self.full_reset(),
+            ([], b'7') => self.saved_cursor = Some((self.cursor_x, self.cursor_y)),
+            ([], b'8') => self.restore_cursor(),
+            // IND, NEL and RI.
+            ([], b'D') => {
+                self.wrap_pending = false;
+                self.line_feed();
+            }
+            ([], b'E') => {
+                self.wrap_pending = false;
+                self.cursor_x = 0;
+                self.line_feed();
+            }
+            ([], b'M') => {
+                self.wrap_pending = false;
+                self.reverse_index();
+            }
+            ([], b'H') => {
+                if let Some(stop) = self.tab_stops.get_mut(self.cursor_x) {
+                    *stop = true;
+                }
+            }
+            // S7C1T / S8C1T; a VT100 conformance level only has 7-bit controls.
+            ([b' '], b'F') => self.c1_8bit = false,
+            ([b' '], b'G') => self.c1_8bit = self.conformance_level > 61,
+            ([b'#'], b'3') => self.set_line_attr(LineAttr::DoubleHeightTop),
+            ([b'#'], b'4') => self.set_line_attr(LineAttr::DoubleHeightBottom),
+            ([b'#'], b'5') => self.set_line_attr(LineAttr::Single),
+            ([b'#'], b'6') => self.set_line_attr(LineAttr::DoubleWidth),
+            _ => {}
+        }
+    }
+}
+
+struct PhantomCompleter {
+    filename_completer: FilenameCompleter,
+}
+
+impl rustyline::completion::Completer for PhantomCompleter {
+    type Candidate = rustyline::completion::Pair;
+
+    fn complete(&self, line: &str, pos: usize, ctx: &rustyline::Context<'_>) 
+        -> rustyline::Result<(usize, Vec<Self::Candidate>)> 
+    {
+        if line.starts_with("cd ") || line.contains('/') {
+            self.filename_completer.complete(line, pos, ctx)
+        } else {
+            let commands = ["cd", "ls", "echo", "cat", "grep", "history", "exit"];
+            let matches: Vec<Self::Candidate> = commands.iter()
+                .filter(|&cmd| cmd.starts_with(&line[..pos]))
+                .map(|&cmd| Self::Candidate { 
+                    display: cmd.to_string(),
+                    replacement: cmd.to_string(),
+                })
+                .collect();
+            Ok((0, matches))
+        }
+    }
+}
 
 impl rustyline::Helper for PhantomCompleter {}
 impl rustyline::highlight::Highlighter for PhantomCompleter {}
@@ -216,383 +2423,5225 @@ impl rustyline::hint::Hinter for PhantomCompleter {
     type Hint = String;
     fn hint(&self, _line: &str, _pos: usize, _ctx: &rustyline::Context<'_>) -> Option<String> { None }
 }
-impl rustyline::validate::Validator for PhantomCompleter {}
+impl rustyline::validate::Validator for PhantomCompleter {}
+
+/// Recently copied snippets, newest first, offered by `phantom:clipboard`.
+#[derive(Serialize, Deserialize, Default)]
+struct ClipboardRing {
+    entries: VecDeque<String>,
+    #[serde(skip)]
+    config: ClipboardConfig,
+}
+
+impl ClipboardRing {
+    fn new(config: ClipboardConfig) -> Self {
+        let mut ring = Self { entries: VecDeque::new(), config };
+        if ring.config.persist {
+            ring.load();
+        }
+        ring
+    }
+
+    fn push(&mut self, text: &str) {
+        if text.is_empty() || self.config.history_size == 0 {
+            return;
+        }
+        let text: String = text.chars().take(self.config.max_snippet_len).collect();
+        self.entries.retain(|entry| *entry != text);
+        self.entries.push_front(text);
+        self.entries.truncate(self.config.history_size);
+        if self.config.persist {
+            self.save();
+        }
+    }
+
+    fn load(&mut self) {
+        let path = get_clipboard_file_path();
+        let Ok(contents) = fs::read_to_string(&path) else { return };
+        match toml::from_str::<ClipboardRing>(&contents) {
+            Ok(saved) => {
+                self.entries = saved.entries;
+                self.entries.truncate(self.config.history_size);
+            }
+            Err(e) => log::error!("Error parsing {}: {}", path.display(), e),
+        }
+    }
+
+    fn save(&self) {
+        let path = get_clipboard_file_path();
+        let result = toml::to_string(self)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            .and_then(|contents| fs::write(&path, contents));
+        if let Err(e) = result {
+            log::error!("Error saving clipboard history: {}", e);
+        }
+    }
+}
+
+/// Suggests a fix for a command the shell reported as not found (exit 127).
+trait NotFoundHelper {
+    fn suggest(&self, command: &str) -> Option<String>;
+}
+
+/// Offers the closest executable on `PATH` by edit distance.
+struct DidYouMean;
+
+impl NotFoundHelper for DidYouMean {
+    fn suggest(&self, command: &str) -> Option<String> {
+        closest_name(command, path_executables())
+            .map(|name| format!("{}: command not found. Did you mean '{}'?", command, name))
+    }
+}
+
+/// The name nearest `command` by edit distance, if within a third of its length
+/// (at least one edit).
+fn closest_name(command: &str, names: Vec<String>) -> Option<String> {
+    let max_distance = (command.chars().count() / 3).max(1);
+    names
+        .into_iter()
+        .map(|name| (edit_distance(command, &name), name))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min()
+        .map(|(_, name)| name)
+}
+
+/// Defers to the distro's `command-not-found` handler (Debian/Ubuntu).
+struct CommandNotFound;
+
+impl NotFoundHelper for CommandNotFound {
+    fn suggest(&self, command: &str) -> Option<String> {
+        let output = std::process::Command::new("/usr/lib/command-not-found")
+            .arg("--")
+            .arg(command)
+            .output()
+            .ok()?;
+        let text = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        if text.is_empty() { None } else { Some(text) }
+    }
+}
+
+fn not_found_helper(name: &str) -> Option<Option<Box<dyn NotFoundHelper>>> {
+    match name {
+        "off" => Some(None),
+        "did-you-mean" => Some(Some(Box::new(DidYouMean))),
+        "command-not-found" => Some(Some(Box::new(CommandNotFound))),
+        _ => None,
+    }
+}
+
+fn path_executables() -> Vec<String> {
+    let path = env::var("PATH").unwrap_or_default();
+    let mut names: Vec<String> = env::split_paths(&path)
+        .filter_map(|dir| fs::read_dir(dir).ok())
+        .flatten()
+        .filter_map(Result::ok)
+        .filter(|entry| {
+            use std::os::unix::fs::PermissionsExt;
+            entry.metadata().map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0).unwrap_or(false)
+        })
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+struct TerminalWidget {
+    output: String,
+    input: String,
+    /// Earlier input line states for Ctrl-Z, oldest first. A paste is one step, and
+    /// so is a run of typing.
+    input_undo: VecDeque<String>,
+    /// The last edit was typing, so more typing joins its undo step.
+    input_typing: bool,
+    /// Something was pasted into the input line since it was last submitted.
+    input_pasted: bool,
+    /// The line just submitted held a paste, taken when it is sent to the shell.
+    paste_submitted: bool,
+    prompt: String,
+    /// The shell history newest first, as set by `PhantomTTY::add_history`; the
+    /// editor's history is the record.
+    history: VecDeque<String>,
+    history_index: Option<usize>,
+    /// Text under the current drag selection, copied by Ctrl+Shift+C.
+    selected_text: Option<String>,
+    /// Selection ends as (row, column) screen cells, anchor first.
+    selection: Option<((usize, usize), (usize, usize))>,
+    /// Text for the app to put on the clipboard.
+    copy_request: Option<String>,
+    /// Row sizes for the screen currently in `output`; empty for plain messages.
+    line_attrs: Vec<LineAttr>,
+    /// Cell styles for the screen currently in `output`, `grid_width` per row.
+    cells: Vec<Cell>,
+    grid_width: usize,
+    /// Theme palette the cell colors index into.
+    palette: Vec<egui::Color32>,
+    /// Grapheme cluster tails the cells refer to.
+    clusters: Vec<String>,
+    /// Per-row exit statuses for the screen currently in `output`.
+    exit_badges: Vec<Option<ExitBadge>>,
+    show_exit_badges: bool,
+    /// Rows of the screen in `output` left out of the layout because their command
+    /// is folded; empty when nothing is.
+    hidden_rows: Vec<bool>,
+    /// Prompt row whose fold was toggled by a click, taken by the app.
+    fold_clicked: Option<usize>,
+    /// Row whose exit badge was clicked, taken by the app.
+    badge_clicked: Option<usize>,
+    open_modifier: ClickModifier,
+    /// Screen cell (row, column) the user modifier-clicked to open a path there.
+    path_clicked: Option<(usize, usize)>,
+    /// Where the live screen's first row is drawn.
+    screen_origin: egui::Pos2,
+    /// Average echo latency shown in the corner while `phantom:latency` is on;
+    /// the inner `None` means nothing has been measured yet.
+    latency: Option<Option<Duration>>,
+    /// Fixed cols×rows to center inside the output area, set while the size is locked.
+    letterbox: Option<(usize, usize)>,
+    /// Screen rect of the character grid from the last frame, for mapping pointer
+    /// positions to cells.
+    grid_rect: egui::Rect,
+    /// Screen rect available for output from the last frame, letterbox bars included.
+    output_rect: egui::Rect,
+    font_size: f32,
+    /// Wakeup interval while the window is unfocused; `None` sleeps until the next
+    /// input event.
+    unfocused_repaint: Option<Duration>,
+    /// Lines moved per mouse-wheel notch.
+    scroll_lines: usize,
+    /// Draw tabs, trailing blanks and non-printing characters as dimmed placeholders.
+    show_whitespace: bool,
+    highlight_current_line: bool,
+    high_contrast: bool,
+    /// Cursor row of the screen in `output`, unset for plain messages and on the
+    /// alternate screen.
+    cursor_row: Option<usize>,
+    /// Cursor to draw over the screen in `output`; unset for plain messages and
+    /// while the program hides it.
+    cursor: Option<CursorMark>,
+    /// Show the input line as `•` and keep it out of history, for password prompts.
+    mask_input: bool,
+    /// Handle Ctrl-L on an empty input line locally; see `Config::clear_scrolls`.
+    clear_scrolls: bool,
+    /// See `Config::home_end_to_program`.
+    home_end_to_program: bool,
+    /// Set when Ctrl-L asked for a local scroll-clear, taken by the app.
+    scroll_clear_requested: bool,
+    /// Set when Ctrl-L asked for `phantom:clear`, taken by the app.
+    clear_requested: bool,
+    /// Fractional wheel movement not yet turned into key presses.
+    wheel_remainder: f32,
+    /// Button code of the press last reported to a mouse-tracking program, until
+    /// its release.
+    mouse_held: Option<u8>,
+    /// Cell of the last reported pointer position, so motion is reported once per cell.
+    mouse_cell: Option<(usize, usize)>,
+    /// Open while searching the output.
+    search: Option<SearchState>,
+    /// Where the first scrollback line is drawn.
+    scrollback_top: f32,
+    /// Bytes for the PTY produced by the widget (keys, wheel), drained each frame.
+    pending_input: Vec<u8>,
+    /// Keeps this widget's scroll offset and follow state apart from other tabs',
+    /// so each comes back where it was left.
+    scroll_id: egui::Id,
+}
+
+impl TerminalWidget {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        Self {
+            output: String::new(),
+            input: String::new(),
+            input_undo: VecDeque::new(),
+            input_typing: false,
+            input_pasted: false,
+            paste_submitted: false,
+            prompt: "$ ".to_string(),
+            history: VecDeque::with_capacity(HISTORY_SIZE),
+            history_index: None,
+            selected_text: None,
+            selection: None,
+            copy_request: None,
+            line_attrs: Vec::new(),
+            cells: Vec::new(),
+            grid_width: 0,
+            palette: Theme::default().palette.iter().map(|c| Theme::color(c)).collect(),
+            clusters: Vec::new(),
+            exit_badges: Vec::new(),
+            show_exit_badges: false,
+            hidden_rows: Vec::new(),
+            fold_clicked: None,
+            badge_clicked: None,
+            open_modifier: ClickModifier::default(),
+            path_clicked: None,
+            screen_origin: egui::Pos2::ZERO,
+            latency: None,
+            letterbox: None,
+            grid_rect: egui::Rect::NOTHING,
+            output_rect: egui::Rect::NOTHING,
+            font_size: DEFAULT_FONT_SIZE,
+            unfocused_repaint: Some(UNFOCUSED_REPAINT_INTERVAL),
+            scroll_lines: 3,
+            show_whitespace: false,
+            highlight_current_line: false,
+            high_contrast: false,
+            cursor_row: None,
+            cursor: None,
+            mask_input: false,
+            clear_scrolls: false,
+            home_end_to_program: false,
+            scroll_clear_requested: false,
+            clear_requested: false,
+            wheel_remainder: 0.0,
+            mouse_held: None,
+            mouse_cell: None,
+            search: None,
+            scrollback_top: 0.0,
+            pending_input: Vec::new(),
+            scroll_id: egui::Id::new(("output", NEXT_ID.fetch_add(1, Ordering::Relaxed))),
+        }
+    }
+
+    fn font_id(&self) -> egui::FontId {
+        egui::FontId::monospace(self.font_size)
+    }
+
+    /// Takes the input line as a command to run, if it isn't blank.
+    fn submit_input(&mut self) -> Option<String> {
+        let command = self.input.trim().to_string();
+        if command.is_empty() {
+            return None;
+        }
+        self.paste_submitted = std::mem::take(&mut self.input_pasted);
+        self.input.clear();
+        self.input_undo.clear();
+        self.input_typing = false;
+        Some(command)
+    }
+
+    /// Records `before` as an undo step for an input edit, unless the edit is more
+    /// typing after typing.
+    fn record_input_edit(&mut self, before: String, paste: bool) {
+        self.input_pasted |= paste;
+        if paste || !self.input_typing {
+            if self.input_undo.len() >= INPUT_UNDO_DEPTH {
+                self.input_undo.pop_front();
+            }
+            self.input_undo.push_back(before);
+        }
+        self.input_typing = !paste;
+    }
+
+    fn undo_input(&mut self) {
+        if let Some(before) = self.input_undo.pop_back() {
+            self.input = before;
+        }
+        self.input_typing = false;
+    }
+
+    /// Columns and rows of glyphs at the current font size that fit the output area.
+    fn grid_size(&self, ctx: &egui::Context) -> Option<(usize, usize)> {
+        if !self.output_rect.is_positive() {
+            return None;
+        }
+        let font_id = self.font_id();
+        let glyph = ctx.fonts(|f| egui::vec2(f.glyph_width(&font_id, 'M'), f.row_height(&font_id)));
+        let size = self.output_rect.size();
+        Some(((size.x / glyph.x) as usize, (size.y / glyph.y) as usize))
+    }
+
+    fn take_pending_input(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.pending_input)
+    }
+
+    /// Turns wheel movement over the output into Up/Down arrow presses, `scroll_lines`
+    /// per notch, for alternate-scroll mode.
+    fn wheel_to_arrow_keys(&mut self, ui: &egui::Ui, font_id: &egui::FontId, modes: InputModes) {
+        let notches = self.wheel_notches(ui, font_id);
+        let key = arrow_key(notches > 0, modes.app_cursor_keys);
+        for _ in 0..notches.unsigned_abs() as usize * self.scroll_lines {
+            self.pending_input.extend_from_slice(key);
+        }
+    }
+
+    /// Whole wheel notches this frame, up positive; the fraction is carried over.
+    fn wheel_notches(&mut self, ui: &egui::Ui, font_id: &egui::FontId) -> i32 {
+        let row_height = ui.fonts(|f| f.row_height(font_id));
+        let page = self.output_rect.height() / row_height;
+        let lines: f32 = ui.input(|i| {
+            i.events.iter().map(|event| match event {
+                egui::Event::MouseWheel { unit, delta, .. } => match unit {
+                    egui::MouseWheelUnit::Line => delta.y,
+                    egui::MouseWheelUnit::Point => delta.y / row_height,
+                    egui::MouseWheelUnit::Page => delta.y * page,
+                },
+                _ => 0.0,
+            }).sum()
+        });
+        self.wheel_remainder += lines;
+        let notches = self.wheel_remainder.trunc();
+        self.wheel_remainder -= notches;
+        notches as i32
+    }
+
+    /// Reports presses, releases, the wheel and (as the program asked) motion over
+    /// the output to a mouse-tracking program. A press has to land on the output;
+    /// its release and drags are reported wherever the pointer goes.
+    fn report_mouse(&mut self, ui: &egui::Ui, font_id: &egui::FontId, modes: InputModes) {
+        let events = ui.input(|i| i.events.clone());
+        let modifiers = ui.input(|i| i.modifiers);
+        for event in events {
+            let (code, pos, pressed, modifiers) = match event {
+                egui::Event::PointerButton { pos, button, pressed, modifiers } => {
+                    let code = match button {
+                        egui::PointerButton::Primary => 0,
+                        egui::PointerButton::Middle => 1,
+                        egui::PointerButton::Secondary => 2,
+                        _ => continue,
+                    };
+                    if pressed {
+                        if !self.output_rect.contains(pos) {
+                            continue;
+                        }
+                        self.mouse_held = Some(code);
+                    } else if self.mouse_held.take().is_none() {
+                        continue;
+                    }
+                    (code, pos, pressed, modifiers)
+                }
+                egui::Event::PointerMoved(pos) => {
+                    let cell = self.cell_at(ui, font_id, pos);
+                    if self.mouse_cell.replace(cell) == Some(cell) {
+                        continue;
+                    }
+                    let code = match (self.mouse_held, modes.mouse_tracking) {
+                        (Some(held), MouseTracking::Drag | MouseTracking::Motion) => held + 32,
+                        (None, MouseTracking::Motion) if self.output_rect.contains(pos) => 3 + 32,
+                        _ => continue,
+                    };
+                    (code, pos, true, modifiers)
+                }
+                _ => continue,
+            };
+            let cell = self.cell_at(ui, font_id, pos);
+            self.pending_input.extend(mouse_report(code, cell, pressed, modifiers, modes.sgr_mouse));
+        }
+        let notches = self.wheel_notches(ui, font_id);
+        if let Some(pos) = ui.input(|i| i.pointer.hover_pos()).filter(|pos| self.output_rect.contains(*pos)) {
+            let cell = self.cell_at(ui, font_id, pos);
+            for _ in 0..notches.unsigned_abs() {
+                let code = if notches > 0 { 64 } else { 65 };
+                self.pending_input.extend(mouse_report(code, cell, true, modifiers, modes.sgr_mouse));
+            }
+        }
+    }
+
+    fn repaint_interval(&self, focused: bool) -> Option<Duration> {
+        if focused {
+            Some(Duration::ZERO)
+        } else {
+            self.unfocused_repaint
+        }
+    }
+
+    fn set_output(&mut self, output: &str) {
+        self.output = output.to_string();
+        self.selection = None;
+        self.selected_text = None;
+        self.line_attrs.clear();
+        self.exit_badges.clear();
+        self.hidden_rows.clear();
+        self.cells.clear();
+        self.cursor_row = None;
+        self.cursor = None;
+    }
+
+    fn set_screen(&mut self, output: &str, vte: &VteTerminal) {
+        self.output = output.to_string();
+        self.line_attrs = vte.line_attrs.clone();
+        self.exit_badges = vte.exit_badges.clone();
+        self.hidden_rows = if vte.alt_screen { Vec::new() } else { vte.folded_rows() };
+        self.cells = vte.cells.clone();
+        self.clusters.clone_from(&vte.clusters);
+        self.grid_width = vte.width;
+        self.cursor_row = (!vte.alt_screen).then_some(vte.cursor_y);
+        let pos = vte.cursor_y * vte.width + vte.cursor_x;
+        self.cursor = vte.cursor_visible.then(|| CursorMark {
+            row: vte.cursor_y,
+            col: vte.cursor_x,
+            glyph: match vte.screen.get(pos) {
+                Some(&c) if c != '\t' && c != WIDE_SPACER => c,
+                _ => ' ',
+            },
+            wide: vte.screen.get(pos + 1) == Some(&WIDE_SPACER) && !(pos + 1).is_multiple_of(vte.width),
+            style: vte.cursor_style,
+        });
+    }
+
+    /// Screen rect of the cursor's cell (both cells on a wide glyph, twice as wide
+    /// on a DECDWL row), given the glyph size.
+    fn cursor_rect(&self, glyph: egui::Vec2) -> Option<egui::Rect> {
+        let cursor = self.cursor.filter(|cursor| self.hidden_rows.get(cursor.row) != Some(&true))?;
+        let scale = match self.line_attrs.get(cursor.row) {
+            Some(LineAttr::Single) | None => 1.0,
+            Some(_) => 2.0,
+        };
+        let cells = if cursor.wide { 2.0 } else { 1.0 };
+        let min = self.screen_origin + egui::vec2(cursor.col as f32 * glyph.x * scale, self.display_row(cursor.row) as f32 * glyph.y);
+        Some(egui::Rect::from_min_size(min, egui::vec2(glyph.x * scale * cells, glyph.y)))
+    }
+
+    /// Paints the cursor in its DECSCUSR shape. It blinks only while the window has
+    /// focus, and shows as an outline when it doesn't.
+    fn show_cursor(&self, ui: &egui::Ui, font_id: &egui::FontId) {
+        let glyph = ui.fonts(|f| egui::vec2(f.glyph_width(font_id, 'M'), f.row_height(font_id)));
+        let (Some(cursor), Some(rect)) = (self.cursor, self.cursor_rect(glyph)) else { return };
+        if !self.grid_rect.contains_rect(rect) {
+            return;
+        }
+        let color = ui.visuals().text_cursor.stroke.color;
+        let painter = ui.painter_at(self.grid_rect);
+        let focused = ui.input(|i| i.focused);
+        if !focused {
+            painter.rect_stroke(rect.shrink(0.5), 0.0, egui::Stroke::new(1.0, color));
+            return;
+        }
+        if cursor.style.blink {
+            let period = CURSOR_BLINK.as_secs_f64();
+            let time = ui.input(|i| i.time);
+            ui.ctx().request_repaint_after(Duration::from_secs_f64(period - time % period));
+            if (time / period) as u64 % 2 == 1 {
+                return;
+            }
+        }
+        match cursor.style.shape {
+            CursorShape::Block => {
+                painter.rect_filled(rect, 0.0, color);
+                let font = egui::FontId::monospace(font_id.size);
+                painter.text(rect.left_top(), egui::Align2::LEFT_TOP, cursor.glyph, font, ui.visuals().extreme_bg_color);
+            }
+            CursorShape::Underline => {
+                painter.rect_filled(egui::Rect::from_min_max(egui::pos2(rect.left(), rect.bottom() - 2.0), rect.max), 0.0, color);
+            }
+            CursorShape::Bar => {
+                painter.rect_filled(egui::Rect::from_min_max(rect.min, egui::pos2(rect.left() + 2.0, rect.bottom())), 0.0, color);
+            }
+        }
+    }
+
+    /// Reserves a row for every scrollback line above the screen, but lays out only
+    /// the ones inside `viewport`, so a long history costs nothing until scrolled to.
+    fn show_scrollback(&mut self, ui: &mut egui::Ui, vte: &VteTerminal, font_id: &egui::FontId, row_height: f32, viewport: egui::Rect) {
+        let total = vte.scrollback.len();
+        let (rect, _) = ui.allocate_exact_size(egui::vec2(ui.available_width(), total as f32 * row_height), egui::Sense::hover());
+        self.scrollback_top = rect.top();
+        let first = ((viewport.min.y / row_height).floor().max(0.0) as usize).min(total);
+        let last = ((viewport.max.y / row_height).ceil().max(0.0) as usize).min(total);
+        if first >= last {
+            return;
+        }
+        let color = ui.visuals().override_text_color.unwrap_or_else(|| ui.visuals().widgets.inactive.text_color());
+        let base = egui::TextFormat::simple(font_id.clone(), color);
+        let colors = CellColors {
+            palette: &self.palette,
+            background: ui.visuals().extreme_bg_color,
+            dim: ui.visuals().weak_text_color(),
+            high_contrast: self.high_contrast,
+            clusters: &self.clusters,
+        };
+        let mut job = egui::text::LayoutJob::default();
+        for line in vte.scrollback.range(first..last) {
+            let text: String = line.chars.iter().map(|&c| if c == '\t' { ' ' } else { c }).chain(['\n']).collect();
+            append_row(&mut job, &text, &base, &line.cells, &colors, self.show_whitespace);
+        }
+        let galley = ui.fonts(|f| f.layout_job(job));
+        ui.painter().galley(rect.min + egui::vec2(0.0, first as f32 * row_height), galley, color);
+    }
+
+    /// The output laid out with its colors, line sizes and display options.
+    fn layout_job(&self, ui: &egui::Ui, font_id: &egui::FontId) -> egui::text::LayoutJob {
+        let options = RenderOptions {
+            show_whitespace: self.show_whitespace,
+            grid_width: self.grid_width,
+            highlight_row: self.cursor_row.filter(|_| self.highlight_current_line),
+            hidden_rows: &self.hidden_rows,
+        };
+        let colors = CellColors {
+            palette: &self.palette,
+            background: ui.visuals().extreme_bg_color,
+            dim: ui.visuals().weak_text_color(),
+            high_contrast: self.high_contrast,
+            clusters: &self.clusters,
+        };
+        output_layout_job(ui, &self.output, font_id, &self.line_attrs, &self.cells, &colors, options)
+    }
+
+    /// Draws a ✔ or ✘ with the code at the right end of each row that holds a
+    /// finished command's prompt, led by the hidden row count when the command is
+    /// folded. Clicking one asks the app to copy that row.
+    fn show_exit_badges(&mut self, ui: &egui::Ui, font_id: &egui::FontId) {
+        let row_height = ui.fonts(|f| f.row_height(font_id));
+        for (row, badge) in self.exit_badges.iter().enumerate() {
+            let Some(badge) = badge else { continue };
+            let (mut label, color) = match badge.status {
+                0 => ("✔".to_string(), egui::Color32::from_rgb(0x4e, 0xc9, 0x4e)),
+                code => (format!("✘ {}", code), egui::Color32::from_rgb(0xe0, 0x4f, 0x4f)),
+            };
+            if badge.folded {
+                let hidden = self.hidden_rows.iter().skip(row + 1).take_while(|&&hidden| hidden).count();
+                label = format!("▸ {} lines  {}", hidden, label);
+            }
+            if self.hidden_rows.get(row) == Some(&true) {
+                continue;
+            }
+            let anchor = egui::pos2(self.grid_rect.right() - 4.0, self.screen_origin.y + self.display_row(row) as f32 * row_height);
+            if anchor.y < self.grid_rect.top() {
+                continue;
+            }
+            if anchor.y + row_height > self.grid_rect.bottom() {
+                break;
+            }
+            let rect = ui.painter().text(anchor, egui::Align2::RIGHT_TOP, label, font_id.clone(), color);
+            let response = ui.interact(rect, ui.id().with(("exit_badge", row)), egui::Sense::click());
+            if response.on_hover_text("Copy this command line").clicked() {
+                self.badge_clicked = Some(row);
+            }
+        }
+    }
+
+    /// Screen rect of `width` cells from a search hit at (line, column), or `None`
+    /// when its row is folded away.
+    fn hit_rect(&self, (line, col): (usize, usize), width: usize, scrollback_len: usize, glyph: egui::Vec2) -> Option<egui::Rect> {
+        let top = match line.checked_sub(scrollback_len) {
+            None => self.scrollback_top + line as f32 * glyph.y,
+            Some(row) if self.hidden_rows.get(row) == Some(&true) => return None,
+            Some(row) => self.screen_origin.y + self.display_row(row) as f32 * glyph.y,
+        };
+        let min = egui::pos2(self.screen_origin.x + col as f32 * glyph.x, top);
+        Some(egui::Rect::from_min_size(min, egui::vec2(width as f32 * glyph.x, glyph.y)))
+    }
+
+    /// Shades the search hits, the current one more strongly.
+    fn show_search_hits(&self, ui: &egui::Ui, font_id: &egui::FontId, scrollback_len: usize) {
+        let Some(ref search) = self.search else { return };
+        let glyph = ui.fonts(|f| egui::vec2(f.glyph_width(font_id, 'M'), f.row_height(font_id)));
+        let width = search.query.width().max(1);
+        let painter = ui.painter().with_clip_rect(self.output_rect);
+        let color = ui.visuals().warn_fg_color;
+        for (i, &hit) in search.hits.iter().enumerate() {
+            let Some(rect) = self.hit_rect(hit, width, scrollback_len, glyph) else { continue };
+            if !rect.intersects(self.output_rect) {
+                continue;
+            }
+            let alpha = if i == search.current { 0.6 } else { 0.25 };
+            painter.rect_filled(rect, 0.0, color.gamma_multiply(alpha));
+        }
+    }
+
+    /// Opens the search bar. A selection (its first line, if it isn't blank) becomes
+    /// the query, and the most recent match is scrolled to.
+    fn open_search(&mut self, vte: &VteTerminal) {
+        let search = self.search.get_or_insert_with(SearchState::default);
+        let selected = self.selected_text.as_deref().and_then(|text| text.lines().next()).map(str::trim);
+        if let Some(query) = selected.filter(|query| !query.is_empty()) {
+            search.query = query.to_string();
+            search.hits = vte.search(&search.query, search.case_sensitive);
+            search.current = search.hits.len().saturating_sub(1);
+            search.jump = !search.hits.is_empty();
+        }
+    }
+
+    /// The search bar under the output: typing searches again, Enter and
+    /// Shift+Enter step to the next and previous hit, Escape closes it.
+    fn show_search_bar(&mut self, ui: &mut egui::Ui, vte: &VteTerminal) {
+        let Some(ref mut search) = self.search else { return };
+        let mut close = false;
+        ui.horizontal(|ui| {
+            ui.label("Find:");
+            let search_id = ui.id().with("search");
+            let response = ui.add(egui::TextEdit::singleline(&mut search.query).id(search_id).desired_width(240.0));
+            let case = ui.checkbox(&mut search.case_sensitive, "Match case");
+            let stepped = response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+            if response.changed() || case.changed() || stepped {
+                search.hits = vte.search(&search.query, search.case_sensitive);
+                if !stepped {
+                    // A new query starts from the most recent match.
+                    search.current = search.hits.len().saturating_sub(1);
+                } else if !search.hits.is_empty() {
+                    let len = search.hits.len();
+                    search.current = if ui.input(|i| i.modifiers.shift) {
+                        (search.current.min(len - 1) + len - 1) % len
+                    } else {
+                        (search.current + 1) % len
+                    };
+                }
+                search.jump = !search.hits.is_empty();
+            }
+            if stepped || case.changed() {
+                response.request_focus();
+            }
+            match search.hits.len() {
+                0 if !search.query.is_empty() => ui.label("No matches"),
+                0 => ui.label(""),
+                len => ui.label(format!("{}/{}", search.current + 1, len)),
+            };
+            close = ui.input(|i| i.key_pressed(egui::Key::Escape)) || ui.button("✕").clicked();
+        });
+        if close {
+            self.search = None;
+        }
+    }
+
+    /// Where `row` of the screen is drawn, counted in rows below the first, with
+    /// folded rows left out.
+    fn display_row(&self, row: usize) -> usize {
+        row - self.hidden_rows.iter().take(row).filter(|&&hidden| hidden).count()
+    }
+
+    /// The screen row drawn `display` rows below the first; the inverse of `display_row`.
+    fn screen_row(&self, display: usize) -> usize {
+        let mut shown = 0;
+        for (row, &hidden) in self.hidden_rows.iter().enumerate() {
+            if hidden {
+                continue;
+            }
+            if shown == display {
+                return row;
+            }
+            shown += 1;
+        }
+        display + self.hidden_rows.iter().filter(|&&hidden| hidden).count()
+    }
+
+    /// Screen cell under `pos`, clamped to the live screen.
+    fn cell_at(&self, ui: &egui::Ui, font_id: &egui::FontId, pos: egui::Pos2) -> (usize, usize) {
+        let glyph = ui.fonts(|f| egui::vec2(f.glyph_width(font_id, 'M'), f.row_height(font_id)));
+        let offset = (pos - self.screen_origin).max(egui::Vec2::ZERO);
+        let (row, col) = (self.screen_row((offset.y / glyph.y) as usize), (offset.x / glyph.x) as usize);
+        if self.grid_width == 0 {
+            return (row, col);
+        }
+        let height = self.cells.len() / self.grid_width;
+        (row.min(height.saturating_sub(1)), col.min(self.grid_width - 1))
+    }
+
+    /// Dragging over the output selects screen text, drawn with the selection color;
+    /// a plain click clears it, and on a badged prompt row also folds or unfolds
+    /// that command's output.
+    fn track_selection(&mut self, ui: &egui::Ui, font_id: &egui::FontId) {
+        let response = ui.interact(self.output_rect, ui.id().with("output_selection"), egui::Sense::click_and_drag());
+        if let Some(pos) = response.interact_pointer_pos() {
+            let cell = self.cell_at(ui, font_id, pos);
+            if response.drag_started() {
+                self.selection = Some((cell, cell));
+            } else if response.dragged() {
+                if let Some((anchor, _)) = self.selection {
+                    self.selection = Some((anchor, cell));
+                    self.selected_text = Some(self.selection_text());
+                }
+            }
+        }
+        if response.clicked() {
+            self.selection = None;
+            self.selected_text = None;
+            let modified = ui.input(|i| self.open_modifier.held(&i.modifiers));
+            if let Some(pos) = response.interact_pointer_pos().filter(|_| self.show_exit_badges && !modified) {
+                let (row, _) = self.cell_at(ui, font_id, pos);
+                if matches!(self.exit_badges.get(row), Some(Some(_))) {
+                    self.fold_clicked = Some(row);
+                }
+            }
+        }
+        let Some((anchor, end)) = self.selection else { return };
+        let (start, end) = (anchor.min(end), anchor.max(end));
+        let glyph = ui.fonts(|f| egui::vec2(f.glyph_width(font_id, 'M'), f.row_height(font_id)));
+        let fill = ui.visuals().selection.bg_fill.gamma_multiply(0.5);
+        let painter = ui.painter().with_clip_rect(self.output_rect);
+        for row in start.0..=end.0 {
+            if self.hidden_rows.get(row) == Some(&true) {
+                continue;
+            }
+            let first = if row == start.0 { start.1 } else { 0 };
+            let last = if row == end.0 { end.1 + 1 } else { self.grid_width.max(end.1 + 1) };
+            let min = self.screen_origin + egui::vec2(first as f32 * glyph.x, self.display_row(row) as f32 * glyph.y);
+            painter.rect_filled(egui::Rect::from_min_size(min, egui::vec2((last - first) as f32 * glyph.x, glyph.y)), 0.0, fill);
+        }
+    }
+
+    /// The output text between the selection's ends, inclusive, one line per row.
+    fn selection_text(&self) -> String {
+        let Some((anchor, end)) = self.selection else { return String::new() };
+        let (start, end) = (anchor.min(end), anchor.max(end));
+        let lines: Vec<String> = self.output.lines().enumerate()
+            .skip(start.0)
+            .take(end.0 - start.0 + 1)
+            .filter(|(row, _)| self.hidden_rows.get(*row) != Some(&true))
+            .map(|(row, line)| {
+                let first = if row == start.0 { start.1 } else { 0 };
+                let last = if row == end.0 { end.1 + 1 } else { usize::MAX };
+                let text: String = line.chars().skip(first).take(last.saturating_sub(first)).filter(|&c| c != WIDE_SPACER).collect();
+                text.trim_end().to_string()
+            })
+            .collect();
+        lines.join("\n")
+    }
+
+    /// Records the cell under a modifier-click on the output so the app can open the
+    /// path there. Plain clicks are left alone.
+    fn check_path_click(&mut self, ui: &egui::Ui, font_id: &egui::FontId) {
+        let click = ui.input(|i| {
+            (i.pointer.primary_clicked() && self.open_modifier.held(&i.modifiers))
+                .then(|| i.pointer.interact_pos())
+                .flatten()
+        });
+        let Some(pos) = click.filter(|pos| self.output_rect.contains(*pos)) else { return };
+        let offset = pos - self.screen_origin;
+        if offset.x >= 0.0 && offset.y >= 0.0 {
+            self.path_clicked = Some(self.cell_at(ui, font_id, pos));
+        }
+    }
+
+    /// Shows `entries`, given oldest first, to the Up and Down keys and the quick
+    /// command bar.
+    fn set_history<'a>(&mut self, entries: impl DoubleEndedIterator<Item = &'a String>) {
+        self.history = entries.rev().take(HISTORY_SIZE).cloned().collect();
+        self.history_index = None;
+    }
+
+    fn get_previous_command(&mut self) -> Option<String> {
+        if self.history.is_empty() {
+            return None;
+        }
+        let index = self.history_index.map(|i| i + 1).unwrap_or(0);
+        if index < self.history.len() {
+            self.history_index = Some(index);
+            Some(self.history[index].clone())
+        } else {
+            None
+        }
+    }
+
+    fn get_next_command(&mut self) -> Option<String> {
+        if let Some(index) = self.history_index {
+            if index > 0 {
+                self.history_index = Some(index - 1);
+                Some(self.history[index - 1].clone())
+            } else {
+                self.history_index = None;
+                Some(String::new())
+            }
+        } else {
+            None
+        }
+    }
+
+    fn show(&mut self, ui: &mut egui::Ui, _ctx: &egui::Context, vte: &VteTerminal) -> Option<String> {
+        let modes = vte.input_modes();
+        let mut executed_command = None;
+    
+        ui.vertical(|ui| {
+            let available_size = ui.available_size();
+            if ui.input_mut(|i| i.consume_key(egui::Modifiers::CTRL | egui::Modifiers::SHIFT, egui::Key::F)) {
+                self.open_search(vte);
+                ui.memory_mut(|m| m.request_focus(ui.id().with("search")));
+            }
+            let input_id = ui.id().with("input");
+            let input_focused = ui.memory(|m| m.has_focus(input_id));
+            let target = home_end_target(input_focused, modes.alt_screen, ui.rect_contains_pointer(self.output_rect), self.home_end_to_program);
+            let mut scroll_to_end = None;
+            if let Some(target) = target.filter(|&target| target != HomeEndTarget::Line) {
+                let home_end = ui.input_mut(|i| [egui::Key::Home, egui::Key::End].into_iter().find(|&key| i.consume_key(egui::Modifiers::NONE, key)));
+                if let Some(key) = home_end {
+                    let home = key == egui::Key::Home;
+                    if target == HomeEndTarget::Program {
+                        self.pending_input.extend_from_slice(home_end_key(home, modes.app_cursor_keys));
+                    } else {
+                        scroll_to_end = Some(!home);
+                    }
+                }
+            }
+            let search_height = if self.search.is_some() { 30.0 } else { 0.0 };
+            let output_height = available_size.y - 30.0 - search_height;
+            let font_id = self.font_id();
+            // Shift gets local selection and scrolling back while a program tracks the mouse.
+            let report_mouse = modes.mouse_tracking != MouseTracking::Off && !ui.input(|i| i.modifiers.shift);
+    
+            if let Some((cols, rows)) = self.letterbox {
+                let glyph = ui.fonts(|f| egui::vec2(f.glyph_width(&font_id, 'M'), f.row_height(&font_id)));
+                let (area, _) = ui.allocate_exact_size(
+                    egui::vec2(available_size.x, output_height),
+                    egui::Sense::hover(),
+                );
+                let grid_size = egui::vec2(cols as f32 * glyph.x, rows as f32 * glyph.y);
+                let grid_rect = egui::Rect::from_center_size(area.center(), grid_size).intersect(area);
+                ui.painter().rect_filled(area, 0.0, egui::Color32::BLACK);
+                ui.painter().rect_filled(grid_rect, 0.0, ui.visuals().extreme_bg_color);
+                ui.allocate_ui_at_rect(grid_rect, |ui| {
+                    let job = self.layout_job(ui, &font_id);
+                    self.screen_origin = ui.add(egui::Label::new(job).wrap_mode(egui::TextWrapMode::Extend).selectable(false)).rect.min;
+                });
+                self.grid_rect = grid_rect;
+                self.output_rect = area;
+            } else {
+                let row_height = ui.fonts(|f| f.row_height(&font_id));
+                // Sticking to the bottom would pull a jump to the top straight back down,
+                // so Home drops it for the frame and jumps without animating.
+                let jump_home = scroll_to_end == Some(false);
+                let output = egui::ScrollArea::vertical()
+                    .id_source(self.scroll_id)
+                    .auto_shrink([false; 2])
+                    .stick_to_bottom(!jump_home)
+                    .animated(!jump_home)
+                    .enable_scrolling(!report_mouse)
+                    .max_height(output_height)
+                    .show_viewport(ui, |ui, viewport| {
+                        if !vte.scrollback.is_empty() {
+                            self.show_scrollback(ui, vte, &font_id, row_height, viewport);
+                        }
+                        let job = self.layout_job(ui, &font_id);
+                        self.screen_origin = ui.add(egui::Label::new(job).wrap_mode(egui::TextWrapMode::Wrap).selectable(false)).rect.min;
+                        match scroll_to_end {
+                            Some(true) => ui.scroll_to_cursor(Some(egui::Align::BOTTOM)),
+                            Some(false) => ui.scroll_to_rect(egui::Rect::from_min_size(ui.min_rect().min, egui::Vec2::splat(1.0)), Some(egui::Align::TOP)),
+                            None => {}
+                        }
+                        let jump_to = self.search.as_mut()
+                            .and_then(|search| std::mem::take(&mut search.jump).then(|| search.hits.get(search.current).copied()))
+                            .flatten();
+                        if let Some(hit) = jump_to {
+                            let glyph = egui::vec2(ui.fonts(|f| f.glyph_width(&font_id, 'M')), row_height);
+                            if let Some(rect) = self.hit_rect(hit, 1, vte.scrollback.len(), glyph) {
+                                ui.scroll_to_rect(rect, Some(egui::Align::Center));
+                            }
+                        }
+                    });
+                self.grid_rect = output.inner_rect;
+                self.output_rect = output.inner_rect;
+            }
+
+            self.show_cursor(ui, &font_id);
+            self.show_search_hits(ui, &font_id, vte.scrollback.len());
+            if report_mouse {
+                self.report_mouse(ui, &font_id, modes);
+            } else {
+                self.mouse_held = None;
+                self.track_selection(ui, &font_id);
+            }
+            if self.show_exit_badges {
+                self.show_exit_badges(ui, &font_id);
+            }
+            self.check_path_click(ui, &font_id);
+            if let Some(latency) = self.latency {
+                let label = match latency {
+                    Some(latency) => format!("echo {} ms", latency.as_millis()),
+                    None => "echo -- ms".to_string(),
+                };
+                let anchor = self.output_rect.right_bottom() - egui::vec2(4.0, 4.0);
+                let font = egui::FontId::monospace(self.font_size * 0.8);
+                ui.painter().text(anchor, egui::Align2::RIGHT_BOTTOM, label, font, ui.visuals().weak_text_color());
+            }
+
+            if !report_mouse && modes.alternate_scroll && ui.rect_contains_pointer(self.output_rect) {
+                self.wheel_to_arrow_keys(ui, &font_id, modes);
+            }
+            self.show_search_bar(ui, vte);
+    
+            // Ctrl+Shift+C arrives as a copy event; take it before the input line
+            // does. Ctrl+Shift+V pastes into the input line even when it lacks focus.
+            let (copy, paste) = ui.input_mut(|i| {
+                let shift = i.modifiers.shift;
+                let copy = shift && i.events.contains(&egui::Event::Copy);
+                if copy {
+                    i.events.retain(|event| *event != egui::Event::Copy);
+                }
+                let paste = i.events.iter().find_map(|event| match event {
+                    egui::Event::Paste(text) if shift => Some(text.clone()),
+                    _ => None,
+                });
+                (copy, paste)
+            });
+            if copy {
+                self.copy_request = self.selected_text.clone();
+            }
+            let focused = ui.memory(|m| m.has_focus(input_id));
+            if let Some(text) = paste.filter(|_| !focused) {
+                self.record_input_edit(self.input.clone(), true);
+                self.input.push_str(&text);
+            }
+            // Ctrl-Z is ours rather than the text edit's, so a paste undoes in one step.
+            if focused && ui.input_mut(|i| i.consume_key(egui::Modifiers::CTRL, egui::Key::Z)) {
+                self.undo_input();
+            }
+            let pasting = focused && ui.input(|i| i.events.iter().any(|event| matches!(event, egui::Event::Paste(_))));
+            // A paste only fills the input line; Ctrl+Shift+Enter then runs it. Multi-line
+            // text is only run when the shell takes bracketed paste, since otherwise each
+            // line would run on its own as it arrived.
+            if focused && ui.input_mut(|i| i.consume_key(egui::Modifiers::COMMAND | egui::Modifiers::SHIFT, egui::Key::Enter)) {
+                if self.input.contains('\n') && !vte.bracketed_paste {
+                    self.set_output("Not running a multi-line paste: the shell hasn't enabled bracketed paste\n");
+                } else {
+                    executed_command = self.submit_input();
+                }
+            }
+            let before = self.input.clone();
+    
+            ui.horizontal(|ui| {
+                ui.label(&self.prompt);
+                let response = ui.add(
+                    egui::TextEdit::singleline(&mut self.input)
+                        .id(input_id)
+                        .password(self.mask_input)
+                        .desired_width(f32::INFINITY)
+                        .font(font_id.clone())
+                );
+                if self.input != before {
+                    self.record_input_edit(before, pasting);
+                }
+    
+                if response.has_focus() && ui.input_mut(|i| i.consume_key(egui::Modifiers::CTRL, egui::Key::L)) {
+                    if !self.input.is_empty() {
+                        self.pending_input.push(0x0c);
+                    } else if self.clear_scrolls {
+                        self.scroll_clear_requested = true;
+                    } else {
+                        self.clear_requested = true;
+                    }
+                }
+
+                if response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+                    if let Some(command) = self.submit_input() {
+                        executed_command = Some(command);
+                    }
+                    response.request_focus();
+                }
+    
+                if ui.input(|i| i.key_pressed(egui::Key::ArrowUp)) {
+                    if let Some(prev_command) = self.get_previous_command() {
+                        self.input = prev_command;
+                    }
+                }
+    
+                if ui.input(|i| i.key_pressed(egui::Key::ArrowDown)) {
+                    if let Some(next_command) = self.get_next_command() {
+                        self.input = next_command;
+                    }
+                }
+            });
+        });
+    
+        executed_command
+    }
+}
+
+struct PhantomTTY {
+    shell_path: String,
+    /// Extra `NAME=value` pairs for this session's shell, on top of `TERM`.
+    env: Vec<(String, String)>,
+    history_file: PathBuf,
+    editor: Editor<PhantomCompleter, DefaultHistory>,
+    terminal: TerminalWidget,
+    term: String,
+    pty_master: Option<File>,
+    pty_reader: Option<mpsc::Receiver<PtyEvent>>,
+    waker: Waker,
+    vte_terminal: VteTerminal,
+    last_command: Option<String>,
+    not_found_helper: Option<Box<dyn NotFoundHelper>>,
+    /// Grid size pinned by `phantom:lock-size`; window resizes leave the PTY at this size.
+    locked_size: Option<(usize, usize)>,
+    mirror: Option<Arc<Mutex<MirrorState>>>,
+    limits: Limits,
+    /// Font size asked for by `phantom:font`, applied by the app.
+    font_request: Option<f32>,
+    clipboard: ClipboardRing,
+    clipboard_picker_open: bool,
+    custom_commands: HashMap<String, String>,
+    child: Option<Pid>,
+    relativize_dropped_paths: bool,
+    /// Opener commands by file extension, for modifier-clicked paths.
+    open_with: HashMap<String, String>,
+    binary_output: BinaryOutputConfig,
+    autosave: AutosaveConfig,
+    /// When output last arrived, while some of it is not autosaved yet.
+    unsaved_output: Option<Instant>,
+    /// `VteTerminal::scrolled_rows` at the last autosave.
+    autosaved_rows: usize,
+    /// This tab's file in the autosave folder, picked at its first save.
+    autosave_path: Option<PathBuf>,
+    separate_stderr: bool,
+    confirm_commands: Vec<regex::Regex>,
+    /// A command that matched `confirm_commands`, with the pattern, awaiting a yes.
+    pending_confirmation: Option<(String, String)>,
+    /// Binary output was seen and the stop-and-reset prompt is showing.
+    binary_prompt: bool,
+    /// The PTY has closed and the shell is waiting to be reaped.
+    pty_closed: bool,
+    /// The shell has exited and the restart-or-close prompt is showing.
+    shell_exited: bool,
+    /// When this tab was last showing, for `max_tabs_policy = "recycle"`.
+    last_used: Instant,
+    /// The current run of binary output has been handled; cleared by normal output.
+    binary_seen: bool,
+    paste_config: PasteConfig,
+    /// Temp files holding large pastes, removed on exit.
+    paste_files: Vec<PathBuf>,
+    copy_tabs_as_spaces: bool,
+    /// Text to put on the clipboard, applied by the app.
+    copy_requests: Vec<String>,
+    detach_on_close: bool,
+    restore_session: bool,
+    clear_on_command: bool,
+    /// Echo timing, while `phantom:latency` is on.
+    latency: Option<LatencyProbe>,
+    /// The output capture in progress, with its deadline and where the output goes.
+    run_pending: Option<(Instant, RunSink)>,
+    insert_multiline: MultilineInsert,
+    /// Echo forced by `phantom:echo`; `None` follows the PTY's termios.
+    echo_override: Option<bool>,
+    notifications: bool,
+    theme: Theme,
+    /// Theme to switch to, applied by the app.
+    theme_request: Option<Theme>,
+    /// Shell to open a new tab with, from `phantom:spawn`, taken by the app.
+    spawn_request: Option<String>,
+    /// Set by the `exit` builtin for the app to close this tab (and the window with
+    /// the last one).
+    close_request: bool,
+}
+
+impl PhantomTTY {
+    /// With `resume`, this is the session that picks up a detached shell or a saved
+    /// snapshot at startup, and is the one kept on exit; other tabs' shells end with
+    /// the window.
+    fn new(shell_path: String, env: Vec<(String, String)>, config: &Config, waker: Waker, resume: bool) -> Self {
+        let history_file = get_history_file_path();
+        let editor_config = EditorConfig::builder()
+            .max_history_size(HISTORY_SIZE)
+            .unwrap()
+            .history_ignore_space(true)
+            .completion_type(CompletionType::List)
+            .edit_mode(EditMode::Emacs)
+            .build();
+        let helper = PhantomCompleter {
+            filename_completer: FilenameCompleter::new(),
+        };
+        let mut editor = Editor::with_config(editor_config).unwrap();
+        editor.set_helper(Some(helper));
+        
+        if let Err(err) = editor.load_history(&history_file) {
+            log::warn!("Failed to load history: {}", err);
+        }
+
+        let shell_error = validate_shell(&shell_path).err();
+        let shell_path = if shell_error.is_some() { get_default_shell() } else { shell_path };
+
+        let term = term_for_shell(&shell_path).to_string();
+
+        let (cols, rows) = initial_grid_size(config, |name| env::var(name).ok());
+
+        let mut phantom_tty = PhantomTTY {
+            shell_path,
+            env,
+            history_file,
+            editor,
+            terminal: TerminalWidget::new(),
+            term,
+            pty_master: None,
+            pty_reader: None,
+            waker,
+            vte_terminal: VteTerminal::new(cols, rows),
+            last_command: None,
+            not_found_helper: None,
+            locked_size: None,
+            mirror: None,
+            limits: config.limits.clone(),
+            font_request: None,
+            clipboard: ClipboardRing::new(config.clipboard.clone()),
+            clipboard_picker_open: false,
+            custom_commands: config.phantom_command.iter()
+                .map(|command| (command.name.clone(), command.run.clone()))
+                .collect(),
+            child: None,
+            relativize_dropped_paths: config.relativize_dropped_paths,
+            open_with: config.open_with.clone(),
+            binary_output: config.binary_output.clone(),
+            autosave: config.autosave.clone(),
+            unsaved_output: None,
+            autosaved_rows: 0,
+            autosave_path: None,
+            separate_stderr: config.separate_stderr,
+            restore_session: resume && config.restore_session,
+            confirm_commands: DANGEROUS_COMMANDS.iter().map(|p| p.to_string())
+                .chain(config.confirm_commands.iter().cloned())
+                .filter_map(|pattern| regex::Regex::new(&pattern)
+                    .map_err(|e| log::warn!("Ignoring invalid confirm_commands pattern {}: {}", pattern, e))
+                    .ok())
+                .collect(),
+            pending_confirmation: None,
+            binary_prompt: false,
+            pty_closed: false,
+            shell_exited: false,
+            last_used: Instant::now(),
+            binary_seen: false,
+            paste_config: config.paste.clone(),
+            paste_files: Vec::new(),
+            copy_tabs_as_spaces: config.copy_tabs_as_spaces,
+            copy_requests: Vec::new(),
+            detach_on_close: resume && config.detach_on_close,
+            clear_on_command: config.clear_on_command,
+            latency: None,
+            run_pending: None,
+            insert_multiline: config.insert_multiline,
+            echo_override: None,
+            notifications: config.notifications,
+            theme: Theme::default(),
+            theme_request: None,
+            spawn_request: None,
+            close_request: false,
+        };
+        match config.theme.validate() {
+            Ok(()) => phantom_tty.theme_request = Some(config.theme.clone()),
+            Err(e) => {
+                log::warn!("Ignoring invalid theme in config: {}", e);
+                if config.high_contrast {
+                    phantom_tty.theme_request = Some(Theme::default());
+                }
+            }
+        }
+        phantom_tty.terminal.set_history(phantom_tty.editor.history().iter());
+        phantom_tty.terminal.clear_scrolls = config.clear_scrolls;
+        phantom_tty.terminal.home_end_to_program = config.home_end_to_program;
+        phantom_tty.terminal.show_exit_badges = config.show_exit_badges;
+        phantom_tty.terminal.highlight_current_line = config.highlight_current_line;
+        phantom_tty.terminal.high_contrast = config.high_contrast;
+        if let Some(size) = config.font_size {
+            phantom_tty.terminal.font_size = size.clamp(MIN_FONT_SIZE, MAX_FONT_SIZE);
+        }
+        phantom_tty.vte_terminal.scrollback_limit = config.scrollback_lines.unwrap_or(DEFAULT_SCROLLBACK_LINES);
+        phantom_tty.terminal.open_modifier = config.open_modifier;
+        phantom_tty.vte_terminal.allow_window_resize = config.allow_window_resize;
+        phantom_tty.vte_terminal.safe_mode = config.safe_mode;
+        if let Some(name) = &config.command_not_found {
+            match not_found_helper(name) {
+                Some(helper) => phantom_tty.not_found_helper = helper,
+                None => log::warn!("Ignoring unknown command_not_found helper {}", name),
+            }
+        }
+        phantom_tty.vte_terminal.default_cursor_style = config.cursor;
+        phantom_tty.vte_terminal.cursor_style = config.cursor;
+        if let Some(tab_width) = config.tab_width.filter(|&width| width > 0) {
+            phantom_tty.vte_terminal.set_tab_width(tab_width);
+        }
+        match shell_error {
+            Some(error) => phantom_tty.terminal.set_output(&format!("{}, using {}\n", error, phantom_tty.shell_path)),
+            None => phantom_tty.terminal.set_output("Welcome to PhantomTTY!\n"),
+        }
+        
+        if let Some(session) = phantom_tty.detach_on_close.then(|| reattach_session(&session_socket_path())).flatten() {
+            phantom_tty.attach(session);
+        } else if let Err(e) = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            if let Some(snapshot) = phantom_tty.restore_session.then(SessionSnapshot::load).flatten() {
+                phantom_tty.vte_terminal.restore_snapshot(snapshot);
+            }
+            phantom_tty.start_shell();
+        })) {
+            log::error!("Error starting shell: {:?}", e);
+            phantom_tty.terminal.set_output("Failed to start shell. Some features may not work correctly.\n");
+        }
+        
+        phantom_tty
+    }
+
+    fn start_shell(&mut self) {
+        let stderr_pipe = if self.separate_stderr {
+            nix::unistd::pipe()
+                .map_err(|e| log::warn!("Failed to create stderr pipe, using the PTY: {}", e))
+                .ok()
+        } else {
+            None
+        };
+        let (pty_master, child) = spawn_shell(
+            &self.shell_path,
+            &self.term,
+            &self.env,
+            &self.limits,
+            (self.vte_terminal.width, self.vte_terminal.height),
+            stderr_pipe,
+        );
+        let (tx, rx) = mpsc::channel();
+        match pty_master.try_clone() {
+            Ok(reader) => {
+                spawn_pty_reader(reader, tx.clone(), self.waker.clone());
+                self.pty_reader = Some(rx);
+            }
+            Err(e) => log::warn!("Failed to start PTY reader: {}", e),
+        }
+        if let Some((read_end, _)) = stderr_pipe {
+            spawn_stderr_reader(unsafe { File::from_raw_fd(read_end) }, tx, self.waker.clone());
+        }
+        self.pty_master = Some(pty_master);
+        self.child = Some(child);
+    }
+
+    /// Takes over a shell left running by a previous window, replaying its recent
+    /// output into the grid.
+    fn attach(&mut self, session: DetachedSession) {
+        match session.master.try_clone() {
+            Ok(reader) => {
+                let (tx, rx) = mpsc::channel();
+                spawn_pty_reader(reader, tx, self.waker.clone());
+                self.pty_reader = Some(rx);
+            }
+            Err(e) => log::warn!("Failed to start PTY reader: {}", e),
+        }
+        self.pty_master = Some(session.master);
+        self.child = Some(session.child);
+        self.vte_terminal.process(&session.replay);
+        // Replies to queries from before the detach are stale.
+        self.vte_terminal.take_responses();
+        self.terminal.set_screen(&self.vte_terminal.screen_text(), &self.vte_terminal);
+        self.set_pty_size();
+    }
+
+    /// Sets the font size and resizes the grid and PTY to what now fits.
+    fn set_font_size(&mut self, ctx: &egui::Context, size: f32) {
+        self.terminal.font_size = size.clamp(MIN_FONT_SIZE, MAX_FONT_SIZE);
+        if let Some((cols, rows)) = self.terminal.grid_size(ctx) {
+            self.resize(cols, rows);
+        }
+    }
+
+    /// Copies the screen into the mirror's shared state, dropping the mirror once
+    /// its window has closed.
+    fn sync_mirror(&mut self) -> Option<Arc<Mutex<MirrorState>>> {
+        let shared = self.mirror.clone()?;
+        let mut state = shared.lock().unwrap();
+        if state.closed {
+            drop(state);
+            self.mirror = None;
+            return None;
+        }
+        state.screen = self.vte_terminal.get_screen();
+        drop(state);
+        Some(shared)
+    }
+
+    /// Resizes the grid and the PTY, unless the size is locked.
+    fn resize(&mut self, cols: usize, rows: usize) {
+        let (cols, rows) = self.locked_size.unwrap_or((cols, rows));
+        if cols == 0 || rows == 0 || (cols, rows) == (self.vte_terminal.width, self.vte_terminal.height) {
+            return;
+        }
+        self.vte_terminal.resize(cols, rows);
+        self.terminal.set_screen(&self.vte_terminal.screen_text(), &self.vte_terminal);
+        self.set_pty_size();
+    }
+
+    /// Tells the PTY the current grid size.
+    fn set_pty_size(&self) {
+        if let Some(ref master) = self.pty_master {
+            let winsize = Winsize {
+                ws_row: self.vte_terminal.height as u16,
+                ws_col: self.vte_terminal.width as u16,
+                ws_xpixel: 0,
+                ws_ypixel: 0,
+            };
+            unsafe {
+                if libc::ioctl(master.as_raw_fd(), libc::TIOCSWINSZ, &winsize) == -1 {
+                    log::warn!("Failed to resize PTY: {}", io::Error::last_os_error());
+                }
+            }
+        }
+    }
+
+    /// Whether the child wants typed input echoed. Readline prompts run with ECHO and
+    /// ICANON both off and do their own echo, so only a canonical read with ECHO off
+    /// (`read -s`, `sudo`, `passwd`) counts as hidden input.
+    fn pty_echo(&self) -> Option<bool> {
+        let master = self.pty_master.as_ref()?;
+        let attrs = termios::tcgetattr(master.as_raw_fd()).ok()?;
+        let flags = attrs.local_flags;
+        Some(flags.contains(termios::LocalFlags::ECHO) || !flags.contains(termios::LocalFlags::ICANON))
+    }
+
+    fn update_input_masking(&mut self) {
+        let echo = self.echo_override.or_else(|| self.pty_echo()).unwrap_or(true);
+        self.terminal.mask_input = !echo;
+    }
+
+    /// Runs `command` in the shell, capturing its output between the OSC 133 `C`
+    /// and `D` marks. The output is sent to `reply` once the command finishes, or
+    /// `RunTimeout` after `RUN_TIMEOUT`, so a caller on another thread can block on
+    /// it while the UI keeps polling. A capture already waiting is dropped, which
+    /// its caller sees as a closed channel.
+    fn run_capture(&mut self, command: &str, reply: mpsc::Sender<Result<String, RunTimeout>>) -> io::Result<()> {
+        self.start_capture(command, RunSink::Caller(reply))
+    }
+
+    fn start_capture(&mut self, command: &str, sink: RunSink) -> io::Result<()> {
+        self.vte_terminal.capture_armed = true;
+        self.vte_terminal.captured = None;
+        self.run_pending = Some((Instant::now() + RUN_TIMEOUT, sink));
+        self.execute_in_shell(command)
+    }
+
+    /// Hands a finished capture to its sink, or gives up on it once the shell has
+    /// been silent about it for `RUN_TIMEOUT`.
+    fn poll_run_capture(&mut self) {
+        let Some((deadline, _)) = self.run_pending else { return };
+        if let Some(output) = self.vte_terminal.take_captured() {
+            let Some((_, sink)) = self.run_pending.take() else { return };
+            let output = output.trim_end_matches('\n');
+            match sink {
+                RunSink::Clipboard => {
+                    let lines = output.lines().count();
+                    self.terminal.set_output(&format!("phantom:run copied {} line{} of output to the clipboard", lines, if lines == 1 { "" } else { "s" }));
+                    self.copy_requests.push(output.to_string());
+                }
+                RunSink::Input => self.insert_output(output),
+                RunSink::Caller(tx) => {
+                    let _ = tx.send(Ok(output.to_string()));
+                }
+            }
+        } else if Instant::now() >= deadline {
+            let Some((_, sink)) = self.run_pending.take() else { return };
+            self.vte_terminal.capture_armed = false;
+            self.vte_terminal.capture = None;
+            let name = match sink {
+                RunSink::Caller(tx) => {
+                    let _ = tx.send(Err(RunTimeout));
+                    return;
+                }
+                RunSink::Clipboard => "run",
+                RunSink::Input => "insert",
+            };
+            self.terminal.set_output(&format!("phantom:{} timed out waiting for the command to finish (is OSC 133 shell integration set up?)", name));
+        }
+    }
+
+    /// Appends captured output to the input line, multi-line output joined or
+    /// quoted per `insert_multiline`.
+    fn insert_output(&mut self, output: &str) {
+        let text = match self.insert_multiline {
+            _ if !output.contains('\n') => output.to_string(),
+            MultilineInsert::Join => output.split_whitespace().collect::<Vec<_>>().join(" "),
+            MultilineInsert::Quote => shell_quote(output),
+        };
+        if !self.terminal.input.is_empty() && !self.terminal.input.ends_with(' ') {
+            self.terminal.input.push(' ');
+        }
+        self.terminal.input.push_str(&text);
+    }
+
+    fn scroll_clear(&mut self) {
+        self.vte_terminal.scroll_clear();
+        self.terminal.set_screen(&self.vte_terminal.screen_text(), &self.vte_terminal);
+    }
+
+    /// `phantom:clear`: blanks the screen and drops the scrollback, then sends form
+    /// feed so the shell redraws its prompt at the top.
+    fn clear(&mut self) -> io::Result<()> {
+        self.vte_terminal.erase_in_display(3, false);
+        self.terminal.set_screen(&self.vte_terminal.screen_text(), &self.vte_terminal);
+        self.send_bytes(b"\x0c")
+    }
+
+    /// Feeds whatever the reader thread has flushed into the terminal.
+    fn read_pty_output(&mut self) {
+        let Some(ref reader) = self.pty_reader else { return };
+        let mut chunks = Vec::new();
+        let mut resumed = false;
+        for event in reader.try_iter() {
+            match event {
+                PtyEvent::Output(chunk) => chunks.push((chunk, false)),
+                PtyEvent::Stderr(chunk) => chunks.push((chunk, true)),
+                PtyEvent::Resumed => resumed = true,
+                PtyEvent::Closed => self.pty_closed = true,
+            }
+        }
+        if resumed {
+            self.redraw_after_resume();
+        }
+        if chunks.is_empty() {
+            return;
+        }
+        if self.binary_output.action != BinaryOutputAction::Off && self.check_binary_output(&chunks) {
+            return;
+        }
+        let now = Instant::now();
+        for (chunk, stderr) in &chunks {
+            if let Some(ref mut latency) = self.latency {
+                latency.received(chunk, now);
+            }
+            self.vte_terminal.stderr_output = *stderr;
+            self.vte_terminal.process(chunk);
+        }
+        self.vte_terminal.stderr_output = false;
+        if self.autosave.enabled {
+            self.unsaved_output = Some(now);
+        }
+        self.terminal.latency = self.latency.as_ref().map(|probe| probe.average());
+        let responses = self.vte_terminal.take_responses();
+        if let Err(e) = self.send_bytes(&responses) {
+            log::error!("Error writing to PTY: {}", e);
+        }
+        let mut output = self.vte_terminal.screen_text();
+        if let Some(suggestion) = self.command_not_found_suggestion() {
+            output.push_str(&suggestion);
+            output.push('\n');
+        }
+        self.terminal.set_screen(&output, &self.vte_terminal);
+    }
+
+    /// Flags the first batch of a run of binary-looking output, prompting or
+    /// resetting as configured. Returns true when the output was stopped and
+    /// should be dropped.
+    fn check_binary_output(&mut self, chunks: &[(Vec<u8>, bool)]) -> bool {
+        let binary = chunks.iter().any(|(chunk, _)| looks_binary(chunk, self.binary_output.threshold));
+        if !binary {
+            self.binary_seen = false;
+            return false;
+        }
+        if std::mem::replace(&mut self.binary_seen, true) {
+            return false;
+        }
+        match self.binary_output.action {
+            BinaryOutputAction::Prompt => self.binary_prompt = true,
+            BinaryOutputAction::Reset => {
+                self.stop_binary_output();
+                return true;
+            }
+            BinaryOutputAction::Off => {}
+        }
+        false
+    }
+
+    /// Once the PTY has closed, reaps the shell without blocking and notes how it
+    /// ended on the screen, then leaves the restart-or-close prompt up.
+    fn poll_shell_exit(&mut self) {
+        if !self.pty_closed {
+            return;
+        }
+        let Some(message) = reap_shell(self.child) else { return };
+        self.pty_closed = false;
+        self.child = None;
+        self.pty_master = None;
+        self.pty_reader = None;
+        self.vte_terminal.process(format!("\r\n{}\r\n", message).as_bytes());
+        self.terminal.set_screen(&self.vte_terminal.screen_text(), &self.vte_terminal);
+        self.shell_exited = true;
+    }
+
+    /// Writes the transcript when `autosave` says it's due. Returns how long until an
+    /// idle save would be due, for scheduling a repaint.
+    fn poll_autosave(&mut self) -> Option<Duration> {
+        let quiet = self.unsaved_output?.elapsed();
+        let new_rows = self.vte_terminal.scrolled_rows.saturating_sub(self.autosaved_rows);
+        if !self.autosave.due(quiet, new_rows) {
+            return (self.autosave.idle_seconds > 0)
+                .then(|| Duration::from_secs(self.autosave.idle_seconds).saturating_sub(quiet));
+        }
+        self.unsaved_output = None;
+        self.autosaved_rows = self.vte_terminal.scrolled_rows;
+        if let Err(e) = self.save_transcript() {
+            log::error!("Error autosaving scrollback: {}", e);
+        }
+        None
+    }
+
+    /// Writes the scrollback and screen text to this tab's autosave file, through a
+    /// temp file so a crash mid-write leaves the previous save. The first save picks
+    /// the file and prunes the folder down to `autosave.keep` files.
+    fn save_transcript(&mut self) -> io::Result<()> {
+        let path = match &self.autosave_path {
+            Some(path) => path.clone(),
+            None => {
+                let dir = get_autosave_dir();
+                fs::create_dir_all(&dir)?;
+                let mut saved: Vec<PathBuf> = fs::read_dir(&dir)?
+                    .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                    .filter(|path| path.extension().is_some_and(|ext| ext == "txt"))
+                    .collect();
+                saved.sort();
+                let excess = (saved.len() + 1).saturating_sub(self.autosave.keep.max(1));
+                for old in &saved[..excess] {
+                    fs::remove_file(old)?;
+                }
+                let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+                let pid = self.child.map_or(0, |child| child.as_raw());
+                let path = dir.join(format!("{}-{}.txt", started, pid));
+                self.autosave_path = Some(path.clone());
+                path
+            }
+        };
+        let temp = path.with_extension("tmp");
+        fs::write(&temp, self.vte_terminal.transcript(self.copy_tabs_as_spaces))?;
+        fs::rename(&temp, &path)
+    }
+
+    /// Interrupts the foreground program and resets the terminal (RIS), dropping
+    /// output that already arrived.
+    fn stop_binary_output(&mut self) {
+        if let Err(e) = self.send_bytes(&[0x03]) {
+            log::error!("Error writing to PTY: {}", e);
+        }
+        if let Some(ref reader) = self.pty_reader {
+            reader.try_iter().for_each(drop);
+        }
+        self.vte_terminal.process(b"\x1bc");
+        self.terminal.set_screen(&self.vte_terminal.screen_text(), &self.vte_terminal);
+    }
+
+    /// After a suspend the PTY size is re-sent and the foreground program gets a
+    /// SIGWINCH so a full-screen app repaints whatever it lost, and the grid is
+    /// redrawn in full.
+    fn redraw_after_resume(&mut self) {
+        self.set_pty_size();
+        if let Some(ref master) = self.pty_master {
+            let pgrp = unsafe { libc::tcgetpgrp(master.as_raw_fd()) };
+            if pgrp > 0 {
+                unsafe { libc::kill(-pgrp, libc::SIGWINCH) };
+            }
+        }
+        self.terminal.set_screen(&self.vte_terminal.screen_text(), &self.vte_terminal);
+    }
+
+    fn command_not_found_suggestion(&mut self) -> Option<String> {
+        if self.vte_terminal.take_exit_status() != Some(127) {
+            return None;
+        }
+        let helper = self.not_found_helper.as_ref()?;
+        let program = self.last_command.as_deref()?.split_whitespace().next()?;
+        helper.suggest(program)
+    }
+
+    /// The shell's working directory, from OSC 7 or else `/proc`.
+    fn cwd(&self) -> Option<PathBuf> {
+        self.vte_terminal.cwd.clone()
+            .or_else(|| fs::read_link(format!("/proc/{}/cwd", self.child?)).ok())
+    }
+
+    /// Appends dropped files to the input line, shell-quoted.
+    fn insert_dropped_paths(&mut self, paths: &[PathBuf]) {
+        let cwd = self.cwd();
+        for path in paths {
+            let text = dropped_path_text(path, cwd.as_deref(), self.relativize_dropped_paths);
+            if !self.terminal.input.is_empty() && !self.terminal.input.ends_with(' ') {
+                self.terminal.input.push(' ');
+            }
+            self.terminal.input.push_str(&shell_quote(&text));
+        }
+    }
+
+    /// Opens the path under a modifier-click by typing a command into the shell:
+    /// `cd` for a directory, the `open_with` entry for the file's extension, or
+    /// `$EDITOR`. Words that aren't existing paths are ignored.
+    fn open_path_at(&mut self, row: usize, col: usize) -> io::Result<()> {
+        let Some(token) = self.vte_terminal.token_at(row, col) else { return Ok(()) };
+        let expanded = match token.strip_prefix("~/") {
+            Some(rest) => dirs::home_dir().map_or_else(|| PathBuf::from(&token), |home| home.join(rest)),
+            None => PathBuf::from(&token),
+        };
+        let path = match self.cwd() {
+            Some(cwd) if expanded.is_relative() => cwd.join(&expanded),
+            _ => expanded,
+        };
+        let command = if path.is_dir() {
+            "cd".to_string()
+        } else if path.is_file() {
+            path.extension()
+                .and_then(|ext| self.open_with.get(&ext.to_string_lossy().to_lowercase()).cloned())
+                .or_else(|| env::var("EDITOR").ok().filter(|editor| !editor.is_empty()))
+                .unwrap_or_else(|| "vi".to_string())
+        } else {
+            return Ok(());
+        };
+        self.send_bytes(format!("{} {}\r", command, shell_quote(&path.display().to_string())).as_bytes())
+    }
+
+    fn save_history(&mut self) {
+        if let Err(err) = self.editor.save_history(&self.history_file) {
+            log::error!("Error saving history: {}", err);
+        }
+    }
+
+    /// Records a command in the editor's history, which is what gets saved, and
+    /// refreshes the input line's view of it. Nothing typed at a hidden prompt is kept.
+    fn add_history(&mut self, command: &str) {
+        if self.terminal.mask_input {
+            return;
+        }
+        if let Err(e) = self.editor.add_history_entry(command) {
+            log::error!("Error adding to history: {}", e);
+        }
+        self.terminal.set_history(self.editor.history().iter());
+    }
+
+    fn execute_command(&mut self, command: &str) -> io::Result<()> {
+        self.add_history(command);
+
+        match command {
+            "history" => self.show_history(),
+            "exit" => {
+                self.save_history();
+                self.close_request = true;
+                Ok(())
+            },
+            _ if command.starts_with("phantom:") => self.handle_phantom_command(&command[8..]),
+            _ => {
+                if let Some(pattern) = self.confirm_commands.iter().find(|re| re.is_match(command)) {
+                    self.pending_confirmation = Some((command.to_string(), pattern.as_str().to_string()));
+                    return Ok(());
+                }
+                self.run_command(command)
+            }
+        }
+    }
+
+    /// Runs a snippet clicked in the snippets panel as if it had been typed.
+    fn run_snippet(&mut self, snippet: &Snippet) -> io::Result<()> {
+        self.execute_command(&snippet.command)
+    }
+
+    /// Sends a submitted command to the shell, clearing first if configured.
+    fn run_command(&mut self, command: &str) -> io::Result<()> {
+        if self.clear_on_command {
+            self.scroll_clear();
+        }
+        self.execute_in_shell(command)
+    }
+
+    /// Lists the history oldest first, numbered in the order it's saved to the file.
+    fn show_history(&mut self) -> io::Result<()> {
+        let history_output: String = self.editor.history()
+            .iter()
+            .enumerate()
+            .map(|(i, cmd)| format!("{}: {}\n", i + 1, cmd))
+            .collect();
+        self.terminal.set_output(&history_output);
+        Ok(())
+    }
+
+    fn handle_phantom_command(&mut self, command: &str) -> io::Result<()> {
+        let command = command.trim();
+        let (name, args) = command.split_once(' ').unwrap_or((command, ""));
+        match name {
+            "hello" => self.terminal.set_output("Hello from PhantomTTY!"),
+            "shell" => self.terminal.set_output(&format!("Current shell: {}", self.shell_path)),
+            "clear" => self.clear()?,
+            "spawn" if !args.trim().is_empty() => match validate_shell(args.trim()) {
+                Ok(()) => self.spawn_request = Some(args.trim().to_string()),
+                Err(e) => self.terminal.set_output(&e),
+            },
+            "spawn" => self.terminal.set_output("Usage: phantom:spawn <shell path>"),
+            "safe-mode" => match args.trim() {
+                "on" => {
+                    self.vte_terminal.safe_mode = true;
+                    self.terminal.set_output("Safe mode enabled: output can't change the title (OSC 0/2), write the clipboard (OSC 52), open links (OSC 8), notify (OSC 9/777), resize the window (CSI 8 t) or ask for status reports (DSR/CPR)");
+                }
+                "off" => {
+                    self.vte_terminal.safe_mode = false;
+                    self.terminal.set_output("Safe mode disabled");
+                }
+                "" => {
+                    let state = if self.vte_terminal.safe_mode { "on" } else { "off" };
+                    self.terminal.set_output(&format!("Safe mode is {}", state));
+                }
+                _ => self.terminal.set_output("Usage: phantom:safe-mode [on|off]"),
+            },
+            "lock-size" => {
+                let dims: Vec<usize> = args.split_whitespace().filter_map(|n| n.parse().ok()).collect();
+                match dims[..] {
+                    [cols, rows] if cols > 0 && rows > 0 && cols <= u16::MAX as usize && rows <= u16::MAX as usize => {
+                        self.locked_size = None;
+                        self.resize(cols, rows);
+                        self.locked_size = Some((cols, rows));
+                        self.terminal.letterbox = Some((cols, rows));
+                    }
+                    _ => self.terminal.set_output("Usage: phantom:lock-size <cols> <rows>"),
+                }
+            }
+            "unfocused-repaint" => match args.trim() {
+                "off" => self.terminal.unfocused_repaint = None,
+                ms => match ms.parse() {
+                    Ok(ms) => self.terminal.unfocused_repaint = Some(Duration::from_millis(ms)),
+                    Err(_) => self.terminal.set_output("Usage: phantom:unfocused-repaint <ms>|off"),
+                },
+            },
+            "font" => match args.trim().parse::<f32>() {
+                Ok(size) if size.is_finite() => self.font_request = Some(size),
+                _ => self.terminal.set_output("Usage: phantom:font <size>"),
+            },
+            "clipboard" => self.clipboard_picker_open = true,
+            "copy-screen" => {
+                let text = self.vte_terminal.copy_text(self.copy_tabs_as_spaces);
+                self.copy_requests.push(text);
+            }
+            "echo" => match args.trim() {
+                "on" => self.echo_override = Some(true),
+                "off" => self.echo_override = Some(false),
+                "auto" => self.echo_override = None,
+                _ => self.terminal.set_output("Usage: phantom:echo on|off|auto"),
+            },
+            "export-theme" if !args.trim().is_empty() => {
+                let file = ThemeFile { theme: self.theme.clone() };
+                let text = toml::to_string(&file).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                fs::write(args.trim(), text)?;
+                self.terminal.set_output(&format!("Theme written to {}", args.trim()));
+            }
+            "import-theme" if !args.trim().is_empty() => {
+                let text = fs::read_to_string(args.trim())?;
+                match toml::from_str::<ThemeFile>(&text).map_err(|e| e.to_string()).and_then(|file| {
+                    file.theme.validate()?;
+                    Ok(file.theme)
+                }) {
+                    Ok(theme) => self.theme_request = Some(theme),
+                    Err(e) => self.terminal.set_output(&format!("Invalid theme {}: {}", args.trim(), e)),
+                }
+            }
+            "export-theme" | "import-theme" => self.terminal.set_output(&format!("Usage: phantom:{} <path>", name)),
+            "run" | "insert" if !args.trim().is_empty() => {
+                // `phantom:insert $(cmd)` reads naturally; the wrapper is optional.
+                let command = args.trim();
+                let command = command.strip_prefix("$(").and_then(|c| c.strip_suffix(')')).unwrap_or(command);
+                let sink = if name == "insert" { RunSink::Input } else { RunSink::Clipboard };
+                return self.start_capture(command, sink);
+            }
+            "insert" => self.terminal.set_output("Usage: phantom:insert <command>"),
+            "run" => self.terminal.set_output("Usage: phantom:run <command>"),
+            "latency" => match args.trim() {
+                "on" => {
+                    self.latency = Some(LatencyProbe::default());
+                    self.terminal.latency = Some(None);
+                }
+                "off" => {
+                    self.latency = None;
+                    self.terminal.latency = None;
+                }
+                _ => self.terminal.set_output("Usage: phantom:latency on|off"),
+            },
+            "reflow" => match args.trim().parse::<usize>() {
+                Ok(width) if width > 0 => {
+                    let rows = reflow_rows(&self.vte_terminal.wrapped_rows(), width);
+                    let text: Vec<String> = rows.into_iter().map(|(text, _)| text).collect();
+                    self.copy_requests.push(text.join("\n").trim_end_matches('\n').to_string());
+                    self.terminal.set_output(&format!("Copied the screen reflowed to {} columns", width));
+                }
+                _ => self.terminal.set_output("Usage: phantom:reflow <width>"),
+            },
+            "whitespace" => match args.trim() {
+                "on" => self.terminal.show_whitespace = true,
+                "off" => self.terminal.show_whitespace = false,
+                _ => self.terminal.set_output("Usage: phantom:whitespace on|off"),
+            },
+            "high-contrast" => match args.trim() {
+                "on" | "off" => {
+                    self.terminal.high_contrast = args.trim() == "on";
+                    self.theme_request = Some(self.theme.clone());
+                }
+                _ => self.terminal.set_output("Usage: phantom:high-contrast on|off"),
+            },
+            "state" => self.terminal.set_output(&self.vte_terminal.state_dump()),
+            "mirror" => {
+                if self.mirror.take().is_none() {
+                    self.mirror = Some(Arc::new(Mutex::new(MirrorState::default())));
+                }
+            }
+            "unlock-size" => {
+                self.locked_size = None;
+                self.terminal.letterbox = None;
+            }
+            "not-found" => match not_found_helper(args.trim()) {
+                Some(helper) => {
+                    self.not_found_helper = helper;
+                    self.terminal.set_output(&format!("Command-not-found helper: {}", args.trim()));
+                }
+                None => self.terminal.set_output("Usage: phantom:not-found off|did-you-mean|command-not-found"),
+            },
+            // Config-defined commands come after the builtins, so they can't shadow one.
+            _ => match self.custom_commands.get(name) {
+                Some(template) => {
+                    let expanded = expand_custom_command(template, args);
+                    return self.execute_in_shell(&expanded);
+                }
+                None => self.terminal.set_output(&format!("Unknown PhantomTTY command: {}", command)),
+            },
+        }
+        Ok(())
+    }
+
+    fn execute_in_shell(&mut self, command: &str) -> io::Result<()> {
+        let command = strip_paste_markers(command);
+        self.last_command = Some(command.clone());
+        if let Some(ref mut latency) = self.latency {
+            latency.sent(command.as_bytes(), Instant::now());
+        }
+        // A line holding a paste goes in brackets when the shell asked for them,
+        // so pasted newlines are inserted rather than each run on the spot.
+        let bracketed = std::mem::take(&mut self.terminal.paste_submitted) && self.vte_terminal.bracketed_paste;
+        if let Some(ref mut master) = self.pty_master {
+            if bracketed {
+                writeln!(master, "{}{}{}", PASTE_START, command, PASTE_END)?;
+            } else {
+                writeln!(master, "{}", command)?;
+            }
+            master.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Writes pasted text to the PTY as typed input, without submitting it.
+    fn paste(&mut self, text: &str) -> io::Result<()> {
+        if self.paste_config.temp_file_threshold.is_some_and(|threshold| text.len() > threshold) {
+            return self.paste_via_temp_file(text);
+        }
+        let mut text = strip_paste_markers(text);
+        if self.vte_terminal.bracketed_paste {
+            text = format!("{}{}{}", PASTE_START, text, PASTE_END);
+        }
+        self.send_bytes(text.as_bytes())
+    }
+
+    /// Pastes the clipboard history entry picked in `phantom:clipboard`.
+    fn paste_clipboard_entry(&mut self, index: usize) -> io::Result<()> {
+        match self.clipboard.entries.get(index).cloned() {
+            Some(text) => self.paste(&text),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes raw input (key sequences, pasted text) to the PTY.
+    fn send_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        if let Some(ref mut latency) = self.latency {
+            latency.sent(bytes, Instant::now());
+        }
+        if let Some(ref mut master) = self.pty_master {
+            master.write_all(bytes)?;
+            master.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Writes an oversized paste to a private temp file and puts a reference to it in
+    /// the input line, instead of pushing it through the line discipline.
+    fn paste_via_temp_file(&mut self, text: &str) -> io::Result<()> {
+        use std::os::unix::fs::OpenOptionsExt;
+        let path = env::temp_dir().join(format!("phantom-paste-{}-{}", std::process::id(), self.paste_files.len()));
+        let mut file = fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path)?;
+        file.write_all(text.as_bytes())?;
+        self.paste_files.push(path.clone());
+
+        let reference = format!("\"$(cat {})\"", shell_quote(&path.display().to_string()));
+        self.terminal.input.push_str(&reference);
+        self.terminal.set_output(&format!("Pasted {} bytes to {}", text.len(), path.display()));
+        Ok(())
+    }
+
+    fn remove_paste_files(&mut self) {
+        for path in self.paste_files.drain(..) {
+            if let Err(e) = fs::remove_file(&path) {
+                log::error!("Error removing {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// Saves the screen and scrollback for the next launch, with `restore_session`
+    /// on. A session kept running by `detach_on_close` is reattached instead.
+    fn save_session(&self) {
+        if self.restore_session && !self.detach_on_close {
+            self.vte_terminal.snapshot().save();
+        }
+    }
+}
+
+impl Drop for PhantomTTY {
+    fn drop(&mut self) {
+        self.remove_paste_files();
+        self.save_session();
+        if self.detach_on_close {
+            if let (Some(master), Some(child)) = (self.pty_master.take(), self.child) {
+                if let Err(e) = detach_session(&master, child) {
+                    log::error!("Error detaching session: {}", e);
+                }
+            }
+        } else if let Some(child) = self.child.take() {
+            // Closing the master hangs up on the shell; reap it off the UI thread.
+            self.pty_master = None;
+            thread::spawn(move || {
+                let _ = waitpid(child, None);
+            });
+        }
+    }
+}
+
+/// Keystroke-to-echo timing for `phantom:latency`. Only writes that start with a
+/// printable byte are tracked (so query replies and key sequences are not), and
+/// that byte counts as echoed the next time it turns up in the output. Times
+/// include the reader's batching delay.
+#[derive(Default)]
+struct LatencyProbe {
+    sent: VecDeque<(u8, Instant)>,
+    samples: VecDeque<Duration>,
+}
+
+impl LatencyProbe {
+    const SAMPLES: usize = 20;
+    /// Bytes not echoed within this long (e.g. typed at a hidden prompt) are dropped.
+    const GIVE_UP: Duration = Duration::from_secs(2);
+
+    fn sent(&mut self, bytes: &[u8], now: Instant) {
+        if let Some(&byte) = bytes.first().filter(|b| b.is_ascii_graphic()) {
+            self.sent.push_back((byte, now));
+        }
+    }
+
+    fn received(&mut self, bytes: &[u8], now: Instant) {
+        self.sent.retain(|&(_, at)| now.duration_since(at) < Self::GIVE_UP);
+        for &byte in bytes {
+            let Some(&(expected, at)) = self.sent.front() else { break };
+            if byte == expected {
+                self.sent.pop_front();
+                self.samples.push_back(now.duration_since(at));
+                if self.samples.len() > Self::SAMPLES {
+                    self.samples.pop_front();
+                }
+            }
+        }
+    }
+
+    /// Mean of the recent samples.
+    fn average(&self) -> Option<Duration> {
+        let count = self.samples.len() as u32;
+        (count > 0).then(|| self.samples.iter().sum::<Duration>() / count)
+    }
+}
+
+/// A shell handed back by the session holder on reattach.
+struct DetachedSession {
+    master: File,
+    child: Pid,
+    replay: Vec<u8>,
+}
+
+fn session_socket_path() -> PathBuf {
+    dirs::runtime_dir()
+        .unwrap_or_else(env::temp_dir)
+        .join(format!("phantomtty-{}.sock", nix::unistd::getuid()))
+}
+
+/// Requests from the control socket; the socket file is removed with it.
+struct ControlSocket {
+    path: PathBuf,
+    requests: mpsc::Receiver<ControlRequest>,
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn control_socket_path() -> PathBuf {
+    dirs::runtime_dir()
+        .unwrap_or_else(env::temp_dir)
+        .join(format!("phantomtty-{}-{}-control.sock", nix::unistd::getuid(), std::process::id()))
+}
+
+/// Listens at `path` for control connections, one request line each. A `run`
+/// request goes to the UI, and its connection is held until the command's output,
+/// or an `error:` line, is written back.
+fn serve_control_socket(path: PathBuf, waker: Waker) -> io::Result<ControlSocket> {
+    use std::os::unix::net::UnixListener;
+
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+    let (tx, requests) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let (tx, waker) = (tx.clone(), waker.clone());
+                    thread::spawn(move || {
+                        if let Err(e) = answer_control(stream, &tx, &waker) {
+                            log::error!("Error on control connection: {}", e);
+                        }
+                    });
+                }
+                Err(e) => log::error!("Error accepting control connection: {}", e),
+            }
+        }
+    });
+    Ok(ControlSocket { path, requests })
+}
+
+fn answer_control(stream: std::os::unix::net::UnixStream, tx: &mpsc::Sender<ControlRequest>, waker: &Waker) -> io::Result<()> {
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    let reply = match line.trim().split_once(' ') {
+        Some(("run", command)) => {
+            let (reply, answer) = mpsc::channel();
+            if tx.send(ControlRequest::Run(command.trim().to_string(), reply)).is_err() {
+                return Ok(());
+            }
+            waker();
+            match answer.recv() {
+                Ok(Ok(output)) => output + "\n",
+                Ok(Err(RunTimeout)) => "error: timed out waiting for the command to finish\n".to_string(),
+                Err(_) => "error: the command was not run\n".to_string(),
+            }
+        }
+        _ => "error: expected run <command>\n".to_string(),
+    };
+    (&stream).write_all(reply.as_bytes())
+}
+
+/// Hands the PTY master to a `--hold-session` copy of this binary, which keeps the
+/// shell's output drained until the next window reattaches.
+fn detach_session(master: &File, child: Pid) -> io::Result<()> {
+    let fd = master.as_raw_fd();
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, 0) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    std::process::Command::new(env::current_exe()?)
+        .args(["--hold-session", &fd.to_string(), &child.as_raw().to_string()])
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .spawn()?;
+    Ok(())
+}
+
+/// Session holder: buffers the shell's recent output and waits on the socket at
+/// `path` for a window to say `attach`, then sends it the PTY master (as
+/// SCM_RIGHTS) with a header of `<pid> <replay length>` padded to
+/// `DETACH_HEADER_LEN`, followed by the replay bytes. Exits once the session is
+/// handed over or the shell is gone.
+fn hold_session(mut master: File, child: Pid, path: &Path) -> io::Result<()> {
+    use nix::sys::socket::{sendmsg, ControlMessage, MsgFlags};
+    use std::os::unix::net::UnixListener;
+
+    let _ = fs::remove_file(path);
+    let listener = UnixListener::bind(path)?;
+    let mut replay = VecDeque::with_capacity(DETACH_REPLAY_BYTES);
+    let mut buf = [0u8; 4096];
+    let result = loop {
+        let mut fds = [
+            PollFd::new(master.as_raw_fd(), PollFlags::POLLIN),
+            PollFd::new(listener.as_raw_fd(), PollFlags::POLLIN),
+        ];
+        if let Err(e) = poll(&mut fds, 1000) {
+            if e != nix::errno::Errno::EINTR {
+                break Err(e.into());
+            }
+            continue;
+        }
+        if fds[0].revents().is_some_and(|r| r.intersects(PollFlags::POLLIN | PollFlags::POLLHUP)) {
+            match master.read(&mut buf) {
+                Ok(0) | Err(_) => break Ok(()),
+                Ok(n) => {
+                    replay.extend(&buf[..n]);
+                    let excess = replay.len().saturating_sub(DETACH_REPLAY_BYTES);
+                    replay.drain(..excess);
+                }
+            }
+        }
+        if fds[1].revents().is_some_and(|r| r.contains(PollFlags::POLLIN)) {
+            let (mut stream, _) = listener.accept()?;
+            let mut request = [0u8; 7];
+            if stream.read_exact(&mut request).is_err() || &request != b"attach\n" {
+                continue;
+            }
+            let replay: Vec<u8> = replay.into_iter().collect();
+            let mut header = format!("{} {}", child.as_raw(), replay.len()).into_bytes();
+            header.resize(DETACH_HEADER_LEN, b' ');
+            let fds = [master.as_raw_fd()];
+            sendmsg::<()>(stream.as_raw_fd(), &[io::IoSlice::new(&header)], &[ControlMessage::ScmRights(&fds)], MsgFlags::empty(), None)?;
+            stream.write_all(&replay)?;
+            break Ok(());
+        }
+        if nix::sys::signal::kill(child, None).is_err() {
+            break Ok(());
+        }
+    };
+    let _ = fs::remove_file(path);
+    result
+}
+
+/// Asks a session holder, if one is listening on `path`, for its shell.
+fn reattach_session(path: &Path) -> Option<DetachedSession> {
+    use nix::sys::socket::{recvmsg, ControlMessageOwned, MsgFlags};
+    use std::os::unix::net::UnixStream;
+
+    let mut stream = UnixStream::connect(path).ok()?;
+    let result = (|| -> io::Result<DetachedSession> {
+        stream.write_all(b"attach\n")?;
+        let mut header = [0u8; DETACH_HEADER_LEN];
+        let mut cmsg = nix::cmsg_space!([std::os::unix::io::RawFd; 1]);
+        let (received, fd) = {
+            let mut iov = [io::IoSliceMut::new(&mut header)];
+            let msg = recvmsg::<()>(stream.as_raw_fd(), &mut iov, Some(&mut cmsg), MsgFlags::empty())?;
+            let fd = msg.cmsgs().find_map(|c| match c {
+                ControlMessageOwned::ScmRights(fds) => fds.first().copied(),
+                _ => None,
+            });
+            (msg.bytes, fd)
+        };
+        let fd = fd.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no PTY in handshake"))?;
+        let master = unsafe { File::from_raw_fd(fd) };
+        stream.read_exact(&mut header[received..])?;
+        let header = String::from_utf8_lossy(&header);
+        let mut fields = header.split_whitespace().map(|n| n.parse::<i32>().ok());
+        let (Some(Some(pid)), Some(Some(len))) = (fields.next(), fields.next()) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "bad handshake header"));
+        };
+        let mut replay = vec![0; len.max(0) as usize];
+        stream.read_exact(&mut replay)?;
+        Ok(DetachedSession { master, child: Pid::from_raw(pid), replay })
+    })();
+    match result {
+        Ok(session) => Some(session),
+        Err(e) => {
+            log::warn!("Failed to reattach to {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// The color from an extended SGR color's selector and arguments: `5;index`, or
+/// `2;r;g;b` with an optional color space id before the components.
+fn extended_color(args: &[u16]) -> Option<Color> {
+    let byte = |n: &u16| u8::try_from(*n).ok();
+    match args {
+        [5, index, ..] => byte(index).map(Color::Indexed),
+        [2, _, r, g, b, ..] | [2, r, g, b] => Some(Color::Rgb(byte(r)?, byte(g)?, byte(b)?)),
+        _ => None,
+    }
+}
+
+/// xterm's color for palette entries past the theme's 16: a 6x6x6 color cube,
+/// then a 24-step gray ramp.
+fn xterm_256_color(index: u8) -> egui::Color32 {
+    const LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+    match index {
+        16..=231 => {
+            let i = usize::from(index - 16);
+            egui::Color32::from_rgb(LEVELS[i / 36], LEVELS[i / 6 % 6], LEVELS[i % 6])
+        }
+        232..=255 => {
+            let level = 8 + 10 * (index - 232);
+            egui::Color32::from_rgb(level, level, level)
+        }
+        _ => egui::Color32::GRAY,
+    }
+}
+
+/// Screen characters as text: tab gaps become spaces and wide-glyph spacers go.
+fn grid_text(chars: &[char]) -> String {
+    chars.iter().filter(|&&c| c != WIDE_SPACER).map(|&c| if c == '\t' { ' ' } else { c }).collect()
+}
+
+/// Copies a row-major grid into a new `width` x `height` one, cutting off or padding
+/// each row with `fill`.
+fn reshape_grid<T: Copy>(grid: &[T], old_width: usize, width: usize, height: usize, fill: T) -> Vec<T> {
+    let mut reshaped = vec![fill; width * height];
+    for (row, line) in grid.chunks(old_width).take(height).enumerate() {
+        let len = line.len().min(width);
+        reshaped[row * width..row * width + len].copy_from_slice(&line[..len]);
+    }
+    reshaped
+}
+
+/// Forks `shell_path` on a new PTY of `size` (columns, rows) as a session leader
+/// with the PTY as its controlling terminal. With `stderr_pipe` its stderr goes to
+/// the pipe's write end instead, which is closed here on the parent's side.
+/// Returns the PTY master and the shell's pid.
+fn spawn_shell(
+    shell_path: &str,
+    term: &str,
+    env: &[(String, String)],
+    limits: &Limits,
+    size: (usize, usize),
+    stderr_pipe: Option<(RawFd, RawFd)>,
+) -> (File, Pid) {
+    let winsize = Winsize {
+        ws_row: size.1 as u16,
+        ws_col: size.0 as u16,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+
+    let pty = openpty(Some(&winsize), None).expect("Failed to open pty");
+    let pty_master = unsafe { File::from_raw_fd(pty.master) };
+    let pty_slave = pty.slave;
+    // Built before forking, as the child shouldn't allocate or take the env lock.
+    let shell = std::ffi::CString::new(shell_path).expect("Shell path contains a NUL byte");
+    let envp = shell_environment(term, env);
+
+    match unsafe { fork() }.expect("Fork failed") {
+        ForkResult::Parent { child } => {
+            if let Some((_, write_end)) = stderr_pipe {
+                let _ = nix::unistd::close(write_end);
+            }
+            if let Err(e) = tcsetpgrp(pty_slave, Pid::from_raw(child.as_raw())) {
+                log::warn!("Failed to set controlling process: {}", e);
+            }
+            // Only the shell's side may hold the slave open, or the master never
+            // sees it close.
+            let _ = nix::unistd::close(pty_slave);
+            (pty_master, child)
+        }
+        ForkResult::Child => {
+            if setsid().is_err() {
+                child_warning("Warning: Failed to create new session\n");
+            }
+            
+            if nix::unistd::setpgid(Pid::from_raw(0), Pid::from_raw(0)).is_err() {
+                child_warning("Warning: Failed to set process group\n");
+            }
+            
+            if tcsetpgrp(pty_slave, Pid::from_raw(0)).is_err() {
+                child_warning("Warning: Failed to set foreground process group\n");
+            }
+    
+            unsafe {
+                if libc::ioctl(pty_slave, libc::TIOCSCTTY, 0) == -1 {
+                    child_warning("Warning: Failed to set controlling terminal\n");
+                }
+            }
+
+            nix::unistd::dup2(pty_slave, 0).expect("Failed to redirect stdin");
+            nix::unistd::dup2(pty_slave, 1).expect("Failed to redirect stdout");
+            match stderr_pipe {
+                Some((read_end, write_end)) => {
+                    nix::unistd::dup2(write_end, 2).expect("Failed to redirect stderr");
+                    let _ = nix::unistd::close(read_end);
+                    let _ = nix::unistd::close(write_end);
+                }
+                None => {
+                    nix::unistd::dup2(pty_slave, 2).expect("Failed to redirect stderr");
+                }
+            }
+
+            drop(pty_master);
+
+            let mut termios = termios::tcgetattr(pty_slave).expect("Failed to get terminal attributes");
+            termios::cfmakeraw(&mut termios);
+            termios::tcsetattr(pty_slave, SetArg::TCSANOW, &termios).expect("Failed to set terminal attributes");
+
+            limits.apply();
+
+            let _ = nix::unistd::execve(&shell, &[&shell], &envp);
+            child_warning("Error: Failed to execute shell\n");
+            unsafe { libc::_exit(127) }
+        }
+    }
+}
+
+/// The shell's environment: PhantomTTY's own, with `TERM` set for the shell,
+/// `COLORTERM` announcing 24-bit color, and `extra` on top, each replacing an
+/// inherited variable of the same name. `COLUMNS` and `LINES` are dropped, as
+/// the PTY's size is what counts.
+fn shell_environment(term: &str, extra: &[(String, String)]) -> Vec<std::ffi::CString> {
+    use std::os::unix::ffi::OsStrExt;
+    let mut vars: Vec<(Vec<u8>, Vec<u8>)> = env::vars_os()
+        .map(|(name, value)| (name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+        .filter(|(name, _)| name != b"COLUMNS" && name != b"LINES")
+        .collect();
+    let overrides = [("TERM", term), ("COLORTERM", "truecolor")].into_iter().chain(extra.iter().map(|(name, value)| (name.as_str(), value.as_str())));
+    for (name, value) in overrides {
+        vars.retain(|(inherited, _)| inherited != name.as_bytes());
+        vars.push((name.into(), value.into()));
+    }
+    vars.into_iter()
+        .filter_map(|(mut entry, value)| {
+            entry.push(b'=');
+            entry.extend(value);
+            std::ffi::CString::new(entry).ok()
+        })
+        .collect()
+}
+
+/// Reaps the shell without blocking once its PTY has closed, returning the line
+/// that reports how it ended. `None` while something it started still has the
+/// terminal, or it was stopped.
+fn reap_shell(child: Option<Pid>) -> Option<String> {
+    let code = match child.map(|child| waitpid(child, Some(WaitPidFlag::WNOHANG))) {
+        Some(Ok(WaitStatus::Exited(_, code))) => Some(code),
+        Some(Ok(WaitStatus::Signaled(_, signal, _))) => Some(128 + signal as i32),
+        Some(Ok(_)) => return None,
+        // A reattached shell isn't our child, so there is no status to collect.
+        Some(Err(_)) | None => None,
+    };
+    Some(match code {
+        Some(code) => format!("[process exited with code {}]", code),
+        None => "[process exited]".to_string(),
+    })
+}
+
+/// Wakes the UI when the reader thread has output ready.
+type Waker = Arc<dyn Fn() + Send + Sync>;
+
+enum PtyEvent {
+    Output(Vec<u8>),
+    /// Bytes from the shell's stderr pipe, with `separate_stderr` on.
+    Stderr(Vec<u8>),
+    /// The machine came back from suspend; the UI should redraw everything.
+    Resumed,
+    /// The PTY reached end of file: the shell, and anything else it started on the
+    /// terminal, has closed it.
+    Closed,
+}
+
+/// Time the machine has spent suspended since boot: `CLOCK_BOOTTIME` keeps
+/// counting through suspend while `CLOCK_MONOTONIC` stops.
+fn suspended_time() -> Duration {
+    let read = |clock| {
+        let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+        unsafe { libc::clock_gettime(clock, &mut ts) };
+        Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+    };
+    read(libc::CLOCK_BOOTTIME).saturating_sub(read(libc::CLOCK_MONOTONIC))
+}
+
+/// Spots a resume from suspend in the reader loop: a jump in suspended time, or
+/// `EINTR_STORM` interrupted polls in a row.
+struct ResumeDetector {
+    suspended: Duration,
+    interrupts: u32,
+}
+
+impl ResumeDetector {
+    fn new(suspended: Duration) -> Self {
+        Self { suspended, interrupts: 0 }
+    }
+
+    /// Takes one poll's outcome and the current `suspended_time()`.
+    fn resumed(&mut self, interrupted: bool, suspended: Duration) -> bool {
+        let mut resumed = suspended.saturating_sub(self.suspended) > SUSPEND_THRESHOLD;
+        if interrupted {
+            self.interrupts += 1;
+            resumed |= self.interrupts >= EINTR_STORM;
+        } else {
+            self.interrupts = 0;
+        }
+        if resumed {
+            self.suspended = suspended;
+            self.interrupts = 0;
+        }
+        resumed
+    }
+}
+
+/// Reads the PTY on a background thread and hands output over in batches. A batch
+/// is flushed after `FLUSH_QUIET` without new bytes, so a trailing partial line
+/// such as a prompt renders promptly instead of waiting for more output.
+/// `PtyEvent::Closed` follows the last output once the PTY closes.
+///
+/// A resume from suspend (a jump in suspended time, or a storm of EINTRs) is
+/// reported as `PtyEvent::Resumed` once the fd checks out as still open.
+fn spawn_pty_reader(mut master: File, tx: mpsc::Sender<PtyEvent>, waker: Waker) {
+    thread::spawn(move || {
+        let mut pending = Vec::new();
+        let mut pending_since = Instant::now();
+        let mut buffer = [0u8; 4096];
+        let mut resume = ResumeDetector::new(suspended_time());
+        loop {
+            let timeout = if pending.is_empty() { SUSPEND_CHECK_INTERVAL } else { FLUSH_QUIET };
+            let mut fds = [PollFd::new(master.as_raw_fd(), PollFlags::POLLIN)];
+            let polled = poll(&mut fds, timeout.as_millis() as i32);
+            if resume.resumed(polled == Err(nix::errno::Errno::EINTR), suspended_time()) {
+                if let Err(e) = nix::fcntl::fcntl(master.as_raw_fd(), nix::fcntl::FcntlArg::F_GETFD) {
+                    log::error!("PTY lost across suspend: {}", e);
+                    break;
+                }
+                if tx.send(PtyEvent::Resumed).is_err() {
+                    return;
+                }
+                waker();
+            }
+            let ready = match polled {
+                Ok(n) => n > 0,
+                Err(nix::errno::Errno::EINTR) => continue,
+                Err(e) => {
+                    log::error!("Error polling PTY: {}", e);
+                    break;
+                }
+            };
+            if !ready && pending.is_empty() {
+                continue;
+            }
+            if ready {
+                match master.read(&mut buffer) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        if pending.is_empty() {
+                            pending_since = Instant::now();
+                        }
+                        pending.extend_from_slice(&buffer[..n]);
+                        if pending.len() < FLUSH_MAX_BYTES && pending_since.elapsed() < FLUSH_MAX_DELAY {
+                            continue;
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        // EIO here just means the child side has closed.
+                        if e.raw_os_error() != Some(libc::EIO) {
+                            log::error!("Error reading from PTY: {}", e);
+                        }
+                        break;
+                    }
+                }
+            }
+            if tx.send(PtyEvent::Output(std::mem::take(&mut pending))).is_err() {
+                return;
+            }
+            waker();
+        }
+        if !pending.is_empty() {
+            let _ = tx.send(PtyEvent::Output(pending));
+        }
+        let _ = tx.send(PtyEvent::Closed);
+        waker();
+    });
+}
+
+/// Forwards the shell's stderr pipe as `PtyEvent::Stderr` until every writer has
+/// closed it.
+fn spawn_stderr_reader(mut pipe: File, tx: mpsc::Sender<PtyEvent>, waker: Waker) {
+    thread::spawn(move || {
+        let mut buffer = [0u8; 4096];
+        loop {
+            match pipe.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => {
+                    if tx.send(PtyEvent::Stderr(buffer[..n].to_vec())).is_err() {
+                        return;
+                    }
+                    waker();
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    log::error!("Error reading shell stderr: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+}
+
+/// A shell on a PTY driving a `VteTerminal`, with no window: what `--headless`
+/// runs, and a way to script the terminal and check what it renders.
+struct Session {
+    master: File,
+    child: Pid,
+    events: mpsc::Receiver<PtyEvent>,
+    vte: VteTerminal,
+    /// Set once the reader has seen the PTY close, until the shell is reaped.
+    pty_closed: bool,
+    /// The shell has exited and been reaped.
+    exited: bool,
+}
+
+impl Session {
+    fn start(config: &Config, cols: usize, rows: usize) -> Self {
+        let shell_path = config.shell.clone()
+            .filter(|shell| validate_shell(shell).is_ok())
+            .unwrap_or_else(get_default_shell);
+        let (master, child) = spawn_shell(&shell_path, term_for_shell(&shell_path), &[], &config.limits, (cols, rows), None);
+        let (tx, events) = mpsc::channel();
+        match master.try_clone() {
+            Ok(reader) => spawn_pty_reader(reader, tx, Arc::new(|| {})),
+            Err(e) => log::warn!("Failed to start PTY reader: {}", e),
+        }
+        Self { master, child, events, vte: VteTerminal::new(cols, rows), pty_closed: false, exited: false }
+    }
+
+    /// Writes bytes to the shell as if typed.
+    fn feed_input(&mut self, bytes: &[u8]) {
+        if let Err(e) = self.master.write_all(bytes) {
+            log::error!("Error writing to PTY: {}", e);
+        }
+    }
+
+    /// Waits until the shell has been silent for `HEADLESS_QUIET` (or `RUN_TIMEOUT`
+    /// passes), then returns the screen as copied text. Replies to queries go
+    /// back to the shell, and a shell that has exited is reaped.
+    fn poll_output(&mut self) -> String {
+        let deadline = Instant::now() + RUN_TIMEOUT;
+        while let Ok(event) = self.events.recv_timeout(HEADLESS_QUIET) {
+            match event {
+                PtyEvent::Output(chunk) | PtyEvent::Stderr(chunk) => self.vte.process(&chunk),
+                PtyEvent::Closed => self.pty_closed = true,
+                PtyEvent::Resumed => {}
+            }
+            let responses = self.vte.take_responses();
+            self.feed_input(&responses);
+            // Nothing here can show a notification or hold a clipboard.
+            self.vte.take_clipboard_writes();
+            self.vte.take_notifications();
+            if Instant::now() >= deadline {
+                break;
+            }
+        }
+        if self.pty_closed {
+            // The shell closes the terminal a moment before it can be reaped.
+            let grace = Instant::now() + HEADLESS_QUIET;
+            let mut reaped = reap_shell(Some(self.child));
+            while reaped.is_none() && Instant::now() < grace {
+                thread::sleep(Duration::from_millis(10));
+                reaped = reap_shell(Some(self.child));
+            }
+            if let Some(message) = reaped {
+                self.pty_closed = false;
+                self.exited = true;
+                self.vte.process(format!("\r\n{}\r\n", message).as_bytes());
+            }
+        }
+        self.vte.copy_text(false)
+    }
+
+    fn resize(&mut self, cols: usize, rows: usize) {
+        let (cols, rows) = (cols.max(1), rows.max(1));
+        self.vte.resize(cols, rows);
+        let winsize = Winsize { ws_row: rows as u16, ws_col: cols as u16, ws_xpixel: 0, ws_ypixel: 0 };
+        if unsafe { libc::ioctl(self.master.as_raw_fd(), libc::TIOCSWINSZ, &winsize) } == -1 {
+            log::warn!("Failed to resize PTY: {}", io::Error::last_os_error());
+        }
+    }
+}
+
+/// `--headless`: runs each stdin line in the shell and prints the screen once the
+/// output settles, followed by a form feed. A line `:resize COLS ROWS` resizes
+/// the terminal instead. Stops once the shell exits.
+fn run_headless(config: &Config) -> io::Result<()> {
+    let (cols, rows) = initial_grid_size(config, |name| env::var(name).ok());
+    let mut session = Session::start(config, cols, rows);
+    let mut stdout = io::stdout().lock();
+    writeln!(stdout, "{}\x0c", session.poll_output())?;
+    for line in io::stdin().lines() {
+        let line = line?;
+        let size = line.strip_prefix(":resize ").map(|size| size.split_whitespace().filter_map(|n| n.parse().ok()).collect::<Vec<usize>>());
+        match size.as_deref() {
+            Some(&[cols, rows]) => session.resize(cols, rows),
+            Some(_) => eprintln!("Usage: :resize COLS ROWS"),
+            None => session.feed_input(format!("{}\n", line).as_bytes()),
+        }
+        writeln!(stdout, "{}\x0c", session.poll_output())?;
+        if session.exited {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Terminal contents shared with the read-only mirror viewport.
+#[derive(Default)]
+struct MirrorState {
+    screen: String,
+    closed: bool,
+}
+
+struct PhantomTTYApp {
+    /// One shell session per tab; the first is the one `detach_on_close` and
+    /// `restore_session` keep.
+    tabs: Vec<PhantomTTY>,
+    /// Index into `tabs` of the tab showing.
+    active: usize,
+    /// Kept for opening new tabs.
+    config: Config,
+    shell_path: String,
+    waker: Waker,
+    snippets: Vec<Snippet>,
+    show_snippets: bool,
+    /// Set until the window has been sized to fit the initial grid.
+    fit_window: bool,
+    /// Output area size the grid was last fitted to. Whenever the area changes size
+    /// the grid and PTY are resized to fill it. `None` until the first frame when the
+    /// window is being sized to a configured grid, so that grid isn't refitted to
+    /// the window's initial size.
+    fitted_output_size: Option<egui::Vec2>,
+    /// Text in the Ctrl-Shift-P quick command bar, while it is open.
+    omnibar: Option<String>,
+    control: Option<ControlSocket>,
+}
+
+impl PhantomTTYApp {
+    fn new(cc: &eframe::CreationContext<'_>, config: Config) -> Self {
+        if config.screen_reader {
+            cc.egui_ctx.enable_accesskit();
+        }
+        let shell_path = config.shell.clone().unwrap_or_else(get_default_shell);
+        let sized = ["COLUMNS", "LINES"].iter().any(|name| env_dimension(name).is_some())
+            || config.columns.is_some()
+            || config.lines.is_some();
+        // A maximized or fullscreen window keeps its size; the grid follows it instead.
+        let fixed_window = config.start_maximized || config.start_fullscreen;
+        let fit_window = sized && !fixed_window;
+        if let Some(path) = &config.font {
+            load_font(&cc.egui_ctx, path);
+        }
+        let waker = repaint_waker(&cc.egui_ctx);
+        let control = config.control_socket.then(|| serve_control_socket(control_socket_path(), waker.clone()))
+            .and_then(|result| result.map_err(|e| log::warn!("Failed to open the control socket: {}", e)).ok());
+        Self {
+            tabs: vec![PhantomTTY::new(shell_path.clone(), Vec::new(), &config, waker.clone(), true)],
+            active: 0,
+            snippets: config.snippets.clone(),
+            show_snippets: config.show_snippets,
+            config,
+            shell_path,
+            waker,
+            fit_window,
+            fitted_output_size: (!fit_window).then_some(egui::Vec2::ZERO),
+            omnibar: None,
+            control,
+        }
+    }
+}
+
+impl eframe::App for PhantomTTYApp {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        if std::mem::take(&mut self.fit_window) {
+            self.fit_window_to_grid(ctx);
+        }
+        // Background tabs keep reading so their shells never block on a full PTY.
+        let mut clipboard_writes = Vec::new();
+        for tab in &mut self.tabs {
+            tab.read_pty_output();
+            tab.poll_shell_exit();
+            tab.update_input_masking();
+            tab.poll_run_capture();
+            if let Some(wait) = tab.poll_autosave() {
+                ctx.request_repaint_after(wait);
+            }
+            clipboard_writes.extend(tab.vte_terminal.take_clipboard_writes());
+            for notification in tab.vte_terminal.take_notifications() {
+                if tab.notifications {
+                    notification.show();
+                }
+            }
+        }
+        for text in clipboard_writes {
+            self.copy_text(ctx, text);
+        }
+        let requests: Vec<ControlRequest> = self.control.iter().flat_map(|control| control.requests.try_iter()).collect();
+        for request in requests {
+            match request {
+                ControlRequest::Run(command, reply) => {
+                    if let Err(e) = self.tabs[self.active].run_capture(&command, reply) {
+                        log::error!("Error running control request: {}", e);
+                    }
+                }
+            }
+        }
+        if let Some((cols, rows)) = self.tabs[self.active].vte_terminal.resize_request.take() {
+            self.tabs[self.active].resize(cols, rows);
+            self.fit_window_to_grid(ctx);
+        }
+        if self.tabs[self.active].vte_terminal.take_title().is_some() {
+            self.show_title(ctx);
+        }
+        for text in std::mem::take(&mut self.tabs[self.active].copy_requests) {
+            self.copy_text(ctx, text);
+        }
+
+        self.handle_zoom_keys(ctx);
+        if ctx.input_mut(|i| i.consume_key(egui::Modifiers::NONE, egui::Key::F11)) {
+            let fullscreen = ctx.input(|i| i.viewport().fullscreen.unwrap_or(false));
+            ctx.send_viewport_cmd(egui::ViewportCommand::Fullscreen(!fullscreen));
+        }
+        let dropped: Vec<PathBuf> = ctx.input(|i| i.raw.dropped_files.iter().filter_map(|f| f.path.clone()).collect());
+        if !dropped.is_empty() {
+            self.tabs[self.active].insert_dropped_paths(&dropped);
+        }
+        if ctx.input_mut(|i| i.consume_key(egui::Modifiers::COMMAND | egui::Modifiers::SHIFT, egui::Key::S)) {
+            self.show_snippets = !self.show_snippets;
+        }
+        if ctx.input_mut(|i| i.consume_key(egui::Modifiers::COMMAND | egui::Modifiers::SHIFT, egui::Key::P)) {
+            self.omnibar = Some(String::new());
+        }
+        if ctx.input_mut(|i| i.consume_key(egui::Modifiers::COMMAND | egui::Modifiers::SHIFT, egui::Key::T)) {
+            self.open_tab(ctx, self.shell_path.clone());
+        }
+        if ctx.input_mut(|i| i.consume_key(egui::Modifiers::COMMAND | egui::Modifiers::SHIFT, egui::Key::W)) {
+            self.close_tab(ctx);
+        }
+        self.show_tab_bar(ctx);
+        self.show_snippets_panel(ctx);
+
+        egui::CentralPanel::default().show(ctx, |ui| {
+            let tab = &mut self.tabs[self.active];
+            if let Some(command) = tab.terminal.show(ui, ctx, &tab.vte_terminal) {
+                if let Err(e) = tab.execute_command(&command) {
+                    tab.terminal.set_output(&format!("Error: {}", e));
+                }
+            }
+        });
+        let output_size = self.tabs[self.active].terminal.output_rect.size();
+        match self.fitted_output_size {
+            Some(fitted) if fitted != output_size => {
+                self.fitted_output_size = Some(output_size);
+                if let Some((cols, rows)) = self.tabs[self.active].terminal.grid_size(ctx) {
+                    self.tabs[self.active].resize(cols, rows);
+                }
+            }
+            Some(_) => {}
+            None => self.fitted_output_size = Some(output_size),
+        }
+        if let Some(row) = self.tabs[self.active].terminal.badge_clicked.take().filter(|&row| row < self.tabs[self.active].vte_terminal.height) {
+            let text = self.tabs[self.active].vte_terminal.row_text(row);
+            self.copy_text(ctx, text);
+        }
+        if let Some(row) = self.tabs[self.active].terminal.fold_clicked.take() {
+            let tab = &mut self.tabs[self.active];
+            tab.vte_terminal.toggle_fold(row);
+            tab.terminal.set_screen(&tab.vte_terminal.screen_text(), &tab.vte_terminal);
+        }
+        if let Some(text) = self.tabs[self.active].terminal.copy_request.take() {
+            self.copy_text(ctx, text);
+        }
+        if let Some((row, col)) = self.tabs[self.active].terminal.path_clicked.take() {
+            if let Err(e) = self.tabs[self.active].open_path_at(row, col) {
+                self.tabs[self.active].terminal.set_output(&format!("Error: {}", e));
+            }
+        }
+        if std::mem::take(&mut self.tabs[self.active].terminal.scroll_clear_requested) {
+            self.tabs[self.active].scroll_clear();
+        }
+        if std::mem::take(&mut self.tabs[self.active].terminal.clear_requested) {
+            if let Err(e) = self.tabs[self.active].clear() {
+                self.tabs[self.active].terminal.set_output(&format!("Error: {}", e));
+            }
+        }
+        let input = self.tabs[self.active].terminal.take_pending_input();
+        if let Err(e) = self.tabs[self.active].send_bytes(&input) {
+            self.tabs[self.active].terminal.set_output(&format!("Error: {}", e));
+        }
+        if let Some(size) = self.tabs[self.active].font_request.take() {
+            self.apply_font_size(ctx, size);
+        }
+        if let Some(theme) = self.tabs[self.active].theme_request.take() {
+            self.apply_theme(ctx, theme);
+        }
+        if let Some(shell_path) = self.tabs[self.active].spawn_request.take() {
+            self.open_tab(ctx, shell_path);
+        }
+        if std::mem::take(&mut self.tabs[self.active].close_request) {
+            self.close_tab(ctx);
+        }
+
+        self.show_mirror(ctx);
+        self.show_clipboard_picker(ctx);
+        self.show_binary_prompt(ctx);
+        self.show_exit_prompt(ctx);
+        self.show_command_confirmation(ctx);
+        self.show_omnibar(ctx);
+
+        let focused = ctx.input(|i| i.focused);
+        if let Some(interval) = self.tabs[self.active].terminal.repaint_interval(focused) {
+            ctx.request_repaint_after(interval);
+        }
+    }
+}
+
+impl PhantomTTYApp {
+    /// Opens a tab running `shell_path` and switches to it. It starts with the
+    /// current font and theme rather than the configured ones.
+    fn open_tab(&mut self, ctx: &egui::Context, shell_path: String) {
+        let last_used: Vec<Instant> = self.tabs.iter().map(|tab| tab.last_used).collect();
+        match self.config.max_tabs_policy.opening(self.config.max_tabs, &last_used, self.active) {
+            TabOpening::Open => {}
+            TabOpening::Replace(index) => self.remove_tab(index),
+            TabOpening::Refuse => {
+                let message = format!("Not opening a tab: the max_tabs limit of {} is reached", self.tabs.len());
+                self.tabs[self.active].terminal.set_output(&message);
+                return;
+            }
+        }
+        let mut tab = PhantomTTY::new(shell_path, Vec::new(), &self.config, self.waker.clone(), false);
+        let current = &self.tabs[self.active];
+        tab.terminal.font_size = current.terminal.font_size;
+        tab.terminal.high_contrast = current.terminal.high_contrast;
+        tab.theme_request = Some(current.theme.clone());
+        self.tabs.push(tab);
+        self.switch_tab(ctx, self.tabs.len() - 1);
+    }
+
+    /// Closes the showing tab, ending its shell, or the window with the last tab.
+    /// When the first tab goes, the next one becomes the session kept on exit.
+    fn close_tab(&mut self, ctx: &egui::Context) {
+        if self.tabs.len() == 1 {
+            ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+            return;
+        }
+        self.remove_tab(self.active);
+        self.switch_tab(ctx, self.active.min(self.tabs.len() - 1));
+    }
+
+    /// Drops tab `index`, ending its shell, and keeps `active` on the same tab when
+    /// another one goes. When the first tab goes, the next one becomes the session
+    /// kept on exit.
+    fn remove_tab(&mut self, index: usize) {
+        let mut closed = self.tabs.remove(index);
+        if index == 0 {
+            self.tabs[0].detach_on_close = std::mem::take(&mut closed.detach_on_close);
+            self.tabs[0].restore_session = std::mem::take(&mut closed.restore_session);
+        }
+        if index < self.active {
+            self.active -= 1;
+        }
+    }
+
+    fn switch_tab(&mut self, ctx: &egui::Context, index: usize) {
+        if let Some(tab) = self.tabs.get_mut(self.active) {
+            tab.last_used = Instant::now();
+        }
+        self.active = index;
+        self.tabs[index].last_used = Instant::now();
+        // Background tabs keep their old size; refit this one to the output area.
+        if self.fitted_output_size.is_some() {
+            self.fitted_output_size = Some(egui::Vec2::ZERO);
+        }
+        self.show_title(ctx);
+    }
+
+    /// Puts the showing tab's title on the window.
+    fn show_title(&self, ctx: &egui::Context) {
+        let title = &self.tabs[self.active].vte_terminal.title;
+        let title = if title.is_empty() { DEFAULT_TITLE.to_string() } else { title.clone() };
+        ctx.send_viewport_cmd(egui::ViewportCommand::Title(title));
+    }
+
+    /// A row of tabs above the output, shown once there is more than one.
+    fn show_tab_bar(&mut self, ctx: &egui::Context) {
+        if self.tabs.len() < 2 {
+            return;
+        }
+        let mut picked = None;
+        egui::TopBottomPanel::top("tabs").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                for (index, tab) in self.tabs.iter().enumerate() {
+                    let title = &tab.vte_terminal.title;
+                    let label = if title.is_empty() { format!("Tab {}", index + 1) } else { title.chars().take(30).collect() };
+                    if ui.selectable_label(index == self.active, label).clicked() {
+                        picked = Some(index);
+                    }
+                }
+                if ui.button("+").on_hover_text("New tab (Ctrl+Shift+T)").clicked() {
+                    picked = Some(self.tabs.len());
+                }
+            });
+        });
+        match picked {
+            Some(index) if index == self.tabs.len() => self.open_tab(ctx, self.shell_path.clone()),
+            Some(index) if index != self.active => self.switch_tab(ctx, index),
+            _ => {}
+        }
+    }
+
+    /// Every copy goes through here so it lands in the clipboard history too.
+    fn copy_text(&mut self, ctx: &egui::Context, text: String) {
+        self.tabs[self.active].clipboard.push(&text);
+        ctx.copy_text(text);
+    }
+
+    fn show_snippets_panel(&mut self, ctx: &egui::Context) {
+        if !self.show_snippets {
+            return;
+        }
+        let mut run = None;
+        egui::SidePanel::right("snippets").resizable(true).show(ctx, |ui| {
+            ui.heading("Snippets");
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                for snippet in &self.snippets {
+                    if ui.button(&snippet.name).on_hover_text(&snippet.command).clicked() {
+                        run = Some(snippet.clone());
+                    }
+                }
+            });
+            ui.separator();
+            let input = self.tabs[self.active].terminal.input.trim();
+            if ui.add_enabled(!input.is_empty(), egui::Button::new("Add current input")).clicked() {
+                let snippet = Snippet { name: input.to_string(), command: input.to_string() };
+                if let Err(e) = save_snippet(&get_config_file_path(), &snippet) {
+                    log::error!("Error saving snippet: {}", e);
+                }
+                self.snippets.push(snippet);
+            }
+        });
+        if let Some(snippet) = run {
+            if let Err(e) = self.tabs[self.active].run_snippet(&snippet) {
+                self.tabs[self.active].terminal.set_output(&format!("Error: {}", e));
+            }
+        }
+    }
+
+    /// Asks before running a command that matched `confirm_commands`.
+    fn show_command_confirmation(&mut self, ctx: &egui::Context) {
+        let Some((command, pattern)) = self.tabs[self.active].pending_confirmation.clone() else { return };
+        let mut answer = None;
+        egui::Window::new("Confirm command")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+            .show(ctx, |ui| {
+                ui.label("This command matches a dangerous pattern:");
+                ui.label(egui::RichText::new(&command).monospace().strong());
+                ui.label(egui::RichText::new(format!("pattern: {}", pattern)).monospace().weak());
+                ui.horizontal(|ui| {
+                    if ui.button("Run it").clicked() {
+                        answer = Some(true);
+                    }
+                    if ui.button("Cancel").clicked() || ui.input(|i| i.key_pressed(egui::Key::Escape)) {
+                        answer = Some(false);
+                    }
+                });
+            });
+        if let Some(run) = answer {
+            self.tabs[self.active].pending_confirmation = None;
+            if run {
+                if let Err(e) = self.tabs[self.active].run_command(&command) {
+                    self.tabs[self.active].terminal.set_output(&format!("Error: {}", e));
+                }
+            } else {
+                self.tabs[self.active].terminal.input = command;
+            }
+        }
+    }
+
+    fn show_binary_prompt(&mut self, ctx: &egui::Context) {
+        if !self.tabs[self.active].binary_prompt {
+            return;
+        }
+        let mut answer = None;
+        egui::Window::new("Binary output")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+            .show(ctx, |ui| {
+                ui.label("This looks like binary output. Stop and reset the terminal?");
+                ui.horizontal(|ui| {
+                    if ui.button("Stop and reset").clicked() {
+                        answer = Some(true);
+                    }
+                    if ui.button("Keep going").clicked() {
+                        answer = Some(false);
+                    }
+                });
+            });
+        if let Some(stop) = answer {
+            self.tabs[self.active].binary_prompt = false;
+            if stop {
+                self.tabs[self.active].stop_binary_output();
+            }
+        }
+    }
+
+    /// Offers to start a new shell in the tab whose shell exited, or to close it.
+    fn show_exit_prompt(&mut self, ctx: &egui::Context) {
+        if !self.tabs[self.active].shell_exited {
+            return;
+        }
+        let mut answer = None;
+        egui::Window::new("Shell exited")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+            .show(ctx, |ui| {
+                ui.label("The shell in this tab has exited.");
+                ui.horizontal(|ui| {
+                    if ui.button("Restart").clicked() {
+                        answer = Some(true);
+                    }
+                    if ui.button("Close tab").clicked() {
+                        answer = Some(false);
+                    }
+                });
+            });
+        match answer {
+            Some(true) => {
+                let tab = &mut self.tabs[self.active];
+                tab.shell_exited = false;
+                tab.start_shell();
+            }
+            Some(false) => self.close_tab(ctx),
+            None => {}
+        }
+    }
+
+    fn show_clipboard_picker(&mut self, ctx: &egui::Context) {
+        if !self.tabs[self.active].clipboard_picker_open {
+            return;
+        }
+        let mut open = true;
+        let mut picked = None;
+        egui::Window::new("Clipboard history")
+            .open(&mut open)
+            .collapsible(false)
+            .show(ctx, |ui| {
+                if self.tabs[self.active].clipboard.entries.is_empty() {
+                    ui.label("Nothing copied yet");
+                }
+                egui::ScrollArea::vertical().max_height(300.0).show(ui, |ui| {
+                    for (index, entry) in self.tabs[self.active].clipboard.entries.iter().enumerate() {
+                        let preview: String = entry.lines().next().unwrap_or("").chars().take(60).collect();
+                        if ui.button(egui::RichText::new(preview).monospace()).clicked() {
+                            picked = Some(index);
+                        }
+                    }
+                });
+            });
+        if let Some(index) = picked {
+            if let Err(e) = self.tabs[self.active].paste_clipboard_entry(index) {
+                self.tabs[self.active].terminal.set_output(&format!("Error: {}", e));
+            }
+            open = false;
+        }
+        self.tabs[self.active].clipboard_picker_open = open;
+    }
+
+    /// Quick command bar: Enter submits the text as if typed on the input line, Tab
+    /// takes the first suggestion and Escape closes it. Suggestions are history
+    /// entries containing what has been typed.
+    fn show_omnibar(&mut self, ctx: &egui::Context) {
+        let Some(mut text) = self.omnibar.take() else { return };
+        let needle = text.to_lowercase();
+        let mut suggestions: Vec<String> = Vec::new();
+        for entry in &self.tabs[self.active].terminal.history {
+            if suggestions.len() == 8 {
+                break;
+            }
+            if entry.to_lowercase().contains(&needle) && !suggestions.contains(entry) {
+                suggestions.push(entry.clone());
+            }
+        }
+        let mut run = None;
+        let mut close = ctx.input_mut(|i| i.consume_key(egui::Modifiers::NONE, egui::Key::Escape));
+        egui::Window::new("Quick command")
+            .title_bar(false)
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_TOP, [0.0, 60.0])
+            .show(ctx, |ui| {
+                if ui.input_mut(|i| i.consume_key(egui::Modifiers::NONE, egui::Key::Tab)) {
+                    if let Some(first) = suggestions.first() {
+                        text = first.clone();
+                    }
+                }
+                let response = ui.add(
+                    egui::TextEdit::singleline(&mut text)
+                        .hint_text("Command to run")
+                        .desired_width(400.0)
+                        .font(egui::TextStyle::Monospace),
+                );
+                // Enter gives up focus, so check for it before taking focus back.
+                if response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+                    run = Some(text.trim().to_string()).filter(|command| !command.is_empty());
+                    close = true;
+                } else {
+                    response.request_focus();
+                }
+                for suggestion in &suggestions {
+                    if ui.selectable_label(false, egui::RichText::new(suggestion).monospace()).clicked() {
+                        run = Some(suggestion.clone());
+                        close = true;
+                    }
+                }
+            });
+        if let Some(command) = run {
+            if let Err(e) = self.tabs[self.active].execute_command(&command) {
+                self.tabs[self.active].terminal.set_output(&format!("Error: {}", e));
+            }
+        }
+        if !close {
+            self.omnibar = Some(text);
+        }
+    }
+
+    /// Sizes the window to hold the current grid plus the input line.
+    fn fit_window_to_grid(&self, ctx: &egui::Context) {
+        let font_id = self.tabs[self.active].terminal.font_id();
+        let glyph = ctx.fonts(|f| egui::vec2(f.glyph_width(&font_id, 'M'), f.row_height(&font_id)));
+        let vte = &self.tabs[self.active].vte_terminal;
+        let margin = ctx.style().spacing.window_margin.sum() + egui::vec2(16.0, 46.0);
+        let size = egui::vec2(vte.width as f32 * glyph.x, vte.height as f32 * glyph.y) + margin;
+        ctx.send_viewport_cmd(egui::ViewportCommand::InnerSize(size));
+    }
+
+    /// Changes the font size in every tab and resizes each grid and PTY to what now
+    /// fits, keeping the two in sync. All zoom paths go through here.
+    fn apply_font_size(&mut self, ctx: &egui::Context, size: f32) {
+        for tab in &mut self.tabs {
+            tab.set_font_size(ctx, size);
+        }
+        ctx.request_repaint();
+    }
+
+    /// Shows `theme`, or the high-contrast colors while that mode is on. Either way
+    /// `theme` stays the one exported and restored.
+    fn apply_theme(&mut self, ctx: &egui::Context, theme: Theme) {
+        let shown = if self.tabs[self.active].terminal.high_contrast { Theme::high_contrast() } else { theme.clone() };
+        ctx.style_mut(|style| {
+            let visuals = &mut style.visuals;
+            visuals.override_text_color = Some(Theme::color(&shown.foreground));
+            visuals.panel_fill = Theme::color(&shown.background);
+            visuals.extreme_bg_color = Theme::color(&shown.background);
+            visuals.text_cursor.stroke.color = Theme::color(&shown.cursor);
+        });
+        let high_contrast = self.tabs[self.active].terminal.high_contrast;
+        for tab in &mut self.tabs {
+            tab.terminal.high_contrast = high_contrast;
+            tab.terminal.palette = shown.palette.iter().map(|c| Theme::color(c)).collect();
+            tab.theme = theme.clone();
+        }
+    }
+
+    fn handle_zoom_keys(&mut self, ctx: &egui::Context) {
+        let size = self.tabs[self.active].terminal.font_size;
+        let new_size = ctx.input_mut(|i| {
+            if i.consume_key(egui::Modifiers::COMMAND, egui::Key::Equals)
+                || i.consume_key(egui::Modifiers::COMMAND, egui::Key::Plus)
+            {
+                Some(size + 1.0)
+            } else if i.consume_key(egui::Modifiers::COMMAND, egui::Key::Minus) {
+                Some(size - 1.0)
+            } else if i.consume_key(egui::Modifiers::COMMAND, egui::Key::Num0) {
+                Some(DEFAULT_FONT_SIZE)
+            } else {
+                None
+            }
+        });
+        if let Some(size) = new_size {
+            self.apply_font_size(ctx, size);
+        }
+    }
+
+    /// Shows the live, read-only mirror window toggled by `phantom:mirror`.
+    fn show_mirror(&mut self, ctx: &egui::Context) {
+        let Some(shared) = self.tabs[self.active].sync_mirror() else { return };
+
+        ctx.show_viewport_deferred(
+            egui::ViewportId::from_hash_of("phantomtty-mirror"),
+            egui::ViewportBuilder::default()
+                .with_title("PhantomTTY (mirror)")
+                .with_inner_size([800.0, 600.0]),
+            move |ctx, _class| {
+                let mut state = shared.lock().unwrap();
+                if ctx.input(|i| i.viewport().close_requested()) {
+                    state.closed = true;
+                }
+                egui::CentralPanel::default().show(ctx, |ui| {
+                    egui::ScrollArea::both().auto_shrink([false; 2]).show(ui, |ui| {
+                        ui.add(
+                            egui::Label::new(egui::RichText::new(&state.screen).font(egui::FontId::monospace(DEFAULT_FONT_SIZE)))
+                                .wrap_mode(egui::TextWrapMode::Extend)
+                        );
+                    });
+                });
+                ctx.request_repaint();
+            },
+        );
+    }
+}
+
+/// Display switches for `output_layout_job` that don't change the cells.
+#[derive(Clone, Copy, Default)]
+struct RenderOptions<'a> {
+    show_whitespace: bool,
+    /// Columns per row in the cell styles handed to `output_layout_job`.
+    grid_width: usize,
+    /// Row to shade with the faint background color.
+    highlight_row: Option<usize>,
+    /// Rows of folded commands, left out.
+    hidden_rows: &'a [bool],
+}
+
+/// How cell styles become text formats in one frame.
+struct CellColors<'a> {
+    /// The theme's 16 ANSI colors; the rest of the 256 are xterm's.
+    palette: &'a [egui::Color32],
+    /// Stands in for the default background when reverse video moves it to the glyph.
+    background: egui::Color32,
+    /// Color of whitespace placeholders.
+    dim: egui::Color32,
+    /// High-contrast mode: faint text keeps its full color. (Blink is never animated.)
+    high_contrast: bool,
+    /// Grapheme cluster tails that `Cell::cluster` refers to.
+    clusters: &'a [String],
+}
+
+impl CellColors<'_> {
+    /// `base` restyled for `cell`. Bold brightens the eight basic colors, as
+    /// there is no bold face to switch to.
+    fn format(&self, base: &egui::TextFormat, cell: Cell) -> egui::TextFormat {
+        let bold = cell.attrs & Cell::BOLD != 0;
+        let lookup = |color: Color, bright: bool| match color {
+            Color::Default => None,
+            Color::Indexed(i) => {
+                let i = if bright && i < 8 { i + 8 } else { i };
+                Some(self.palette.get(usize::from(i)).copied().unwrap_or_else(|| xterm_256_color(i)))
+            }
+            Color::Rgb(r, g, b) => Some(egui::Color32::from_rgb(r, g, b)),
+        };
+        let mut fg = lookup(cell.fg, bold).unwrap_or(base.color);
+        let mut bg = lookup(cell.bg, false).unwrap_or(base.background);
+        if cell.attrs & Cell::STDERR != 0 {
+            fg = fg.lerp_to_gamma(self.palette.get(9).copied().unwrap_or(egui::Color32::RED), 0.6);
+        }
+        if cell.attrs & Cell::FAINT != 0 && !self.high_contrast {
+            let glyph = if bg == egui::Color32::TRANSPARENT { self.background } else { bg };
+            fg = fg.lerp_to_gamma(glyph, 0.4);
+        }
+        if cell.attrs & Cell::REVERSE != 0 {
+            let glyph = if bg == egui::Color32::TRANSPARENT { self.background } else { bg };
+            (fg, bg) = (glyph, fg);
+        }
+        egui::TextFormat {
+            color: fg,
+            background: bg,
+            italics: cell.attrs & Cell::ITALIC != 0,
+            underline: if cell.attrs & Cell::UNDERLINE != 0 { egui::Stroke::new(1.0, fg) } else { egui::Stroke::NONE },
+            ..base.clone()
+        }
+    }
+}
+
+/// Lays out terminal output row by row, scaling rows marked double-width or
+/// double-height. A double-height pair is drawn once, at twice the font size, from
+/// its top half.
+fn output_layout_job(
+    ui: &egui::Ui,
+    text: &str,
+    font_id: &egui::FontId,
+    line_attrs: &[LineAttr],
+    cells: &[Cell],
+    colors: &CellColors,
+    options: RenderOptions<'_>,
+) -> egui::text::LayoutJob {
+    let color = ui.visuals().override_text_color.unwrap_or_else(|| ui.visuals().widgets.inactive.text_color());
+    let glyph_width = ui.fonts(|f| f.glyph_width(font_id, 'M'));
+    let mut job = egui::text::LayoutJob::default();
+    let mut previous = LineAttr::Single;
+    for (row, line) in text.split_inclusive('\n').enumerate() {
+        if options.hidden_rows.get(row) == Some(&true) {
+            continue;
+        }
+        let attr = line_attrs.get(row).copied().unwrap_or_default();
+        let mut format = egui::TextFormat::simple(font_id.clone(), color);
+        if options.highlight_row == Some(row) {
+            format.background = ui.visuals().faint_bg_color;
+        }
+        let line = match attr {
+            LineAttr::Single => line.to_string(),
+            LineAttr::DoubleHeightBottom if previous == LineAttr::DoubleHeightTop => {
+                previous = attr;
+                continue;
+            }
+            LineAttr::DoubleWidth => {
+                format.extra_letter_spacing = glyph_width;
+                format!("{}\n", line.trim_end())
+            }
+            LineAttr::DoubleHeightTop | LineAttr::DoubleHeightBottom => {
+                format.font_id.size *= 2.0;
+                format!("{}\n", line.trim_end())
+            }
+        };
+        let row_cells = options.grid_width
+            .checked_mul(row)
+            .and_then(|start| cells.get(start..start + options.grid_width))
+            .unwrap_or(&[]);
+        append_row(&mut job, &line, &format, row_cells, colors, options.show_whitespace);
+        previous = attr;
+    }
+    job
+}
+
+/// Re-wraps rows to `width` columns. Rows flagged as wrapped are joined with the
+/// next one to recover logical lines, which are then split again; every row but
+/// the last of a line comes back flagged as wrapped.
+fn reflow_rows(rows: &[(String, bool)], width: usize) -> Vec<(String, bool)> {
+    let width = width.max(1);
+    let mut reflowed = Vec::new();
+    let mut line: Vec<char> = Vec::new();
+    for (i, (text, wrapped)) in rows.iter().enumerate() {
+        line.extend(text.chars());
+        if *wrapped && i + 1 < rows.len() {
+            continue;
+        }
+        let mut chunks = line.chunks(width).peekable();
+        if chunks.peek().is_none() {
+            reflowed.push((String::new(), false));
+        }
+        while let Some(chunk) = chunks.next() {
+            reflowed.push((chunk.iter().collect(), chunks.peek().is_some()));
+        }
+        line.clear();
+    }
+    reflowed
+}
+
+/// Appends one row, each run of characters styled by its cells. With
+/// `show_whitespace`, tabs are drawn as `→`, trailing blanks as `·` and non-printing
+/// characters as control pictures, dimmed. There is one placeholder per cell, so
+/// columns stay aligned, and the cells themselves are left untouched.
+fn append_row(
+    job: &mut egui::text::LayoutJob,
+    line: &str,
+    base: &egui::TextFormat,
+    cells: &[Cell],
+    colors: &CellColors,
+    show_whitespace: bool,
+) {
+    let content = line.trim_end_matches('\n');
+    let trailing_start = content.trim_end_matches(' ').len();
+    let mut run = String::new();
+    let mut run_format: Option<egui::TextFormat> = None;
+    for (col, (i, c)) in content.char_indices().enumerate() {
+        if c == WIDE_SPACER {
+            continue;
+        }
+        let placeholder = match c {
+            _ if !show_whitespace => None,
+            '\t' => Some('→'),
+            ' ' if i >= trailing_start && trailing_start > 0 => Some('·'),
+            '\u{7f}' => Some('␡'),
+            c if (c as u32) < 0x20 => char::from_u32(0x2400 + c as u32),
+            c if c.is_control() || matches!(c, '\u{200b}'..='\u{200f}' | '\u{2060}' | '\u{feff}') => Some('¤'),
+            _ => None,
+        };
+        let cell = cells.get(col).copied().unwrap_or_default();
+        let mut format = colors.format(base, cell);
+        if placeholder.is_some() {
+            format.color = colors.dim;
+        }
+        if run_format.as_ref() != Some(&format) {
+            if let Some(previous) = run_format.replace(format) {
+                job.append(&run, 0.0, previous);
+                run.clear();
+            }
+        }
+        run.push(placeholder.unwrap_or(if c == '\t' { ' ' } else { c }));
+        if let Some(tail) = usize::from(cell.cluster).checked_sub(1).and_then(|i| colors.clusters.get(i)) {
+            run.push_str(tail);
+        }
+    }
+    if let Some(format) = run_format {
+        job.append(&run, 0.0, format);
+    }
+    job.append(&line[content.len()..], 0.0, base.clone());
+}
+
+/// The text to insert for a dropped path: relative to `cwd` when asked for and the
+/// path lies strictly inside it, otherwise absolute.
+fn dropped_path_text(path: &Path, cwd: Option<&Path>, relativize: bool) -> String {
+    if relativize {
+        if let Some(relative) = cwd.and_then(|cwd| path.strip_prefix(cwd).ok()) {
+            if !relative.as_os_str().is_empty() {
+                return relative.display().to_string();
+            }
+        }
+    }
+    path.display().to_string()
+}
+
+/// A `[[phantom_command]]` template with `{args}` replaced by the arguments, each
+/// quoted for the shell.
+fn expand_custom_command(template: &str, args: &str) -> String {
+    let quoted: Vec<String> = args.split_whitespace().map(shell_quote).collect();
+    template.replace("{args}", &quoted.join(" "))
+}
+
+fn shell_quote(text: &str) -> String {
+    if !text.is_empty() && text.chars().all(|c| c.is_ascii_alphanumeric() || "/._-+,:@%".contains(c)) {
+        text.to_string()
+    } else {
+        format!("'{}'", text.replace('\'', "'\\''"))
+    }
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok());
+        match (bytes[i], hex.and_then(|h| u8::from_str_radix(h, 16).ok())) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// A mouse report for button `code` (0-2 buttons, +32 motion, 64/65 wheel) at the
+/// 0-based (row, column) `cell`: `CSI < b ; col ; row M` (`m` on release) in SGR
+/// encoding, else X10's `CSI M` and three bytes, which can't go past column 223.
+fn mouse_report(code: u8, cell: (usize, usize), pressed: bool, modifiers: egui::Modifiers, sgr: bool) -> Vec<u8> {
+    let mut button = code;
+    if modifiers.shift {
+        button += 4;
+    }
+    if modifiers.alt {
+        button += 8;
+    }
+    if modifiers.ctrl {
+        button += 16;
+    }
+    let (row, col) = (cell.0 + 1, cell.1 + 1);
+    if sgr {
+        return format!("\x1b[<{};{};{}{}", button, col, row, if pressed { 'M' } else { 'm' }).into_bytes();
+    }
+    if !pressed {
+        // X10 can't say which button was released.
+        button = (button & !3) | 3;
+    }
+    match (u8::try_from(col + 32), u8::try_from(row + 32)) {
+        (Ok(x), Ok(y)) => vec![0x1b, b'[', b'M', button + 32, x, y],
+        _ => Vec::new(),
+    }
+}
+
+fn home_end_key(home: bool, app_cursor_keys: bool) -> &'static [u8] {
+    match (home, app_cursor_keys) {
+        (true, false) => b"\x1b[H",
+        (false, false) => b"\x1b[F",
+        (true, true) => b"\x1bOH",
+        (false, true) => b"\x1bOF",
+    }
+}
+
+fn arrow_key(up: bool, app_cursor_keys: bool) -> &'static [u8] {
+    match (up, app_cursor_keys) {
+        (true, false) => b"\x1b[A",
+        (false, false) => b"\x1b[B",
+        (true, true) => b"\x1bOA",
+        (false, true) => b"\x1bOB",
+    }
+}
+
+/// Removes bracketed-paste markers from text headed to the PTY, so pasted
+/// content can never close (or open) a paste bracket on its own.
+fn strip_paste_markers(text: &str) -> String {
+    text.replace(PASTE_START, "").replace(PASTE_END, "")
+}
+
+fn get_clipboard_file_path() -> PathBuf {
+    let mut path = get_history_file_path();
+    path.set_file_name("clipboard.toml");
+    path
+}
+
+fn get_snapshot_file_path() -> PathBuf {
+    let mut path = get_history_file_path();
+    path.set_file_name("session.toml");
+    path
+}
+
+fn get_autosave_dir() -> PathBuf {
+    let mut path = get_history_file_path();
+    path.set_file_name("autosave");
+    path
+}
 
-struct TerminalWidget {
-    output: String,
-    input: String,
-    prompt: String,
-    history: VecDeque<String>,
-    history_index: Option<usize>,
-    selected_text: Option<String>,
+fn get_config_file_path() -> PathBuf {
+    let mut path = get_history_file_path();
+    path.set_file_name("config.toml");
+    path
 }
 
-impl TerminalWidget {
-    fn new() -> Self {
-        Self {
-            output: String::new(),
-            input: String::new(),
-            prompt: "$ ".to_string(),
-            history: VecDeque::with_capacity(HISTORY_SIZE),
-            history_index: None,
-            selected_text: None,
+fn get_log_file_path() -> PathBuf {
+    let mut path = get_history_file_path();
+    path.set_file_name("phantomtty.log");
+    path
+}
+
+/// PhantomTTY's own diagnostics, as logged through the `log` macros. Other crates'
+/// records are kept at warnings and above so eframe and winit don't drown ours out.
+struct DiagnosticLogger {
+    sink: Mutex<Box<dyn Write + Send>>,
+    level: log::LevelFilter,
+}
+
+impl log::Log for DiagnosticLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        let ours = metadata.target().starts_with(module_path!());
+        metadata.level() <= if ours { self.level } else { log::LevelFilter::Warn }
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            if let Ok(mut sink) = self.sink.lock() {
+                let secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+                let _ = writeln!(sink, "{} {:<5} {}", secs, record.level(), record.args());
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut sink) = self.sink.lock() {
+            let _ = sink.flush();
+        }
+    }
+}
+
+/// Installs the logger. An explicit `--log-file` is always used; otherwise the default
+/// file in the config directory, rotated once it passes `LOG_ROTATE_BYTES`, is used
+/// only when stderr isn't a terminal, so running from a shell shows diagnostics
+/// inline. `PHANTOM_LOG` sets the level (`info` by default).
+fn init_logging(log_file: Option<PathBuf>) {
+    let path = match log_file {
+        Some(path) => Some(path),
+        None if nix::unistd::isatty(2).unwrap_or(false) => None,
+        None => {
+            let path = get_log_file_path();
+            if fs::metadata(&path).is_ok_and(|meta| meta.len() > LOG_ROTATE_BYTES) {
+                let _ = fs::rename(&path, path.with_extension("log.1"));
+            }
+            Some(path)
+        }
+    };
+    let mut open_error = None;
+    let sink: Box<dyn Write + Send> = match path.as_ref().map(|path| fs::OpenOptions::new().create(true).append(true).open(path)) {
+        Some(Ok(file)) => Box::new(file),
+        Some(Err(e)) => {
+            open_error = Some(e);
+            Box::new(io::stderr())
+        }
+        None => Box::new(io::stderr()),
+    };
+    let level = env::var("PHANTOM_LOG").ok().and_then(|level| level.parse().ok()).unwrap_or(log::LevelFilter::Info);
+    let logger = Box::leak(Box::new(DiagnosticLogger { sink: Mutex::new(sink), level }));
+    if log::set_logger(logger).is_ok() {
+        log::set_max_level(level.max(log::LevelFilter::Warn));
+    }
+    if let (Some(path), Some(e)) = (path, open_error) {
+        log::error!("Error opening log file {}: {}", path.display(), e);
+    }
+}
+
+fn load_config() -> Config {
+    let path = get_config_file_path();
+    match fs::read_to_string(&path) {
+        Ok(contents) => toml::from_str(&contents).unwrap_or_else(|e| {
+            log::error!("Error parsing {}: {}", path.display(), e);
+            Config::default()
+        }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            if let Err(e) = fs::write(&path, DEFAULT_CONFIG) {
+                log::error!("Error writing {}: {}", path.display(), e);
+            }
+            Config::default()
+        }
+        Err(e) => {
+            log::error!("Error reading {}: {}", path.display(), e);
+            Config::default()
+        }
+    }
+}
+
+/// Appends a snippet to the config file as a `[[snippets]]` table, leaving the
+/// rest of the file, comments included, as it was.
+fn save_snippet(path: &Path, snippet: &Snippet) -> io::Result<()> {
+    #[derive(Serialize)]
+    struct Snippets<'a> {
+        snippets: [&'a Snippet; 1],
+    }
+    let table = toml::to_string(&Snippets { snippets: [snippet] })
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+    write!(file, "\n{}", table)
+}
+
+fn get_history_file_path() -> PathBuf {
+    let mut path = if let Some(config_dir) = dirs::config_dir() {
+        config_dir
+    } else {
+        PathBuf::from(env::var("HOME").unwrap_or_else(|_| String::from("/")))
+    };
+    path.push("phantomtty");
+    fs::create_dir_all(&path).unwrap_or_else(|e| log::error!("Error creating config directory: {}", e));
+    path.push("history");
+    path
+}
+
+/// Puts the font at `path` first in the monospace family, ahead of the built-in
+/// Hack as a fallback. A file that can't be read or parsed leaves the default.
+fn load_font(ctx: &egui::Context, path: &Path) {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) => {
+            log::error!("Error reading font {}: {}", path.display(), e);
+            return;
+        }
+    };
+    if let Err(e) = ab_glyph::FontRef::try_from_slice(&data) {
+        log::error!("Error loading font {}: {}", path.display(), e);
+        return;
+    }
+    let mut fonts = egui::FontDefinitions::default();
+    fonts.font_data.insert("configured".to_string(), egui::FontData::from_owned(data));
+    fonts.families.entry(egui::FontFamily::Monospace).or_default().insert(0, "configured".to_string());
+    ctx.set_fonts(fonts);
+}
+
+fn repaint_waker(ctx: &egui::Context) -> Waker {
+    let ctx = ctx.clone();
+    Arc::new(move || ctx.request_repaint())
+}
+
+fn valid_dimension(n: usize) -> bool {
+    (1..=u16::MAX as usize).contains(&n)
+}
+
+fn parse_dimension(value: &str) -> Option<usize> {
+    value.trim().parse().ok().filter(|&n| valid_dimension(n))
+}
+
+fn env_dimension(name: &str) -> Option<usize> {
+    parse_dimension(&env::var(name).ok()?)
+}
+
+/// Grid size to start with: `COLUMNS`/`LINES` as looked up by `var`, then the
+/// config, then 80x24.
+fn initial_grid_size(config: &Config, var: impl Fn(&str) -> Option<String>) -> (usize, usize) {
+    let dimension = |name: &str, configured: Option<usize>, default: usize| {
+        var(name).and_then(|value| parse_dimension(&value))
+            .or(configured.filter(|&n| valid_dimension(n)))
+            .unwrap_or(default)
+    };
+    (dimension("COLUMNS", config.columns, 80), dimension("LINES", config.lines, 24))
+}
+
+/// `TERM` for the shell: xterm for the shells known to handle it, vt100 otherwise.
+fn term_for_shell(shell_path: &str) -> &'static str {
+    match shell_path {
+        "/bin/bash" | "/usr/bin/bash" => "xterm-256color",
+        "/bin/zsh" | "/usr/bin/zsh" => "xterm-256color",
+        "/bin/fish" | "/usr/bin/fish" => "xterm-256color",
+        _ => "vt100",
+    }
+}
+
+fn get_default_shell() -> String {
+    if let Ok(shell) = env::var("SHELL") {
+        return shell;
+    }
+
+    let username = env::var("USER").unwrap_or_else(|_| String::from("root"));
+    if let Ok(file) = File::open("/etc/passwd") {
+        let reader = BufReader::new(file);
+        for line in reader.lines().map_while(Result::ok) {
+            let parts: Vec<&str> = line.split(':').collect();
+            if parts.len() >= 7 && parts[0] == username {
+                return String::from(parts[6]);
+            }
+        }
+    }
+
+    String::from("/bin/sh")
+}
+
+/// Checks that `path` names an executable file, so a bad per-session shell is
+/// reported instead of failing inside the forked child.
+fn validate_shell(path: &str) -> Result<(), String> {
+    use std::os::unix::fs::PermissionsExt;
+    match fs::metadata(path) {
+        Ok(meta) if meta.is_file() && meta.permissions().mode() & 0o111 != 0 => Ok(()),
+        Ok(_) => Err(format!("Shell {} is not an executable file", path)),
+        Err(e) => Err(format!("Shell {} is unavailable: {}", path, e)),
+    }
+}
+
+fn main() -> Result<(), eframe::Error> {
+    let args: Vec<String> = env::args().collect();
+    let log_file = args.iter().position(|arg| arg == "--log-file").and_then(|i| args.get(i + 1)).map(PathBuf::from);
+    init_logging(log_file);
+    if let [_, flag, fd, pid] = &args[..] {
+        if flag == "--hold-session" {
+            if let (Ok(fd), Ok(pid)) = (fd.parse(), pid.parse()) {
+                if let Err(e) = setsid() {
+                    log::warn!("Failed to create new session: {}", e);
+                }
+                let master = unsafe { File::from_raw_fd(fd) };
+                if let Err(e) = hold_session(master, Pid::from_raw(pid), &session_socket_path()) {
+                    log::error!("Error holding session: {}", e);
+                }
+            }
+            return Ok(());
+        }
+    }
+    let config = load_config();
+    if args.iter().any(|arg| arg == "--headless") {
+        if let Err(e) = run_headless(&config) {
+            log::error!("Error in headless session: {}", e);
+        }
+        return Ok(());
+    }
+    let options = eframe::NativeOptions {
+        viewport: egui::ViewportBuilder::default()
+            .with_inner_size([800.0, 600.0])
+            .with_maximized(config.start_maximized)
+            .with_fullscreen(config.start_fullscreen),
+        ..Default::default()
+    };
+    eframe::run_native(
+        DEFAULT_TITLE,
+        options,
+        Box::new(|cc| Ok(Box::new(PhantomTTYApp::new(cc, config)))),
+    )
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn safe_mode_ignores_clipboard_writes() {
+        let mut vte = VteTerminal::new(20, 4);
+        vte.safe_mode = true;
+        vte.process(b"\x1b]52;c;aGVsbG8=\x07");
+        assert!(vte.take_clipboard_writes().is_empty());
+        vte.safe_mode = false;
+        vte.process(b"\x1b]52;c;aGVsbG8=\x07");
+        assert_eq!(vte.take_clipboard_writes(), vec!["hello".to_string()]);
+    }
+
+    #[test]
+    fn safe_mode_ignores_titles_and_status_reports() {
+        let mut vte = VteTerminal::new(20, 4);
+        vte.safe_mode = true;
+        vte.process(b"\x1b]2;pwned\x07\x1b[6n\x1b[5n");
+        assert_eq!(vte.take_title(), None);
+        assert!(vte.take_responses().is_empty());
+    }
+
+    #[test]
+    fn safe_mode_config_starts_sessions_safe() {
+        assert!(!app().vte_terminal.safe_mode);
+        let config = Config { safe_mode: true, ..Config::default() };
+        let mut app = app_with(&config);
+        assert!(app.vte_terminal.safe_mode);
+        app.vte_terminal.process(b"\x1b]52;c;aGk=\x07");
+        assert!(app.vte_terminal.take_clipboard_writes().is_empty());
+    }
+
+    #[test]
+    fn command_not_found_config_picks_the_helper() {
+        assert!(app().not_found_helper.is_none());
+        let config = |name: &str| Config { command_not_found: Some(name.to_string()), ..Config::default() };
+        assert!(app_with(&config("did-you-mean")).not_found_helper.is_some());
+        assert!(app_with(&config("command-not-found")).not_found_helper.is_some());
+        assert!(app_with(&config("off")).not_found_helper.is_none());
+        assert!(app_with(&config("bogus")).not_found_helper.is_none());
+    }
+
+    #[test]
+    fn edit_distance_counts_single_edits() {
+        assert_eq!(edit_distance("git", "git"), 0);
+        assert_eq!(edit_distance("gti", "git"), 2);
+        assert_eq!(edit_distance("gitt", "git"), 1);
+        assert_eq!(edit_distance("", "ls"), 2);
+    }
+
+    #[test]
+    fn did_you_mean_picks_the_nearest_name() {
+        let names = || ["cat", "git", "grep", "gzip"].map(String::from).to_vec();
+        assert_eq!(closest_name("gut", names()), Some("git".to_string()));
+        assert_eq!(closest_name("grp", names()), Some("grep".to_string()));
+        assert_eq!(closest_name("python", names()), None);
+    }
+
+    fn app() -> PhantomTTY {
+        app_with(&Config::default())
+    }
+
+    fn app_with(config: &Config) -> PhantomTTY {
+        PhantomTTY::new("/bin/sh".to_string(), Vec::new(), config, Arc::new(|| {}), false)
+    }
+
+    #[test]
+    fn locked_size_ignores_window_resizes() {
+        let mut app = app();
+        app.handle_phantom_command("lock-size 80 24").unwrap();
+        app.resize(132, 50);
+        assert_eq!((app.vte_terminal.width, app.vte_terminal.height), (80, 24));
+        app.handle_phantom_command("unlock-size").unwrap();
+        app.resize(132, 50);
+        assert_eq!((app.vte_terminal.width, app.vte_terminal.height), (132, 50));
+    }
+
+    #[test]
+    fn mirror_reads_the_shared_screen() {
+        let mut app = app();
+        app.handle_phantom_command("mirror").unwrap();
+        app.vte_terminal.process(b"shared text");
+        let shared = app.sync_mirror().unwrap();
+        assert!(shared.lock().unwrap().screen.starts_with("shared text"));
+        shared.lock().unwrap().closed = true;
+        assert!(app.sync_mirror().is_none());
+        assert!(app.mirror.is_none());
+    }
+
+    fn term(width: usize, height: usize, input: &[u8]) -> VteTerminal {
+        let mut term = VteTerminal::new(width, height);
+        term.process(input);
+        term
+    }
+
+    #[test]
+    fn rep_of_a_wide_char_wraps_like_typing() {
+        let repeated = term(5, 3, "你\x1b[3b".as_bytes());
+        let typed = term(5, 3, "你你你你".as_bytes());
+        assert_eq!(repeated.screen, typed.screen);
+        assert_eq!(&repeated.screen[..5], &['你', WIDE_SPACER, '你', WIDE_SPACER, ' ']);
+        assert_eq!(&repeated.screen[5..10], &['你', WIDE_SPACER, '你', WIDE_SPACER, ' ']);
+        assert_eq!((repeated.cursor_x, repeated.cursor_y), (4, 1));
+        assert_eq!((typed.cursor_x, typed.cursor_y), (4, 1));
+    }
+
+    #[test]
+    fn stray_paste_end_marker_is_swallowed() {
+        let mut term = term(10, 2, b"ab\x1b[201~cd");
+        assert_eq!(term.row_text(0).trim_end(), "abcd");
+        assert_eq!((term.cursor_x, term.cursor_y), (4, 0));
+        assert!(term.take_responses().is_empty());
+        term.process(b"\x1b[31mX");
+        assert_eq!(term.row_text(0).trim_end(), "abcdX");
+    }
+
+    #[test]
+    fn paste_markers_are_stripped_from_pasted_text() {
+        assert_eq!(strip_paste_markers("a\x1b[200~b\x1b[201~c"), "abc");
+    }
+
+    #[test]
+    fn repaint_interval_depends_on_focus() {
+        let mut widget = TerminalWidget::new();
+        assert_eq!(widget.repaint_interval(true), Some(Duration::ZERO));
+        assert_eq!(widget.repaint_interval(false), Some(UNFOCUSED_REPAINT_INTERVAL));
+        widget.unfocused_repaint = None;
+        assert_eq!(widget.repaint_interval(false), None);
+        assert_eq!(widget.repaint_interval(true), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn decid_replies_like_primary_device_attributes() {
+        let mut decid = term(80, 24, b"\x1bZ");
+        let mut da = term(80, 24, b"\x1b[c");
+        let reply = decid.take_responses();
+        assert!(reply.starts_with(b"\x1b[?"));
+        assert_eq!(reply, da.take_responses());
+    }
+
+    #[test]
+    fn configured_limits_apply_to_the_shell() {
+        let config = Config {
+            shell: Some("/bin/sh".to_string()),
+            limits: Limits { open_files: Some(64), ..Limits::default() },
+            ..Config::default()
+        };
+        let mut session = Session::start(&config, 80, 24);
+        session.poll_output();
+        let limits = fs::read_to_string(format!("/proc/{}/limits", session.child)).unwrap();
+        let open_files = limits.lines().find(|line| line.starts_with("Max open files")).unwrap();
+        assert_eq!(open_files.split_whitespace().collect::<Vec<_>>()[3..5], ["64", "64"]);
+        session.feed_input(b"exit\n");
+        session.poll_output();
+    }
+
+    #[test]
+    fn larger_font_fits_fewer_cells() {
+        let ctx = egui::Context::default();
+        let _ = ctx.run(egui::RawInput::default(), |_| {});
+        let mut app = app();
+        app.terminal.output_rect = egui::Rect::from_min_size(egui::Pos2::ZERO, egui::vec2(800.0, 600.0));
+        app.set_font_size(&ctx, 12.0);
+        let small = (app.vte_terminal.width, app.vte_terminal.height);
+        app.set_font_size(&ctx, 24.0);
+        let large = (app.vte_terminal.width, app.vte_terminal.height);
+        assert!(large.0 < small.0 && large.1 < small.1, "{:?} vs {:?}", large, small);
+        assert_eq!(Some(large), app.terminal.grid_size(&ctx));
+    }
+
+    #[test]
+    fn only_short_csi_t_scrolls_down() {
+        let mut scrolled = term(5, 3, b"a\r\nb\r\nc");
+        scrolled.process(b"\x1b[1T");
+        assert_eq!(scrolled.row_text(0).trim_end(), "");
+        assert_eq!(scrolled.row_text(1).trim_end(), "a");
+
+        let mut tracking = term(5, 3, b"a\r\nb\r\nc");
+        tracking.process(b"\x1b[1;1;1;1;3T");
+        assert_eq!(tracking.row_text(0).trim_end(), "a");
+        assert_eq!(tracking.row_text(2).trim_end(), "c");
+    }
+
+    /// Points the app's PTY writes at a temp file, returned for reading back.
+    fn capture_pty_writes(app: &mut PhantomTTY, name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("phantom-test-{}-{}", std::process::id(), name));
+        app.pty_master = Some(File::create(&path).unwrap());
+        path
+    }
+
+    #[test]
+    fn picked_clipboard_entry_is_pasted() {
+        let mut app = app();
+        app.clipboard.push("first");
+        app.clipboard.push("second");
+        assert_eq!(app.clipboard.entries, ["second", "first"]);
+        let path = capture_pty_writes(&mut app, "clipboard");
+        app.vte_terminal.bracketed_paste = true;
+        app.paste_clipboard_entry(1).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "\x1b[200~first\x1b[201~");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn decdwl_marks_the_row_double_width() {
+        let mut term = term(10, 2, b"\x1b[31mabcdefghij\x1b#6");
+        assert_eq!(term.line_attrs[0], LineAttr::DoubleWidth);
+        assert_eq!(term.row_text(0).trim_end(), "abcde");
+        assert!(term.cells[5..10].iter().all(|cell| *cell == Cell::default()));
+        assert_eq!((term.cursor_x, term.cursor_y), (4, 0));
+        term.process(b"\x1b#5");
+        assert_eq!(term.line_attrs[0], LineAttr::Single);
+    }
+
+    #[test]
+    fn clicked_snippet_is_sent_to_the_shell() {
+        let mut app = app();
+        let path = capture_pty_writes(&mut app, "snippet");
+        let snippet = Snippet { name: "deploy".to_string(), command: "./deploy.sh prod".to_string() };
+        app.run_snippet(&snippet).unwrap();
+        assert_eq!(app.last_command.as_deref(), Some("./deploy.sh prod"));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "./deploy.sh prod\n");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn columns_and_lines_seed_the_grid() {
+        let config = Config { columns: Some(100), lines: Some(30), ..Config::default() };
+        let env = |name: &str| match name {
+            "COLUMNS" => Some("120".to_string()),
+            "LINES" => Some(" 40 ".to_string()),
+            _ => None,
+        };
+        assert_eq!(initial_grid_size(&config, env), (120, 40));
+        assert_eq!(initial_grid_size(&config, |_| Some("0".to_string())), (100, 30));
+        assert_eq!(initial_grid_size(&Config::default(), |_| Some("wide".to_string())), (80, 24));
+    }
+
+    #[test]
+    fn custom_command_expands_and_runs_in_the_shell() {
+        let config = Config {
+            phantom_command: vec![
+                PhantomCommand { name: "gs".to_string(), run: "git status".to_string() },
+                PhantomCommand { name: "g".to_string(), run: "git {args}".to_string() },
+                PhantomCommand { name: "hello".to_string(), run: "echo shadowed".to_string() },
+            ],
+            ..Config::default()
+        };
+        let mut app = app_with(&config);
+        let path = capture_pty_writes(&mut app, "custom-command");
+        app.execute_command("phantom:gs").unwrap();
+        assert_eq!(app.last_command.as_deref(), Some("git status"));
+        app.execute_command("phantom:g log it's; rm").unwrap();
+        assert_eq!(app.last_command.as_deref(), Some("git log 'it'\\''s;' rm"));
+        app.execute_command("phantom:hello").unwrap();
+        assert_eq!(app.terminal.output, "Hello from PhantomTTY!");
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn shell_quote_leaves_plain_words_alone() {
+        assert_eq!(shell_quote("src/main.rs"), "src/main.rs");
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote("$(rm -rf ~)"), "'$(rm -rf ~)'");
+        assert_eq!(expand_custom_command("grep {args} .", "-r foo"), "grep -r foo .");
+    }
+
+    #[test]
+    fn decscl_records_level_and_c1_controls() {
+        let mut term = term(80, 24, b"");
+        assert_eq!((term.conformance_level, term.c1_8bit), (61, false));
+        term.process(b"\x1b[64;0\"p");
+        assert_eq!((term.conformance_level, term.c1_8bit), (64, true));
+        term.process(b"\x1b[5n");
+        assert_eq!(term.take_responses(), b"\x9b0n");
+        term.process(b"\x1b[63;1\"p");
+        assert_eq!((term.conformance_level, term.c1_8bit), (63, false));
+        term.process(b"\x1b[70\"p");
+        assert_eq!(term.conformance_level, 63);
+    }
+
+    #[test]
+    fn dropped_path_is_relative_only_under_the_cwd() {
+        let cwd = Path::new("/home/user/project");
+        let inside = Path::new("/home/user/project/src/main.rs");
+        let outside = Path::new("/etc/hosts");
+        assert_eq!(dropped_path_text(inside, Some(cwd), true), "src/main.rs");
+        assert_eq!(dropped_path_text(inside, Some(cwd), false), "/home/user/project/src/main.rs");
+        assert_eq!(dropped_path_text(outside, Some(cwd), true), "/etc/hosts");
+        assert_eq!(dropped_path_text(cwd, Some(cwd), true), "/home/user/project");
+        assert_eq!(dropped_path_text(inside, None, true), "/home/user/project/src/main.rs");
+    }
+
+    #[test]
+    fn large_paste_goes_through_a_temp_file() {
+        let config = Config { paste: PasteConfig { temp_file_threshold: Some(8) }, ..Config::default() };
+        let mut app = app_with(&config);
+        let sent = capture_pty_writes(&mut app, "large-paste");
+        app.paste("short").unwrap();
+        app.paste("a much longer paste").unwrap();
+        assert_eq!(fs::read_to_string(&sent).unwrap(), "short");
+        let path = app.paste_files[0].clone();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "a much longer paste");
+        assert_eq!(app.terminal.input, format!("\"$(cat {})\"", path.display()));
+        app.remove_paste_files();
+        assert!(!path.exists());
+        fs::remove_file(sent).unwrap();
+    }
+
+    #[test]
+    fn alternate_scroll_turns_the_wheel_into_arrow_keys() {
+        let mut term = term(80, 24, b"\x1b[?1007h");
+        assert!(term.alternate_scroll);
+        assert!(!term.input_modes().alternate_scroll);
+        term.process(b"\x1b[?1049h\x1b[?1h");
+        let modes = term.input_modes();
+        assert!(modes.alternate_scroll);
+
+        let mut widget = TerminalWidget::new();
+        widget.scroll_lines = 2;
+        let ctx = egui::Context::default();
+        let wheel = |delta: f32| egui::RawInput {
+            events: vec![egui::Event::MouseWheel {
+                unit: egui::MouseWheelUnit::Line,
+                delta: egui::vec2(0.0, delta),
+                modifiers: egui::Modifiers::NONE,
+            }],
+            ..Default::default()
+        };
+        let font_id = egui::FontId::monospace(DEFAULT_FONT_SIZE);
+        let _ = ctx.run(wheel(1.0), |ctx| {
+            egui::CentralPanel::default().show(ctx, |ui| widget.wheel_to_arrow_keys(ui, &font_id, modes));
+        });
+        assert_eq!(widget.take_pending_input(), b"\x1bOA\x1bOA");
+        let _ = ctx.run(wheel(-1.0), |ctx| {
+            egui::CentralPanel::default().show(ctx, |ui| widget.wheel_to_arrow_keys(ui, &font_id, modes));
+        });
+        assert_eq!(widget.take_pending_input(), b"\x1bOB\x1bOB");
+    }
+
+    #[test]
+    fn state_dump_reflects_toggled_modes() {
+        let mut term = term(80, 24, b"");
+        let dump = term.state_dump();
+        assert!(dump.contains("Bracketed paste (?2004): off\n"));
+        assert!(dump.contains("Cursor keys (DECCKM): normal\n"));
+        term.process(b"\x1b[?2004h\x1b[?1h\x1b[5;10r\x1b[3;4H");
+        let dump = term.state_dump();
+        assert!(dump.contains("Bracketed paste (?2004): on\n"));
+        assert!(dump.contains("Cursor keys (DECCKM): application\n"));
+        assert!(dump.contains("Scroll region: rows 5-10\n"));
+        assert!(dump.contains("Cursor: row 3, col 4\n"));
+    }
+
+    #[test]
+    fn carriage_return_clears_a_pending_wrap() {
+        let term = term(5, 3, b"abcde\rXY");
+        assert!(!term.wrap_pending);
+        assert_eq!(term.row_text(0), "XYcde");
+        assert_eq!(term.row_text(1).trim_end(), "");
+        assert_eq!((term.cursor_x, term.cursor_y), (2, 0));
+    }
+
+    #[test]
+    fn configured_tab_width_sets_stops_and_copy_form() {
+        let mut app = app_with(&Config { tab_width: Some(4), ..Config::default() });
+        app.vte_terminal.process(b"a\tb");
+        assert_eq!(app.vte_terminal.screen[4], 'b');
+        assert_eq!(app.vte_terminal.copy_text(false), "a\tb");
+        assert_eq!(app.vte_terminal.copy_text(true), "a   b");
+    }
+
+    #[test]
+    fn trailing_partial_prompt_is_flushed_while_the_writer_idles() {
+        let (read_fd, write_fd) = nix::unistd::pipe().unwrap();
+        let (reader, mut writer) = unsafe { (File::from_raw_fd(read_fd), File::from_raw_fd(write_fd)) };
+        let woken = Arc::new(AtomicU64::new(0));
+        let (tx, rx) = mpsc::channel();
+        let counter = woken.clone();
+        spawn_pty_reader(reader, tx, Arc::new(move || { counter.fetch_add(1, Ordering::SeqCst); }));
+        writer.write_all(b"user@host:~$ ").unwrap();
+        let sent = Instant::now();
+        match rx.recv_timeout(FLUSH_MAX_DELAY * 4) {
+            Ok(PtyEvent::Output(bytes)) => assert_eq!(bytes, b"user@host:~$ "),
+            _ => panic!("partial prompt was held back"),
+        }
+        assert!(sent.elapsed() >= FLUSH_QUIET);
+        drop(writer);
+        assert!(matches!(rx.recv_timeout(RUN_TIMEOUT), Ok(PtyEvent::Closed)));
+        // The wake for the prompt came before the close was sent.
+        assert!(woken.load(Ordering::SeqCst) > 0);
+    }
+
+    fn test_colors() -> CellColors<'static> {
+        CellColors {
+            palette: &[],
+            background: egui::Color32::BLACK,
+            dim: egui::Color32::DARK_GRAY,
+            high_contrast: false,
+            clusters: &[],
+        }
+    }
+
+    #[test]
+    fn whitespace_placeholders_replace_tabs_and_trailing_blanks() {
+        let base = egui::TextFormat::simple(egui::FontId::monospace(DEFAULT_FONT_SIZE), egui::Color32::WHITE);
+        let line = "a\t  b  \n";
+        let mut shown = egui::text::LayoutJob::default();
+        append_row(&mut shown, line, &base, &[], &test_colors(), true);
+        assert_eq!(shown.text, "a→  b··\n");
+        let dimmed: String = shown.sections.iter()
+            .filter(|section| section.format.color == egui::Color32::DARK_GRAY)
+            .map(|section| &shown.text[section.byte_range.clone()])
+            .collect();
+        assert_eq!(dimmed, "→··");
+
+        let mut plain = egui::text::LayoutJob::default();
+        append_row(&mut plain, line, &base, &[], &test_colors(), false);
+        assert_eq!(plain.text, "a   b  \n");
+    }
+
+    #[test]
+    fn sessions_keep_their_own_shell_and_env() {
+        let config = Config::default();
+        let env = vec![("PHANTOM_PANE".to_string(), "left".to_string())];
+        let mut dash = PhantomTTY::new("/bin/dash".to_string(), env.clone(), &config, Arc::new(|| {}), false);
+        let mut bash = PhantomTTY::new("/bin/bash".to_string(), Vec::new(), &config, Arc::new(|| {}), false);
+        assert_eq!((dash.shell_path.as_str(), dash.env.as_slice()), ("/bin/dash", env.as_slice()));
+        assert_eq!((bash.shell_path.as_str(), bash.env.as_slice()), ("/bin/bash", &[][..]));
+        assert_eq!((dash.term.as_str(), bash.term.as_str()), ("vt100", "xterm-256color"));
+        bash.handle_phantom_command("shell").unwrap();
+        assert_eq!(bash.terminal.output, "Current shell: /bin/bash");
+        dash.handle_phantom_command("spawn /nonexistent/shell").unwrap();
+        assert!(dash.spawn_request.is_none());
+    }
+
+    #[test]
+    fn decstbm_defaults_and_validation() {
+        let mut term = term(10, 10, b"\x1b[3;6r");
+        assert_eq!((term.scroll_top, term.scroll_bottom), (2, 5));
+        assert_eq!((term.cursor_x, term.cursor_y), (0, 0));
+
+        term.process(b"\x1b[5;5H\x1b[r");
+        assert_eq!((term.scroll_top, term.scroll_bottom), (0, 9));
+        assert_eq!((term.cursor_x, term.cursor_y), (0, 0));
+
+        term.process(b"\x1b[4r");
+        assert_eq!((term.scroll_top, term.scroll_bottom), (3, 9));
+
+        term.process(b"\x1b[5;5H\x1b[7;7r\x1b[8;2r");
+        assert_eq!((term.scroll_top, term.scroll_bottom), (3, 9));
+        assert_eq!((term.cursor_x, term.cursor_y), (4, 4));
+
+        term.process(b"\x1b[?6h\x1b[5;8r");
+        assert_eq!((term.cursor_x, term.cursor_y), (0, 4));
+    }
+
+    #[test]
+    fn echo_flag_flips_input_masking() {
+        let mut app = app();
+        let pty = openpty(None, None).unwrap();
+        app.pty_master = Some(unsafe { File::from_raw_fd(pty.master) });
+        let slave = unsafe { File::from_raw_fd(pty.slave) };
+        let set_echo = |echo: bool| {
+            let mut attrs = termios::tcgetattr(slave.as_raw_fd()).unwrap();
+            attrs.local_flags.insert(termios::LocalFlags::ICANON);
+            attrs.local_flags.set(termios::LocalFlags::ECHO, echo);
+            termios::tcsetattr(slave.as_raw_fd(), SetArg::TCSANOW, &attrs).unwrap();
+        };
+        set_echo(false);
+        app.update_input_masking();
+        assert!(app.terminal.mask_input);
+        set_echo(true);
+        app.update_input_masking();
+        assert!(!app.terminal.mask_input);
+        app.handle_phantom_command("echo off").unwrap();
+        app.update_input_masking();
+        assert!(app.terminal.mask_input);
+    }
+
+    #[test]
+    fn exported_theme_imports_identically() {
+        let path = env::temp_dir().join(format!("phantom-test-{}-theme.toml", std::process::id()));
+        let mut source = app();
+        source.theme.palette[1] = "#aa0000".to_string();
+        source.theme.cursor = "#00ff00".to_string();
+        let theme = source.theme.clone();
+        source.handle_phantom_command(&format!("export-theme {}", path.display())).unwrap();
+
+        let mut target = app();
+        target.theme_request = None;
+        target.handle_phantom_command(&format!("import-theme {}", path.display())).unwrap();
+        assert!(target.theme_request == Some(theme));
+
+        fs::write(&path, "[theme]\nforeground = \"red\"\n").unwrap();
+        let mut invalid = app();
+        invalid.theme_request = None;
+        invalid.handle_phantom_command(&format!("import-theme {}", path.display())).unwrap();
+        assert!(invalid.theme_request.is_none());
+        assert!(invalid.terminal.output.starts_with("Invalid theme"));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn osc_9_and_777_become_notifications() {
+        let mut term = term(80, 24, b"\x1b]9;Build done; 3 warnings\x07\x1b]777;notify;Tests;All passed\x1b\\\x1b]9;4;1;50\x07");
+        let notifications = term.take_notifications();
+        let parsed: Vec<(&str, &str)> = notifications.iter().map(|n| (n.title.as_str(), n.body.as_str())).collect();
+        assert_eq!(parsed, [("PhantomTTY", "Build done; 3 warnings"), ("Tests", "All passed")]);
+
+        let long = format!("\x1b]9;{}\x07", "x".repeat(1000));
+        term.process(long.as_bytes());
+        assert_eq!(term.take_notifications()[0].body.len(), Notification::MAX_BODY);
+    }
+
+    #[test]
+    fn scroll_clear_moves_the_screen_into_scrollback() {
+        let mut term = term(10, 4, b"one\r\ntwo\r\n$ ");
+        term.scroll_clear();
+        let saved: Vec<String> = term.scrollback.iter().map(|line| line.chars.iter().collect::<String>().trim_end().to_string()).collect();
+        assert_eq!(saved, ["one", "two"]);
+        assert_eq!(term.row_text(0).trim_end(), "$");
+        assert!((1..4).all(|row| term.row_text(row).trim().is_empty()));
+        assert_eq!((term.cursor_x, term.cursor_y), (2, 0));
+    }
+
+    #[test]
+    fn key_modifier_options_leave_the_pen_alone() {
+        let mut term = term(80, 24, b"\x1b[31m");
+        let pen = term.pen;
+        term.process(b"\x1b[=1;2m\x1b[=0m");
+        assert!(term.pen == pen);
+        term.process(b"X");
+        assert_eq!(term.cells[0].fg, Color::Indexed(1));
+    }
+
+    #[test]
+    fn run_returns_the_command_output() {
+        use std::os::unix::net::UnixStream;
+
+        let mut app = app();
+        let path = capture_pty_writes(&mut app, "run");
+        let socket_path = env::temp_dir().join(format!("phantom-test-{}-control.sock", std::process::id()));
+        let control = serve_control_socket(socket_path.clone(), Arc::new(|| {})).unwrap();
+        let caller = thread::spawn(move || {
+            let mut stream = UnixStream::connect(&socket_path).unwrap();
+            stream.write_all(b"run echo hi\n").unwrap();
+            let mut answer = String::new();
+            stream.read_to_string(&mut answer).unwrap();
+            answer
+        });
+        let ControlRequest::Run(command, reply) = control.requests.recv_timeout(Duration::from_secs(5)).unwrap();
+        app.run_capture(&command, reply).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "echo hi\n");
+        // What a shell with OSC 133 integration prints for it.
+        app.vte_terminal.process(b"echo hi\r\n\x1b]133;C\x07hi\r\n\x1b]133;D;0\x07\x1b]133;A\x07$ ");
+        app.poll_run_capture();
+        assert_eq!(caller.join().unwrap(), "hi\n");
+        assert!(app.run_pending.is_none());
+        assert!(app.copy_requests.is_empty());
+
+        // A command that never reaches its D mark times out.
+        let (reply, answer) = mpsc::channel();
+        app.run_capture("cat", reply).unwrap();
+        app.run_pending.as_mut().unwrap().0 = Instant::now();
+        app.poll_run_capture();
+        assert_eq!(answer.recv().unwrap(), Err(RunTimeout));
+        assert!(!app.vte_terminal.capture_armed);
+
+        // phantom:run puts it on the clipboard instead.
+        app.handle_phantom_command("run echo hi").unwrap();
+        app.vte_terminal.process(b"echo hi\r\n\x1b]133;C\x07hi\r\n\x1b]133;D;0\x07\x1b]133;A\x07$ ");
+        app.poll_run_capture();
+        assert_eq!(app.copy_requests, ["hi"]);
+        let socket_path = control.path.clone();
+        drop(control);
+        assert!(!socket_path.exists());
+        fs::remove_file(path).unwrap();
+    }
+
+    fn rows(term: &VteTerminal) -> Vec<String> {
+        (0..term.height).map(|row| term.row_text(row)).collect()
+    }
+
+    #[test]
+    fn decfra_and_decera_change_only_the_rectangle() {
+        let mut term = term(6, 4, b"abcdefghijklmnopqrstuvwx");
+        term.process(b"\x1b[31m\x1b[88;2;2;3;4$x");
+        assert_eq!(rows(&term), ["abcdef", "gXXXkl", "mXXXqr", "stuvwx"]);
+        assert_eq!(term.cells[7].fg, Color::Indexed(1));
+        assert_eq!(term.cells[10].fg, Color::Default);
+        term.process(b"\x1b[0;44m\x1b[1;5;2;6$z");
+        assert_eq!(rows(&term), ["abcd", "gXXX", "mXXXqr", "stuvwx"]);
+        assert_eq!((term.cells[4].fg, term.cells[4].bg), (Color::Default, Color::Indexed(4)));
+        assert_eq!(term.cells[9].bg, Color::Default);
+        term.process(b"\x1b[0m");
+        term.process(b"\x1b[3;5;9;99$z");
+        assert_eq!(rows(&term), ["abcd", "gXXX", "mXXX", "stuv"]);
+        // A wide fill character is ignored.
+        term.process("\x1b[20320;1;1;4;6$x".as_bytes());
+        assert_eq!(rows(&term), ["abcd", "gXXX", "mXXX", "stuv"]);
+    }
+
+    #[test]
+    fn rectangle_edge_splits_a_wide_glyph() {
+        let mut term = term(6, 1, "你好ab".as_bytes());
+        term.process(b"\x1b[42;1;2;1;3$x");
+        assert_eq!(&term.screen[..], &[' ', '*', '*', ' ', 'a', 'b']);
+    }
+
+    #[test]
+    fn detached_session_is_handed_back_with_its_replay() {
+        let path = env::temp_dir().join(format!("phantom-test-{}-session.sock", std::process::id()));
+        let pty = openpty(None, None).unwrap();
+        let (master, mut slave) = unsafe { (File::from_raw_fd(pty.master), File::from_raw_fd(pty.slave)) };
+        slave.write_all(b"before detach").unwrap();
+        let child = nix::unistd::getpid();
+        let holder_path = path.clone();
+        let holder = thread::spawn(move || hold_session(master, child, &holder_path));
+        // Keep knocking until the holder is listening.
+        let deadline = Instant::now() + RUN_TIMEOUT;
+        let mut session = loop {
+            match reattach_session(&path) {
+                Some(session) => break session,
+                None if Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+                None => panic!("no session holder on {}", path.display()),
+            }
+        };
+        holder.join().unwrap().unwrap();
+        assert_eq!(session.child, child);
+        assert_eq!(session.replay, b"before detach");
+        assert!(!path.exists());
+        slave.write_all(b"after").unwrap();
+        let mut buf = [0u8; 5];
+        session.master.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"after");
+    }
+
+    #[test]
+    fn sl_and_sr_shift_columns_and_blank_the_rest() {
+        let mut term = term(6, 2, b"abcdefghijkl");
+        term.process(b"\x1b[2 @");
+        assert_eq!(rows(&term), ["cdef", "ijkl"]);
+        term.process(b"\x1b[3 A");
+        assert_eq!(rows(&term), ["   cde", "   ijk"]);
+        term.process(b"\x1b[99 @");
+        assert_eq!(rows(&term), ["", ""]);
+    }
+
+    #[test]
+    fn sl_drops_a_wide_glyph_cut_by_the_shift() {
+        let mut term = term(6, 1, "a你bcd".as_bytes());
+        term.process(b"\x1b[2 @");
+        assert_eq!(&term.screen[..], &[' ', 'b', 'c', 'd', ' ', ' ']);
+    }
+
+    #[test]
+    fn token_at_trims_surrounding_punctuation() {
+        let term = term(40, 1, b"see (notes.md), and \"src/main.rs\".");
+        assert_eq!(term.token_at(0, 6).as_deref(), Some("notes.md"));
+        assert_eq!(term.token_at(0, 25).as_deref(), Some("src/main.rs"));
+        assert_eq!(term.token_at(0, 3), None);
+        assert_eq!(term.token_at(0, 39), None);
+    }
+
+    #[test]
+    fn clicked_path_opens_by_kind() {
+        let dir = env::temp_dir().join(format!("phantom-test-{}-open", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("notes.MD"), "").unwrap();
+        let mut open_with = HashMap::new();
+        open_with.insert("md".to_string(), "glow".to_string());
+        let mut app = app_with(&Config { open_with, ..Config::default() });
+        let sent = capture_pty_writes(&mut app, "open-sent");
+        app.vte_terminal.cwd = Some(dir.clone());
+        app.vte_terminal.process(b"notes.MD sub missing");
+        app.open_path_at(0, 2).unwrap();
+        app.open_path_at(0, 10).unwrap();
+        app.open_path_at(0, 15).unwrap();
+        let expected = format!("glow {}\rcd {}\r", dir.join("notes.MD").display(), dir.join("sub").display());
+        assert_eq!(fs::read_to_string(&sent).unwrap(), expected);
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_file(sent).unwrap();
+    }
+
+    #[test]
+    fn search_is_seeded_from_the_selection() {
+        let term = term(20, 3, b"error: one\r\nok\r\nerror: two");
+        let mut widget = TerminalWidget::new();
+        widget.selected_text = Some("  error \nsecond line".to_string());
+        widget.open_search(&term);
+        let search = widget.search.as_ref().unwrap();
+        assert_eq!(search.query, "error");
+        assert_eq!(search.hits.len(), 2);
+        assert_eq!(search.current, 1);
+        assert!(search.jump);
+
+        let mut blank = TerminalWidget::new();
+        blank.selected_text = Some("   ".to_string());
+        blank.open_search(&term);
+        assert_eq!(blank.search.as_ref().unwrap().query, "");
+    }
+
+    #[test]
+    fn decrqm_reports_insert_mode() {
+        let mut term = term(80, 24, b"\x1b[4$p");
+        assert_eq!(term.take_responses(), b"\x1b[4;2$y");
+        term.process(b"\x1b[4h\x1b[4$p");
+        assert_eq!(term.take_responses(), b"\x1b[4;1$y");
+        term.process(b"\x1b[20$p");
+        assert_eq!(term.take_responses(), b"\x1b[20;2$y");
+    }
+
+    #[test]
+    fn clear_on_command_moves_prior_output_to_scrollback() {
+        let mut app = app_with(&Config { clear_on_command: true, ..Config::default() });
+        let sent = capture_pty_writes(&mut app, "clear-on-command");
+        app.vte_terminal.process(b"old output\r\n$ ");
+        app.execute_command("ls").unwrap();
+        let saved: Vec<String> = app.vte_terminal.scrollback.iter().map(|line| grid_text(&line.chars).trim_end().to_string()).collect();
+        assert_eq!(saved, ["old output"]);
+        assert_eq!(app.vte_terminal.row_text(0), "$");
+        assert_eq!(app.vte_terminal.row_text(1), "");
+        assert_eq!(fs::read_to_string(&sent).unwrap(), "ls\n");
+        fs::remove_file(sent).unwrap();
+    }
+
+    #[test]
+    fn s8c1t_switches_replies_to_8_bit_csi() {
+        // A VT100-level terminal has no 8-bit controls to switch to.
+        let mut term = term(80, 24, b"\x1b G\x1b[c");
+        assert!(term.take_responses().starts_with(b"\x1b[?"));
+        term.process(b"\x1b[64;1\"p\x1b G\x1b[c");
+        assert!(term.take_responses().starts_with(b"\x9b?"));
+        term.process(b"\x1b F\x1b[c");
+        assert!(term.take_responses().starts_with(b"\x1b[?"));
+    }
+
+    #[test]
+    fn decrqcra_replies_with_the_rectangle_checksum() {
+        let mut term = term(10, 3, b"AB\r\nCD");
+        term.process(b"\x1b[7;1;1;1;2;2*y");
+        // 'A' + 'B' + 'C' + 'D' = 0x010A, negated.
+        assert_eq!(term.take_responses(), b"\x1bP7!~FEF6\x1b\\");
+        term.process(b"\x1b[1;1;3;1;3;1*y");
+        // One blank: 0x20 negated.
+        assert_eq!(term.take_responses(), b"\x1bP1!~FFE0\x1b\\");
+    }
+
+    #[test]
+    fn warnings_land_in_the_log_file() {
+        let path = env::temp_dir().join(format!("phantom-test-{}-diagnostics.log", std::process::id()));
+        // The logger is installed once per process, so it runs in a child.
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                init_logging(Some(path.clone()));
+                log::warn!("Failed to do something");
+                log::logger().flush();
+                unsafe { libc::_exit(0) };
+            }
+            ForkResult::Parent { child } => {
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            }
         }
+        let logged = fs::read_to_string(&path).unwrap();
+        fs::remove_file(path).unwrap();
+        assert!(logged.ends_with(" WARN  Failed to do something\n"), "{:?}", logged);
     }
 
-    fn set_output(&mut self, output: &str) {
-        self.output = output.to_string();
+    #[test]
+    fn failed_command_gets_a_badge_with_its_code() {
+        let term = term(20, 5, b"\x1b]133;A\x07$ false\r\n\x1b]133;C\x07\x1b]133;D;2\x07\x1b]133;A\x07$ true\r\n\x1b]133;D;0\x07");
+        assert_eq!(term.exit_badges[0], Some(ExitBadge { status: 2, folded: false }));
+        assert_eq!(term.exit_badges[1], Some(ExitBadge { status: 0, folded: false }));
+        let mut widget = TerminalWidget::new();
+        widget.set_screen(&term.screen_text(), &term);
+        assert_eq!(widget.exit_badges[0].map(|badge| badge.status), Some(2));
     }
 
-    fn add_to_history(&mut self, command: String) {
-        self.history.push_front(command);
-        if self.history.len() > HISTORY_SIZE {
-            self.history.pop_back();
-        }
-        self.history_index = None;
+    #[test]
+    fn decsel_keeps_protected_cells() {
+        let mut term = term(10, 1, b"ab\x1b[1\"qCD\x1b[0\"qef\x1b[1G\x1b[?2K");
+        assert_eq!(term.row_text(0), "  CD");
+        assert!(term.cells[2].protected && !term.cells[4].protected);
+        // Plain EL erases everything.
+        term.process(b"\x1b[2K");
+        assert_eq!(term.row_text(0), "");
     }
 
-    fn get_previous_command(&mut self) -> Option<String> {
-        if self.history.is_empty() {
-            return None;
-        }
-        let index = self.history_index.map(|i| i + 1).unwrap_or(0);
-        if index < self.history.len() {
-            self.history_index = Some(index);
-            Some(self.history[index].clone())
-        } else {
-            None
+    #[test]
+    fn latency_probe_matches_sent_bytes_to_their_echo() {
+        let start = Instant::now();
+        let ms = |n| start + Duration::from_millis(n);
+        let mut probe = LatencyProbe::default();
+        probe.sent(b"a", ms(0));
+        probe.sent(b"\r", ms(5));
+        probe.sent(b"b", ms(10));
+        assert_eq!(probe.sent.len(), 2);
+        probe.received(b"\x1b[Kxa", ms(30));
+        probe.received(b"b", ms(50));
+        assert_eq!(probe.samples, [Duration::from_millis(30), Duration::from_millis(40)]);
+        assert_eq!(probe.average(), Some(Duration::from_millis(35)));
+
+        probe.sent(b"c", ms(100));
+        probe.received(b"c", ms(100) + LatencyProbe::GIVE_UP);
+        assert_eq!(probe.samples.len(), 2);
+        assert!(probe.sent.is_empty());
+    }
+
+    #[test]
+    fn resets_restore_the_configured_cursor() {
+        let configured = CursorStyle { shape: CursorShape::Bar, blink: false };
+        let mut app = app_with(&Config { cursor: configured, ..Config::default() });
+        let term = &mut app.vte_terminal;
+        assert_eq!(term.cursor_style, configured);
+        term.process(b"\x1b[3 q");
+        assert_eq!(term.cursor_style, CursorStyle { shape: CursorShape::Underline, blink: true });
+        term.process(b"\x1bc");
+        assert_eq!(term.cursor_style, configured);
+        term.process(b"\x1b[1 q\x1b[!p");
+        assert_eq!(term.cursor_style, configured);
+        term.process(b"\x1b[1 q\x1b[0 q");
+        assert_eq!(term.cursor_style, configured);
+    }
+
+    /// A window around `tabs`, as `PhantomTTYApp::new` builds one minus the egui setup.
+    fn window(tabs: Vec<PhantomTTY>, config: Config) -> PhantomTTYApp {
+        PhantomTTYApp {
+            tabs,
+            active: 0,
+            snippets: config.snippets.clone(),
+            show_snippets: config.show_snippets,
+            config,
+            shell_path: "/bin/sh".to_string(),
+            waker: Arc::new(|| {}),
+            fit_window: false,
+            fitted_output_size: Some(egui::Vec2::ZERO),
+            omnibar: None,
+            control: None,
         }
     }
 
-    fn get_next_command(&mut self) -> Option<String> {
-        if let Some(index) = self.history_index {
-            if index > 0 {
-                self.history_index = Some(index - 1);
-                Some(self.history[index - 1].clone())
-            } else {
-                self.history_index = None;
-                Some(String::new())
-            }
-        } else {
-            None
+    fn key(key: egui::Key) -> egui::Event {
+        egui::Event::Key { key, physical_key: None, pressed: true, repeat: false, modifiers: egui::Modifiers::NONE }
+    }
+
+    #[test]
+    fn omnibar_submits_through_execute_command() {
+        let mut tab = app();
+        let sent = capture_pty_writes(&mut tab, "omnibar");
+        let mut window = window(vec![tab], Config::default());
+        window.omnibar = Some("echo quick".to_string());
+        let ctx = egui::Context::default();
+        // The bar takes focus over the first frames, as it would on screen.
+        for _ in 0..2 {
+            let _ = ctx.run(egui::RawInput::default(), |ctx| window.show_omnibar(ctx));
         }
+        let enter = egui::RawInput { events: vec![key(egui::Key::Enter)], ..Default::default() };
+        let _ = ctx.run(enter, |ctx| window.show_omnibar(ctx));
+        assert!(window.omnibar.is_none());
+        assert_eq!(window.tabs[0].last_command.as_deref(), Some("echo quick"));
+        assert_eq!(window.tabs[0].terminal.history.back().map(String::as_str), Some("echo quick"));
+        assert_eq!(fs::read_to_string(&sent).unwrap(), "echo quick\n");
+        fs::remove_file(sent).unwrap();
     }
 
-    fn show(&mut self, ui: &mut egui::Ui, _ctx: &egui::Context) -> Option<String> {
-        let mut executed_command = None;
-    
-        ui.vertical(|ui| {
-            let available_size = ui.available_size();
-            let output_height = available_size.y - 30.0;
-    
-            egui::ScrollArea::vertical()
-                .auto_shrink([false; 2])
-                .stick_to_bottom(true)
-                .max_height(output_height)
-                .show(ui, |ui| {
-                    ui.add(
-                        egui::TextEdit::multiline(&mut self.output)
-                            .font(egui::FontId::monospace(14.0))
-                            .desired_width(f32::INFINITY)
-                            .desired_rows((output_height / 14.0) as usize)
-                            .lock_focus(true)
-                            .interactive(false)
-                    );
-                });
-    
-            ui.horizontal(|ui| {
-                ui.label(&self.prompt);
-                let response = ui.add(
-                    egui::TextEdit::singleline(&mut self.input)
-                        .desired_width(f32::INFINITY)
-                        .font(egui::FontId::monospace(14.0))
-                );
-    
-                if response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
-                    let command = self.input.trim().to_string();
-                    if !command.is_empty() {
-                        self.add_to_history(command.clone());
-                        executed_command = Some(command);
-                        self.input.clear();
-                    }
-                    response.request_focus();
-                }
-    
-                if ui.input(|i| i.key_pressed(egui::Key::ArrowUp)) {
-                    if let Some(prev_command) = self.get_previous_command() {
-                        self.input = prev_command;
-                    }
-                }
-    
-                if ui.input(|i| i.key_pressed(egui::Key::ArrowDown)) {
-                    if let Some(next_command) = self.get_next_command() {
-                        self.input = next_command;
-                    }
-                }
+    /// Text of the sections of `job` whose background is `color`.
+    fn text_with_background(job: &egui::text::LayoutJob, color: egui::Color32) -> String {
+        job.sections.iter()
+            .filter(|section| section.format.background == color)
+            .map(|section| &job.text[section.byte_range.clone()])
+            .collect()
+    }
+
+    #[test]
+    fn current_line_highlight_follows_the_cursor_row() {
+        let mut term = term(10, 3, b"first\r\nsecond\r\nthird\x1b[2;1H");
+        let mut widget = TerminalWidget::new();
+        widget.highlight_current_line = true;
+        widget.set_screen(&term.screen_text(), &term);
+        let ctx = egui::Context::default();
+        let font_id = egui::FontId::monospace(DEFAULT_FONT_SIZE);
+        let mut highlighted = String::new();
+        let _ = ctx.run(egui::RawInput::default(), |ctx| {
+            egui::CentralPanel::default().show(ctx, |ui| {
+                let job = widget.layout_job(ui, &font_id);
+                highlighted = text_with_background(&job, ui.visuals().faint_bg_color);
             });
         });
-    
-        executed_command
+        assert_eq!(highlighted.trim_end(), "second");
+
+        term.process(b"\x1b[?1049h");
+        widget.set_screen(&term.screen_text(), &term);
+        assert_eq!(widget.cursor_row, None);
     }
-}
 
-struct PhantomTTY {
-    shell_path: String,
-    history_file: PathBuf,
-    editor: Editor<PhantomCompleter, DefaultHistory>,
-    terminal: TerminalWidget,
-    term: String,
-    pty_master: Option<File>,
-    vte_terminal: VteTerminal,
-}
+    #[test]
+    fn reflow_rejoins_wrapped_rows_at_the_new_width() {
+        let rows = |rows: &[(&str, bool)]| rows.iter().map(|&(text, wrapped)| (text.to_string(), wrapped)).collect::<Vec<_>>();
+        let screen = rows(&[("abcd", true), ("efgh", true), ("ij", false), ("", false), ("klm", false)]);
+        assert_eq!(reflow_rows(&screen, 6), rows(&[("abcdef", true), ("ghij", false), ("", false), ("klm", false)]));
+        assert_eq!(reflow_rows(&screen, 3), rows(&[("abc", true), ("def", true), ("ghi", true), ("j", false), ("", false), ("klm", false)]));
 
-impl PhantomTTY {
-    fn new(shell_path: String) -> Self {
-        let history_file = get_history_file_path();
-        let config = Config::builder()
-            .history_ignore_space(true)
-            .completion_type(CompletionType::List)
-            .edit_mode(EditMode::Emacs)
-            .build();
-        let helper = PhantomCompleter {
-            filename_completer: FilenameCompleter::new(),
-        };
-        let mut editor = Editor::with_config(config).unwrap();
-        editor.set_helper(Some(helper));
-        
-        if let Err(err) = editor.load_history(&history_file) {
-            eprintln!("Failed to load history: {}", err);
-        }
+        let term = term(4, 3, b"abcdefghij");
+        assert_eq!(reflow_rows(&term.wrapped_rows(), 10)[0], ("abcdefghij".to_string(), false));
+    }
 
-        let term = match shell_path.as_str() {
-            "/bin/bash" | "/usr/bin/bash" => "xterm-256color",
-            "/bin/zsh" | "/usr/bin/zsh" => "xterm-256color",
-            "/bin/fish" | "/usr/bin/fish" => "xterm-256color",
-            _ => "vt100",
-        }.to_string();
+    #[test]
+    fn scrolled_off_rows_go_to_a_bounded_scrollback() {
+        let mut term = VteTerminal::new(5, 2);
+        term.scrollback_limit = 2;
+        term.process(b"one\r\ntwo\r\nthree\r\nfour");
+        assert_eq!(term.get_scrollback_lines(0, 10), ["one  ", "two  "]);
+        assert_eq!(term.get_scrollback_lines(1, 1), ["two  "]);
+        term.process(b"\r\nfive");
+        assert_eq!(term.get_scrollback_lines(0, 10), ["two  ", "three"]);
+        term.process(b"\x1b[2J");
+        assert_eq!(term.scrollback.len(), 2);
+        term.process(b"\x1b[3J");
+        assert!(term.scrollback.is_empty());
+    }
 
-        let mut phantom_tty = PhantomTTY {
-            shell_path,
-            history_file,
-            editor,
-            terminal: TerminalWidget::new(),
-            term,
-            pty_master: None,
-            vte_terminal: VteTerminal::new(80, 24),
-        };
-        phantom_tty.terminal.set_output("Welcome to PhantomTTY!\n");
-        
-        if let Err(e) = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
-            phantom_tty.start_shell();
-        })) {
-            eprintln!("Error starting shell: {:?}", e);
-            phantom_tty.terminal.set_output("Failed to start shell. Some features may not work correctly.\n");
-        }
-        
-        phantom_tty
+    #[test]
+    fn xtpopsgr_restores_the_pushed_pen() {
+        let mut term = term(10, 1, b"\x1b[31m\x1b[#{\x1b[34;1mB\x1b[#}R");
+        assert_eq!(term.cells[0].fg, Color::Indexed(4));
+        assert_eq!(term.cells[1].fg, Color::Indexed(1));
+        assert_eq!(term.cells[1].attrs & Cell::BOLD, 0);
+        // Popping an empty stack leaves the pen as it is.
+        term.process(b"\x1b[#}X");
+        assert_eq!(term.cells[2].fg, Color::Indexed(1));
     }
 
-    fn start_shell(&mut self) {
-        let winsize = Winsize {
-            ws_row: 24,
-            ws_col: 80,
-            ws_xpixel: 0,
-            ws_ypixel: 0,
-        };
+    #[test]
+    fn size_report_replies_with_the_grid() {
+        let mut term = term(100, 30, b"\x1b[18t");
+        assert_eq!(term.take_responses(), b"\x1b[8;30;100t");
+        term.resize(80, 24);
+        term.process(b"\x1b[18t");
+        assert_eq!(term.take_responses(), b"\x1b[8;24;80t");
+        // Unsupported window ops are dropped without a reply.
+        term.process(b"\x1b[9;1t");
+        assert!(term.take_responses().is_empty());
+    }
 
-        let pty = openpty(Some(&winsize), None).expect("Failed to open pty");
-        let pty_master = unsafe { File::from_raw_fd(pty.master) };
-        let pty_slave = pty.slave;
+    #[test]
+    fn eintr_storm_or_clock_jump_counts_as_a_resume() {
+        let mut resume = ResumeDetector::new(Duration::ZERO);
+        // A few interrupts, or a burst broken by a good poll, are just signals.
+        assert!(!(1..EINTR_STORM).any(|_| resume.resumed(true, Duration::ZERO)));
+        assert!(!resume.resumed(false, Duration::ZERO));
+        assert!(!(1..EINTR_STORM).any(|_| resume.resumed(true, Duration::ZERO)));
+        assert!(resume.resumed(true, Duration::ZERO));
+        assert!(!resume.resumed(true, Duration::ZERO));
+        assert!(!resume.resumed(false, SUSPEND_THRESHOLD));
+        assert!(resume.resumed(false, SUSPEND_THRESHOLD * 3));
+        assert!(!resume.resumed(false, SUSPEND_THRESHOLD * 3));
+    }
 
-        match unsafe { fork() }.expect("Fork failed") {
-            ForkResult::Parent { child } => {
-                self.pty_master = Some(pty_master);
-                if let Err(e) = tcsetpgrp(pty_slave, Pid::from_raw(child.as_raw() as i32)) {
-                    eprintln!("Warning: Failed to set controlling process: {}", e);
-                }
-            }
-            ForkResult::Child => {
-                if let Err(e) = setsid() {
-                    eprintln!("Warning: Failed to create new session: {}", e);
-                }
-                
-                if let Err(e) = nix::unistd::setpgid(Pid::from_raw(0), Pid::from_raw(0)) {
-                    eprintln!("Warning: Failed to set process group: {}", e);
-                }
-                
-                if let Err(e) = tcsetpgrp(pty_slave, Pid::from_raw(0)) {
-                    eprintln!("Warning: Failed to set foreground process group: {}", e);
-                }
-        
-                unsafe {
-                    if libc::ioctl(pty_slave, libc::TIOCSCTTY, 0) == -1 {
-                        eprintln!("Warning: Failed to set controlling terminal");
-                    }
-                }
+    #[test]
+    fn resume_forces_a_redraw() {
+        let mut app = app();
+        let (tx, rx) = mpsc::channel();
+        app.pty_reader = Some(rx);
+        app.vte_terminal.process(b"drawn while asleep");
+        assert!(!app.terminal.output.contains("drawn while asleep"));
+        tx.send(PtyEvent::Resumed).unwrap();
+        app.read_pty_output();
+        assert!(app.terminal.output.starts_with("drawn while asleep"));
+    }
 
-                nix::unistd::dup2(pty_slave, 0).expect("Failed to redirect stdin");
-                nix::unistd::dup2(pty_slave, 1).expect("Failed to redirect stdout");
-                nix::unistd::dup2(pty_slave, 2).expect("Failed to redirect stderr");
+    #[test]
+    fn binary_heuristic_flags_control_heavy_output() {
+        let elf: Vec<u8> = b"\x7fELF\x02\x01\x01".iter().copied().cycle().take(512).collect();
+        assert!(looks_binary(&elf, 0.3));
+        let colored_text = "\x1b[1;32mok\x1b[0m\tdone\r\n".repeat(20);
+        assert!(!looks_binary(colored_text.as_bytes(), 0.3));
+        // Too little output to judge.
+        assert!(!looks_binary(&[0u8; 16], 0.3));
+        let mostly_text: Vec<u8> = [&[0u8; 40][..], &[b'a'; 216][..]].concat();
+        assert!(looks_binary(&mostly_text, 0.1));
+        assert!(!looks_binary(&mostly_text, 0.2));
+    }
 
-                drop(pty_master);
+    #[test]
+    fn high_contrast_keeps_faint_text_at_full_color() {
+        let base = egui::TextFormat::simple(egui::FontId::monospace(DEFAULT_FONT_SIZE), egui::Color32::WHITE);
+        let faint = Cell { attrs: Cell::FAINT, ..Cell::default() };
+        let dimmed = test_colors().format(&base, faint);
+        assert_ne!(dimmed.color, egui::Color32::WHITE);
+        let colors = CellColors { high_contrast: true, ..test_colors() };
+        assert_eq!(colors.format(&base, faint).color, egui::Color32::WHITE);
+    }
 
-                let mut termios = termios::tcgetattr(pty_slave).expect("Failed to get terminal attributes");
-                termios::cfmakeraw(&mut termios);
-                termios::tcsetattr(pty_slave, SetArg::TCSANOW, &termios).expect("Failed to set terminal attributes");
+    #[test]
+    fn scroll_position_survives_switching_tabs() {
+        let lines: String = (0..100).map(|i| format!("line {i}\r\n")).collect();
+        let mut terms = [term(20, 5, lines.as_bytes()), term(20, 5, lines.as_bytes())];
+        let mut widgets = [TerminalWidget::new(), TerminalWidget::new()];
+        let ctx = egui::Context::default();
+        let mut time = 0.0;
+        let mut frame = |widgets: &mut [TerminalWidget; 2], terms: &[VteTerminal; 2], tab: usize, events: Vec<egui::Event>| {
+            // A second per frame lets scroll animations finish.
+            time += 1.0;
+            let input = egui::RawInput {
+                screen_rect: Some(egui::Rect::from_min_size(egui::Pos2::ZERO, egui::vec2(800.0, 600.0))),
+                time: Some(time),
+                events,
+                ..Default::default()
+            };
+            widgets[tab].set_screen(&terms[tab].screen_text(), &terms[tab]);
+            let _ = ctx.run(input, |ctx| {
+                egui::CentralPanel::default().show(ctx, |ui| widgets[tab].show(ui, ctx, &terms[tab]));
+            });
+            widgets[tab].screen_origin.y
+        };
+        frame(&mut widgets, &terms, 0, Vec::new());
+        let bottom = frame(&mut widgets, &terms, 0, Vec::new());
+        frame(&mut widgets, &terms, 0, vec![egui::Event::PointerMoved(egui::pos2(400.0, 300.0))]);
+        let wheel = egui::Event::MouseWheel { unit: egui::MouseWheelUnit::Point, delta: egui::vec2(0.0, 300.0), modifiers: egui::Modifiers::NONE };
+        frame(&mut widgets, &terms, 0, vec![wheel]);
+        // Wheel scrolling is smoothed over a few frames.
+        for _ in 0..5 {
+            frame(&mut widgets, &terms, 0, Vec::new());
+        }
+        let scrolled = frame(&mut widgets, &terms, 0, Vec::new());
+        assert!(scrolled > bottom);
 
-                let err = nix::unistd::execve(
-                    &std::ffi::CString::new(self.shell_path.clone()).unwrap(),
-                    &[&std::ffi::CString::new(self.shell_path.clone()).unwrap()],
-                    &[&std::ffi::CString::new(format!("TERM={}", self.term)).unwrap()],
-                );
-                panic!("Failed to execute shell: {:?}", err);
-            }
+        for _ in 0..3 {
+            frame(&mut widgets, &terms, 1, Vec::new());
         }
+        assert_eq!(frame(&mut widgets, &terms, 0, Vec::new()), scrolled);
+
+        // Output in the background leaves the scrolled tab put and the following tab at the end.
+        terms[0].process(b"more\r\n");
+        terms[1].process(b"more\r\n");
+        frame(&mut widgets, &terms, 1, Vec::new());
+        assert_eq!(frame(&mut widgets, &terms, 1, Vec::new()), bottom);
+        frame(&mut widgets, &terms, 0, Vec::new());
+        assert!(frame(&mut widgets, &terms, 0, Vec::new()) > scrolled);
     }
 
-    fn read_pty_output(&mut self) {
-        if let Some(ref mut master) = self.pty_master {
-            let mut fd_set = FdSet::new();
-            fd_set.insert(master.as_raw_fd());
-            let mut timeout = TimeVal::new(0, 100_000);
-            match select(None, Some(&mut fd_set), None, None, Some(&mut timeout)) {
-                Ok(_) => {
-                    if fd_set.contains(master.as_raw_fd()) {
-                        let mut buffer = [0u8; 1024];
-                        match master.read(&mut buffer) {
-                            Ok(n) if n > 0 => {
-                                self.vte_terminal.process(&buffer[..n]);
-                                self.terminal.set_output(&self.vte_terminal.get_screen());
-                            }
-                            Err(e) => eprintln!("Error reading from PTY: {}", e),
-                            _ => {}
-                        }
-                    }
-                }
-                Err(e) => eprintln!("Error in select: {}", e),
-            }
-        }
+    #[test]
+    fn matching_command_waits_for_confirmation() {
+        let config = Config { confirm_commands: vec!["^kubectl delete ".to_string()], ..Config::default() };
+        let mut app = app_with(&config);
+        let path = capture_pty_writes(&mut app, "confirm");
+        app.execute_command("kubectl delete ns prod").unwrap();
+        assert_eq!(app.pending_confirmation, Some(("kubectl delete ns prod".to_string(), "^kubectl delete ".to_string())));
+        app.execute_command("rm -rf /").unwrap();
+        assert_eq!(app.pending_confirmation.as_ref().map(|(command, _)| command.as_str()), Some("rm -rf /"));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+
+        app.pending_confirmation = None;
+        app.execute_command("kubectl get pods").unwrap();
+        assert_eq!(app.pending_confirmation, None);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "kubectl get pods\n");
+        fs::remove_file(path).unwrap();
     }
-    fn save_history(&mut self) {
-        if let Err(err) = self.editor.save_history(&self.history_file) {
-            eprintln!("Error saving history: {}", err);
-        }
+
+    #[test]
+    fn da_reply_lists_the_implemented_extensions() {
+        let reply = String::from_utf8(term(80, 24, b"\x1b[c").take_responses()).unwrap();
+        let codes: Vec<u16> = reply.strip_prefix("\x1b[?").and_then(|r| r.strip_suffix('c')).unwrap()
+            .split(';').map(|code| code.parse().unwrap()).collect();
+        assert_eq!(codes[0], 64);
+        assert_eq!(&codes[1..], DA_EXTENSIONS);
+        assert_eq!(da_reply(&[4, 6, 22, 28]), "?64;4;6;22;28c");
     }
 
-    fn execute_command(&mut self, command: &str) -> io::Result<()> {
-        self.editor.add_history_entry(command.to_string()).unwrap();
+    #[test]
+    fn wide_glyphs_take_two_cells() {
+        let term = term(12, 2, "ab你好😀c".as_bytes());
+        assert_eq!(&term.screen[..10], &['a', 'b', '你', WIDE_SPACER, '好', WIDE_SPACER, '😀', WIDE_SPACER, 'c', ' ']);
+        assert_eq!(term.cursor_x, 9);
+        assert_eq!(term.get_screen().lines().next(), Some("ab你好😀c   "));
 
-        match command {
-            "history" => self.show_history(),
-            "exit" => {
-                self.save_history();
-                std::process::exit(0);
-            },
-            _ if command.starts_with("phantom:") => self.handle_phantom_command(&command[8..]),
-            _ => self.execute_in_shell(command),
-        }
+        let mut widget = TerminalWidget::new();
+        widget.set_screen(&term.screen_text(), &term);
+        let ctx = egui::Context::default();
+        let mut text = String::new();
+        let _ = ctx.run(egui::RawInput::default(), |ctx| {
+            egui::CentralPanel::default().show(ctx, |ui| {
+                text = widget.layout_job(ui, &egui::FontId::monospace(DEFAULT_FONT_SIZE)).text;
+            });
+        });
+        assert!(text.starts_with("ab你好😀c"));
+        assert!(!text.contains(WIDE_SPACER));
     }
 
-    fn show_history(&mut self) -> io::Result<()> {
-        let history_output: String = self.terminal.history
-            .iter()
-            .enumerate()
-            .map(|(i, cmd)| format!("{}: {}\n", i + 1, cmd))
+    #[test]
+    fn insert_puts_the_command_output_on_the_input_line() {
+        let mut app = app();
+        let path = capture_pty_writes(&mut app, "insert");
+        app.terminal.input = "git show".to_string();
+        app.handle_phantom_command("insert $(echo abc)").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "echo abc\n");
+        app.vte_terminal.process(b"echo abc\r\n\x1b]133;C\x07abc\r\n\x1b]133;D;0\x07\x1b]133;A\x07$ ");
+        app.poll_run_capture();
+        assert_eq!(app.terminal.input, "git show abc");
+        assert!(app.copy_requests.is_empty());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn wide_glyph_in_the_last_column_wraps() {
+        let term = term(5, 2, "abcd你".as_bytes());
+        assert_eq!(&term.screen[..5], &['a', 'b', 'c', 'd', ' ']);
+        assert_eq!(&term.screen[5..7], &['你', WIDE_SPACER]);
+        assert_eq!((term.cursor_x, term.cursor_y), (2, 1));
+        assert_eq!(rows(&term), ["abcd", "你"]);
+        assert!(term.wrapped[0]);
+    }
+
+    #[test]
+    fn stderr_output_is_tinted() {
+        let mut app = app();
+        let (tx, rx) = mpsc::channel();
+        app.pty_reader = Some(rx);
+        tx.send(PtyEvent::Output(b"out ".to_vec())).unwrap();
+        tx.send(PtyEvent::Stderr(b"err".to_vec())).unwrap();
+        tx.send(PtyEvent::Output(b" out".to_vec())).unwrap();
+        app.read_pty_output();
+        let tagged: String = app.vte_terminal.cells[..11].iter()
+            .map(|cell| if cell.attrs & Cell::STDERR != 0 { 'e' } else { '-' })
             .collect();
-        self.terminal.set_output(&history_output);
-        Ok(())
+        assert_eq!(tagged, "----eee----");
+
+        let base = egui::TextFormat::simple(egui::FontId::monospace(DEFAULT_FONT_SIZE), egui::Color32::WHITE);
+        let [out, err] = [0, 4].map(|i| test_colors().format(&base, app.vte_terminal.cells[i]).color);
+        assert_eq!(out, egui::Color32::WHITE);
+        assert_ne!(err, out);
     }
 
-    fn handle_phantom_command(&mut self, command: &str) -> io::Result<()> {
-        match command.trim() {
-            "hello" => self.terminal.set_output("Hello from PhantomTTY!"),
-            "shell" => self.terminal.set_output(&format!("Current shell: {}", self.shell_path)),
-            _ => self.terminal.set_output(&format!("Unknown PhantomTTY command: {}", command)),
-        }
-        Ok(())
+    #[test]
+    fn undo_takes_back_a_paste_in_one_step() {
+        let term = term(20, 3, b"");
+        let mut widget = TerminalWidget::new();
+        let ctx = egui::Context::default();
+        let frame = |widget: &mut TerminalWidget, events: Vec<egui::Event>| {
+            let input = egui::RawInput {
+                screen_rect: Some(egui::Rect::from_min_size(egui::Pos2::ZERO, egui::vec2(800.0, 600.0))),
+                events,
+                ..Default::default()
+            };
+            let _ = ctx.run(input, |ctx| {
+                egui::CentralPanel::default().show(ctx, |ui| widget.show(ui, ctx, &term));
+            });
+            widget.input.clone()
+        };
+        frame(&mut widget, Vec::new());
+        // Click the input line, just below the output.
+        let pos = egui::pos2(400.0, widget.output_rect.max.y + 15.0);
+        let click = |pressed| egui::Event::PointerButton { pos, button: egui::PointerButton::Primary, pressed, modifiers: egui::Modifiers::NONE };
+        frame(&mut widget, vec![egui::Event::PointerMoved(pos), click(true)]);
+        frame(&mut widget, vec![click(false)]);
+        assert_eq!(frame(&mut widget, vec![egui::Event::Text("ls ".to_string())]), "ls ");
+        assert_eq!(frame(&mut widget, vec![egui::Event::Paste("-la /var/log".to_string())]), "ls -la /var/log");
+        let undone = frame(&mut widget, vec![egui::Event::Key {
+            key: egui::Key::Z,
+            physical_key: None,
+            pressed: true,
+            repeat: false,
+            modifiers: egui::Modifiers::CTRL,
+        }]);
+        assert_eq!(undone, "ls ");
     }
 
-    fn execute_in_shell(&mut self, command: &str) -> io::Result<()> {
-        if let Some(ref mut master) = self.pty_master {
-            writeln!(master, "{}", command)?;
-            master.flush()?;
-        }
-        Ok(())
+    #[test]
+    fn flags_and_accents_stay_one_cluster() {
+        let term = term(10, 2, "🇯🇵e\u{301}\u{323}x".as_bytes());
+        assert_eq!(&term.screen[..5], &['🇯', WIDE_SPACER, 'e', 'x', ' ']);
+        assert_eq!(term.cluster_tail(term.cells[0]), "🇵");
+        assert_eq!(term.cluster_tail(term.cells[2]), "\u{301}\u{323}");
+        assert_eq!(term.cluster_tail(term.cells[3]), "");
+        assert_eq!(term.cursor_x, 4);
+
+        let mut widget = TerminalWidget::new();
+        widget.set_screen(&term.screen_text(), &term);
+        let ctx = egui::Context::default();
+        let mut text = String::new();
+        let _ = ctx.run(egui::RawInput::default(), |ctx| {
+            egui::CentralPanel::default().show(ctx, |ui| {
+                text = widget.layout_job(ui, &egui::FontId::monospace(DEFAULT_FONT_SIZE)).text;
+            });
+        });
+        assert!(text.starts_with("🇯🇵e\u{301}\u{323}x"));
     }
-}
 
-struct PhantomTTYApp {
-    phantom_tty: PhantomTTY,
-}
+    #[test]
+    fn headless_session_runs_commands_and_resizes() {
+        let config = Config { shell: Some("/bin/sh".to_string()), ..Config::default() };
+        let mut session = Session::start(&config, 40, 10);
+        session.feed_input(b"stty size; echo one-$((1 + 1))\n");
+        let screen = session.poll_output();
+        assert!(screen.contains("10 40"), "{screen}");
+        assert!(screen.contains("one-2"), "{screen}");
 
-impl PhantomTTYApp {
-    fn new(_cc: &eframe::CreationContext<'_>) -> Self {
-        let shell_path = get_default_shell();
-        Self {
-            phantom_tty: PhantomTTY::new(shell_path),
-        }
+        session.resize(60, 12);
+        session.feed_input(b"stty size; exit\n");
+        let screen = session.poll_output();
+        assert!(screen.contains("12 60"), "{screen}");
+        assert!(session.exited);
     }
-}
 
-impl eframe::App for PhantomTTYApp {
-    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
-        self.phantom_tty.read_pty_output();
+    #[test]
+    fn opening_past_max_tabs_applies_the_policy() {
+        let start = Instant::now();
+        let tabs = |names: &[&str]| names.iter().enumerate().map(|(i, name)| {
+            let mut tab = app();
+            tab.last_command = Some(name.to_string());
+            tab.last_used = start + Duration::from_secs([2, 1, 3][i]);
+            tab
+        }).collect::<Vec<_>>();
+        let names = |window: &PhantomTTYApp| window.tabs.iter().map(|tab| tab.last_command.clone().unwrap_or_default()).collect::<Vec<_>>();
+        let ctx = egui::Context::default();
 
-        egui::CentralPanel::default().show(ctx, |ui| {
-            let available_size = ui.available_size();
-            
-            if let Some(command) = self.phantom_tty.terminal.show(ui, ctx) {
-                if let Err(e) = self.phantom_tty.execute_command(&command) {
-                    self.phantom_tty.terminal.set_output(&format!("Error: {}", e));
-                }
-            }
-        });
+        let mut refusing = window(tabs(&["a", "b", "c"]), Config { max_tabs: Some(3), ..Config::default() });
+        refusing.open_tab(&ctx, "/bin/sh".to_string());
+        assert_eq!(names(&refusing), ["a", "b", "c"]);
+        assert_eq!(refusing.active, 0);
 
-        ctx.request_repaint();
+        // "b" went longest without showing; the showing "c" is never recycled, however old.
+        let config = Config { max_tabs: Some(3), max_tabs_policy: TabLimitPolicy::Recycle, ..Config::default() };
+        let mut recycling = window(tabs(&["a", "b", "c"]), config);
+        recycling.open_tab(&ctx, "/bin/sh".to_string());
+        assert_eq!(names(&recycling), ["a", "c", ""]);
+        assert_eq!(recycling.active, 2);
+        recycling.switch_tab(&ctx, 1);
+        recycling.open_tab(&ctx, "/bin/sh".to_string());
+        assert_eq!(names(&recycling), ["c", "", ""]);
+
+        let mut unlimited = window(tabs(&["a", "b", "c"]), Config::default());
+        unlimited.open_tab(&ctx, "/bin/sh".to_string());
+        assert_eq!(unlimited.tabs.len(), 4);
     }
-}
 
-fn get_history_file_path() -> PathBuf {
-    let mut path = if let Some(config_dir) = dirs::config_dir() {
-        config_dir
-    } else {
-        PathBuf::from(env::var("HOME").unwrap_or_else(|_| String::from("/")))
-    };
-    path.push("phantomtty");
-    fs::create_dir_all(&path).unwrap_or_else(|e| eprintln!("Error creating config directory: {}", e));
-    path.push("history");
-    path
-}
+    #[test]
+    fn output_between_prompts_folds_as_one_block() {
+        let mut term = term(20, 8, concat!(
+            "\x1b]133;A\x07$ ls\r\n\x1b]133;C\x07a\r\nb\r\n\x1b]133;D;0\x07",
+            "\x1b]133;A\x07$ pwd\r\n\x1b]133;C\x07/\r\n\x1b]133;D;0\x07",
+            "\x1b]133;A\x07$ ",
+        ).as_bytes());
+        assert_eq!(rows(&term)[..6], ["$ ls", "a", "b", "$ pwd", "/", "$"]);
+        assert!(term.folded_rows().is_empty());
+        term.toggle_fold(0);
+        assert_eq!(term.folded_rows()[..6], [false, true, true, false, false, false]);
+        term.toggle_fold(3);
+        assert_eq!(term.folded_rows()[..6], [false, true, true, false, true, false]);
+        term.toggle_fold(0);
+        term.toggle_fold(3);
+        assert!(term.folded_rows().is_empty());
+    }
 
-fn get_default_shell() -> String {
-    if let Ok(shell) = env::var("SHELL") {
-        return shell;
+    #[test]
+    fn home_and_end_route_by_focus_and_mode() {
+        use HomeEndTarget::*;
+        // (input focused, full screen, over output, program first)
+        assert_eq!(home_end_target(true, false, true, false), Some(Line));
+        assert_eq!(home_end_target(true, true, false, false), Some(Line));
+        assert_eq!(home_end_target(true, true, false, true), Some(Program));
+        assert_eq!(home_end_target(false, true, false, false), Some(Program));
+        assert_eq!(home_end_target(false, false, true, false), Some(Scrollback));
+        assert_eq!(home_end_target(false, false, false, true), None);
+        assert_eq!(home_end_key(true, false), b"\x1b[H");
+        assert_eq!(home_end_key(false, true), b"\x1bOF");
+
+        let lines: String = (0..100).map(|i| format!("line {i}\r\n")).collect();
+        let mut term = term(20, 5, lines.as_bytes());
+        let mut widget = TerminalWidget::new();
+        let ctx = egui::Context::default();
+        let mut time = 0.0;
+        let mut frame = |widget: &mut TerminalWidget, term: &VteTerminal, events: Vec<egui::Event>| {
+            time += 1.0;
+            let input = egui::RawInput {
+                screen_rect: Some(egui::Rect::from_min_size(egui::Pos2::ZERO, egui::vec2(800.0, 600.0))),
+                time: Some(time),
+                events,
+                ..Default::default()
+            };
+            widget.set_screen(&term.screen_text(), term);
+            let _ = ctx.run(input, |ctx| {
+                egui::CentralPanel::default().show(ctx, |ui| widget.show(ui, ctx, term));
+            });
+            widget.screen_origin.y
+        };
+        frame(&mut widget, &term, Vec::new());
+        let bottom = frame(&mut widget, &term, vec![egui::Event::PointerMoved(egui::pos2(400.0, 300.0))]);
+        // Over the scrollback, Home goes to the top and stays there; End follows the output again.
+        frame(&mut widget, &term, vec![key(egui::Key::Home)]);
+        let top = frame(&mut widget, &term, Vec::new());
+        assert!(top > bottom);
+        assert_eq!(frame(&mut widget, &term, Vec::new()), top);
+        frame(&mut widget, &term, vec![key(egui::Key::End)]);
+        frame(&mut widget, &term, Vec::new());
+        assert_eq!(frame(&mut widget, &term, Vec::new()), bottom);
+
+        // A full-screen program gets them as keys.
+        term.process(b"\x1b[?1049h\x1b[?1h");
+        frame(&mut widget, &term, vec![key(egui::Key::Home)]);
+        assert_eq!(widget.pending_input, b"\x1bOH");
     }
 
-    let username = env::var("USER").unwrap_or_else(|_| String::from("root"));
-    if let Ok(file) = File::open("/etc/passwd") {
-        let reader = BufReader::new(file);
-        for line in reader.lines() {
-            if let Ok(line) = line {
-                let parts: Vec<&str> = line.split(':').collect();
-                if parts.len() >= 7 && parts[0] == username {
-                    return String::from(parts[6]);
-                }
-            }
-        }
+    #[test]
+    fn snapshot_round_trips_through_toml() {
+        let mut original = term(8, 3, "one\r\n\x1b[1;31mred\x1b[0m\tx\r\n你e\u{301}🇯🇵\r\nwrapping past\r\n".as_bytes());
+        original.process(b"\x1b[4mlast");
+        let contents = toml::to_string(&original.snapshot()).unwrap();
+        let mut restored = term(8, 3, b"");
+        restored.restore_snapshot(toml::from_str(&contents).unwrap());
+
+        let mut expected: Vec<(Vec<char>, Vec<Cell>, bool)> = original.scrollback.iter()
+            .map(|line| (line.chars.clone(), line.cells.clone(), line.wrapped))
+            .collect();
+        expected.extend((0..=original.cursor_y).map(|row| {
+            let range = row * original.width..(row + 1) * original.width;
+            (original.screen[range.clone()].to_vec(), original.cells[range].to_vec(), original.wrapped[row])
+        }));
+        let actual: Vec<(Vec<char>, Vec<Cell>, bool)> = restored.scrollback.iter()
+            .map(|line| (line.chars.clone(), line.cells.clone(), line.wrapped))
+            .collect();
+        assert_eq!(actual, expected);
+        assert_eq!(restored.clusters, original.clusters);
     }
 
-    String::from("/bin/sh")
-}
+    #[test]
+    fn autosave_is_due_by_quiet_time_or_line_count() {
+        let secs = Duration::from_secs;
+        let autosave = AutosaveConfig { enabled: true, idle_seconds: 30, lines: 1000, keep: 5 };
+        assert!(!autosave.due(secs(29), 999));
+        assert!(autosave.due(secs(30), 0));
+        assert!(autosave.due(Duration::ZERO, 1000));
 
-fn main() -> Result<(), eframe::Error> {
-    let options = eframe::NativeOptions {
-        initial_window_size: Some(egui::vec2(800.0, 600.0)),
-        ..Default::default()
-    };
-    eframe::run_native(
-        "PhantomTTY",
-        options,
-        Box::new(|cc| Ok(Box::new(PhantomTTYApp::new(cc)))),
-    )
-}
\ No newline at end of file
+        let lines_only = AutosaveConfig { idle_seconds: 0, ..autosave };
+        assert!(!lines_only.due(secs(3600), 999));
+        assert!(lines_only.due(Duration::ZERO, 1000));
+        let idle_only = AutosaveConfig { lines: 0, ..autosave };
+        assert!(!idle_only.due(secs(29), 100_000));
+        assert!(idle_only.due(secs(30), 0));
+    }
+
+    #[test]
+    fn paste_only_fills_the_line_and_paste_and_run_submits() {
+        let mut term = term(20, 3, b"");
+        let mut widget = TerminalWidget::new();
+        let ctx = egui::Context::default();
+        let frame = |widget: &mut TerminalWidget, term: &VteTerminal, events: Vec<egui::Event>| {
+            let input = egui::RawInput {
+                screen_rect: Some(egui::Rect::from_min_size(egui::Pos2::ZERO, egui::vec2(800.0, 600.0))),
+                events,
+                ..Default::default()
+            };
+            let mut executed = None;
+            let _ = ctx.run(input, |ctx| {
+                egui::CentralPanel::default().show(ctx, |ui| executed = widget.show(ui, ctx, term));
+            });
+            executed
+        };
+        let run = egui::Event::Key {
+            key: egui::Key::Enter,
+            physical_key: None,
+            pressed: true,
+            repeat: false,
+            modifiers: egui::Modifiers::COMMAND | egui::Modifiers::SHIFT,
+        };
+        frame(&mut widget, &term, Vec::new());
+        let pos = egui::pos2(400.0, widget.output_rect.max.y + 15.0);
+        let click = |pressed| egui::Event::PointerButton { pos, button: egui::PointerButton::Primary, pressed, modifiers: egui::Modifiers::NONE };
+        frame(&mut widget, &term, vec![egui::Event::PointerMoved(pos), click(true)]);
+        frame(&mut widget, &term, vec![click(false)]);
+
+        assert_eq!(frame(&mut widget, &term, vec![egui::Event::Paste("make test".to_string())]), None);
+        assert_eq!(widget.input, "make test");
+        assert_eq!(frame(&mut widget, &term, vec![run.clone()]).as_deref(), Some("make test"));
+        assert!(widget.paste_submitted);
+        assert_eq!(widget.input, "");
+
+        // Multi-line text only runs once the shell takes bracketed paste.
+        widget.input = "make\nmake install".to_string();
+        assert_eq!(frame(&mut widget, &term, vec![run.clone()]), None);
+        assert_eq!(widget.input, "make\nmake install");
+        term.process(b"\x1b[?2004h");
+        assert_eq!(frame(&mut widget, &term, vec![run]).as_deref(), Some("make\nmake install"));
+    }
+
+    #[test]
+    fn drags_past_the_grid_report_its_edge() {
+        let term = term(10, 3, b"\x1b[?1002h\x1b[?1006h");
+        let mut widget = TerminalWidget::new();
+        widget.set_screen(&term.screen_text(), &term);
+        let ctx = egui::Context::default();
+        let frame = |widget: &mut TerminalWidget, events: Vec<egui::Event>| {
+            let input = egui::RawInput {
+                screen_rect: Some(egui::Rect::from_min_size(egui::Pos2::ZERO, egui::vec2(800.0, 600.0))),
+                events,
+                ..Default::default()
+            };
+            let _ = ctx.run(input, |ctx| {
+                egui::CentralPanel::default().show(ctx, |ui| widget.show(ui, ctx, &term));
+            });
+            String::from_utf8(std::mem::take(&mut widget.pending_input)).unwrap()
+        };
+        frame(&mut widget, Vec::new());
+        let pos = widget.screen_origin + egui::vec2(1.0, 1.0);
+        let press = egui::Event::PointerButton { pos, button: egui::PointerButton::Primary, pressed: true, modifiers: egui::Modifiers::NONE };
+        assert_eq!(frame(&mut widget, vec![egui::Event::PointerMoved(pos), press]), "\x1b[<0;1;1M");
+        // Far right of and below the 10x3 grid, the drag stays on its last cell.
+        let outside = egui::pos2(790.0, 590.0);
+        assert_eq!(frame(&mut widget, vec![egui::Event::PointerMoved(outside)]), "\x1b[<32;10;3M");
+    }
+
+    #[test]
+    fn added_snippets_are_appended_to_the_config() {
+        let path = env::temp_dir().join(format!("phantom-test-{}-snippets.toml", std::process::id()));
+        fs::write(&path, "# keep me\nfont_size = 16.0\n\n[theme]\nforeground = \"#ffffff\"\n").unwrap();
+        save_snippet(&path, &Snippet { name: "build".to_string(), command: "cargo build".to_string() }).unwrap();
+        save_snippet(&path, &Snippet { name: "say \"hi\"".to_string(), command: "echo \"hi\"".to_string() }).unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.starts_with("# keep me\n"));
+        let config: Config = toml::from_str(&contents).unwrap();
+        assert_eq!(config.font_size, Some(16.0));
+        let snippets: Vec<(&str, &str)> = config.snippets.iter().map(|s| (s.name.as_str(), s.command.as_str())).collect();
+        assert_eq!(snippets, [("build", "cargo build"), ("say \"hi\"", "echo \"hi\"")]);
+        fs::remove_file(path).unwrap();
+    }
+}